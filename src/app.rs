@@ -1,21 +1,33 @@
 use std::{
     collections::HashMap,
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
 use anyhow::{Ok, Result};
+use chrono::{DateTime, FixedOffset};
 use futures::future::join_all;
-use tokio::{spawn, time::sleep};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::{
+    spawn,
+    time::{sleep_until, Instant},
+};
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, trace};
+use tracing::{debug, error, info, trace, warn};
 
 use crate::{
     config,
-    database::Database,
-    operations::destination::post,
-    sources::source::{get, retain_all_dst_statuses},
-    store,
+    database::{Conflict, Database},
+    operations::{destination::post, dry_run::dry_run, reconcile::reconcile},
+    protocols::{create_http_client, media::MediaFetcher},
+    sources::{
+        backfill::backfill,
+        retract::retract,
+        source::{get, retain_all_dst_statuses},
+    },
+    store::{self, operations::AccountPair},
+    utils::format_rfc3339_option,
 };
 
 #[derive(Clone, Eq, Hash, PartialEq)]
@@ -24,17 +36,70 @@ pub struct AccountKey {
     pub identifier: String,
 }
 
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase", tag = "command")]
+pub enum Command {
+    #[default]
+    Run,
+    /** 送信する内容をログに出すだけで、送信も commit もしない */
+    DryRun,
+    #[serde(rename_all = "camelCase")]
+    Backfill {
+        account_pair: AccountPair,
+        #[serde(default, deserialize_with = "format_rfc3339_option::deserialize")]
+        since: Option<DateTime<FixedOffset>>,
+        #[serde(default, deserialize_with = "format_rfc3339_option::deserialize")]
+        until: Option<DateTime<FixedOffset>>,
+        count: Option<usize>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Retract {
+        account_pair: AccountPair,
+        #[serde(default, deserialize_with = "format_rfc3339_option::deserialize")]
+        since: Option<DateTime<FixedOffset>>,
+        #[serde(default, deserialize_with = "format_rfc3339_option::deserialize")]
+        until: Option<DateTime<FixedOffset>>,
+        #[serde(default)]
+        src_identifiers: Vec<String>,
+    },
+}
+
+impl Command {
+    /** command を持たない入力 (定期実行など) は通常の実行とみなす */
+    pub fn from_json(json: Option<Value>) -> Result<Self> {
+        match json {
+            Some(json) if json.get("command").is_some() => Ok(serde_json::from_value(json)?),
+            _ => Ok(Command::Run),
+        }
+    }
+}
+
 pub async fn do_main_task(
     cancellation_token: &CancellationToken,
+    send_deadline: Instant,
     config: &config::Config,
     store: &mut store::Store,
 ) -> Result<()> {
     trace!("do_main_task");
-    let http_client = Arc::new(reqwest::Client::new());
+    store.release_jobs(config.job_operations_per_run);
+    let http_client = Arc::new(create_http_client(config.http_timeout())?);
+    let media_fetcher = Arc::new(MediaFetcher::new(
+        http_client.clone(),
+        config.media_cache_dir(),
+    ));
+    media_fetcher.prune(config.media_cache_max_age()).await;
     let mut dst_client_map = HashMap::new();
     let store = Mutex::new(store);
     let users = config.users.iter();
-    let futures = users.map(|config_user| get(&http_client, config_user, &store));
+    let futures = users.map(|config_user| {
+        get(
+            &http_client,
+            &media_fetcher,
+            config_user,
+            config.sweep_archived_posts_per_run,
+            &store,
+        )
+    });
     for result in join_all(futures).await {
         if let Some((src_account_key, dst_clients)) = result? {
             dst_client_map.insert(src_account_key, dst_clients);
@@ -45,36 +110,136 @@ pub async fn do_main_task(
         debug!("cancel accepted");
         return Ok(());
     }
-    post(cancellation_token, store, &mut dst_client_map).await?;
+    let post_result = post(
+        cancellation_token,
+        send_deadline,
+        config,
+        &http_client,
+        &media_fetcher,
+        store,
+        &mut dst_client_map,
+    )
+    .await;
+    // 送信に失敗しても、消えた status が原因ならここで対応を捨てて次回に進めるようにする
+    reconcile(cancellation_token, config, store, &mut dst_client_map).await;
+    post_result?;
     if cancellation_token.is_cancelled() {
         debug!("cancel accepted");
         return Ok(());
     }
     if store.operations.is_empty() {
-        retain_all_dst_statuses(store).await?;
+        retain_all_dst_statuses(
+            store,
+            chrono::Duration::days(config.archive_retention_days.into()),
+        )
+        .await?;
     }
     trace!("do_main_task completed");
 
     Ok(())
 }
 
-pub async fn app(database: impl Database) -> Result<()> {
-    let cancellation_token = CancellationToken::new();
-    spawn({
-        let cancellation_token = cancellation_token.clone();
-        async move {
-            sleep(Duration::from_secs(60 + 20)).await;
-            debug!("cancel request");
-            cancellation_token.cancel();
+async fn commit(
+    database: &impl Database,
+    base: &store::Store,
+    store: &mut store::Store,
+) -> Result<()> {
+    loop {
+        match database.commit(store).await {
+            Err(err) if err.is::<Conflict>() => {
+                warn!("store is updated by another run, rebase and retry");
+                let theirs = database.fetch().await?;
+                store.rebase(base, theirs);
+            }
+            result => return result,
         }
+    }
+}
+
+/**
+ * 制限時間から commit の時間を除いた分が過ぎたら打ち切り、その時刻を返す。
+ * deadline が無ければ config の制限時間を使う
+ */
+fn spawn_cancellation(
+    config: &config::Config,
+    deadline: Option<SystemTime>,
+    cancellation_token: CancellationToken,
+) -> Instant {
+    let deadline = deadline
+        .unwrap_or_else(|| SystemTime::now() + Duration::from_secs(config.run_timeout_seconds));
+    let budget = deadline
+        .duration_since(SystemTime::now())
+        .unwrap_or_default()
+        .saturating_sub(Duration::from_secs(config.commit_reserve_seconds));
+    debug!("run budget: {:?}", budget);
+    let send_deadline = Instant::now() + budget;
+    spawn(async move {
+        sleep_until(send_deadline).await;
+        debug!("cancel request");
+        cancellation_token.cancel();
     });
+    send_deadline
+}
+
+pub async fn app(
+    database: impl Database,
+    command: Command,
+    deadline: Option<SystemTime>,
+) -> Result<()> {
+    let cancellation_token = CancellationToken::new();
     spawn(async move {
         let config = database.config().await?;
-        let mut store = database.fetch().await.unwrap_or_default();
+        let send_deadline = spawn_cancellation(&config, deadline, cancellation_token.clone());
+        let mut store = match database.fetch().await {
+            Result::Ok(store) => store,
+            Err(err) if err.is::<Conflict>() => {
+                info!("another run is in progress, back off");
+                return Ok(());
+            }
+            Err(_) => store::Store::default(),
+        };
+        let base = store.clone();
 
-        let main_result = do_main_task(&cancellation_token, &config, &mut store).await;
+        let main_result = match &command {
+            Command::Run => {
+                do_main_task(&cancellation_token, send_deadline, &config, &mut store).await
+            }
+            Command::DryRun => {
+                let result = dry_run(&cancellation_token, send_deadline, &config, &mut store).await;
+                database.rollback().await?;
+                return result;
+            }
+            Command::Backfill {
+                account_pair,
+                since,
+                until,
+                count,
+            } => {
+                backfill(
+                    &config,
+                    &mut store,
+                    account_pair,
+                    since.as_ref(),
+                    until.as_ref(),
+                    *count,
+                )
+                .await
+            }
+            Command::Retract {
+                account_pair,
+                since,
+                until,
+                src_identifiers,
+            } => retract(
+                &mut store,
+                account_pair,
+                since.as_ref(),
+                until.as_ref(),
+                src_identifiers,
+            ),
+        };
 
-        let commit_result = database.commit(&store).await;
+        let commit_result = commit(&database, &base, &mut store).await;
         if let Err(main_error) = main_result {
             if let Err(commit_error) = commit_result {
                 error!("commit error: {:?}", commit_error);
@@ -1,6 +1,11 @@
-use serde::Deserialize;
+use std::{path::PathBuf, time::Duration};
 
-#[derive(Deserialize)]
+use chrono::{DateTime, FixedOffset, NaiveTime};
+use chrono_tz::Tz;
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer};
+
+#[derive(Clone, Deserialize)]
 #[serde(tag = "protocol")]
 pub enum Account {
     #[serde(rename = "atproto")]
@@ -32,13 +37,330 @@ pub enum Account {
     },
 }
 
+impl Account {
+    pub fn origin(&self) -> &str {
+        match self {
+            Account::AtProtocol { origin, .. }
+            | Account::Mastodon { origin, .. }
+            | Account::Misskey { origin, .. } => origin,
+            Account::Twitter { .. } => crate::protocols::twitter_client::ORIGIN,
+        }
+    }
+}
+
+/** 対象の投稿が転送されていない repost の扱い */
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RepostFallback {
+    #[default]
+    Skip,
+    /** 対象の投稿へのリンクを投稿する */
+    Link,
+    /** 対象の投稿の本文を引用して投稿する */
+    Quote,
+}
+
+/** メディアを転送できなかった投稿の扱い */
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MediaFailure {
+    /** 間隔を空けて数回まで再送し、それでも転送できなければ投稿を諦める */
+    #[default]
+    Fail,
+    /** 転送できなかったメディアを除き、代わりにその URL を本文の末尾に付けて投稿する */
+    DropAndLink,
+}
+
+/** 取得範囲外の投稿へのリプライの扱い */
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ReplyPolicy {
+    #[default]
+    Skip,
+    /** 返信先へのリンクを付けて、単独の投稿にする */
+    Standalone,
+    /** 宛先のサーバーで返信先を解決してスレッドにする。解決できなければ Standalone と同じ */
+    Resolve,
+}
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Regex, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Regex::new(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
+fn deserialize_regex_option<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| Regex::new(&s).map_err(de::Error::custom))
+        .transpose()
+}
+
+fn deserialize_time<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    NaiveTime::parse_from_str(&String::deserialize(deserializer)?, "%H:%M")
+        .map_err(de::Error::custom)
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum StatusKind {
+    Post,
+    Reply,
+    Repost,
+}
+
+fn default_time_zone() -> Tz {
+    Tz::UTC
+}
+
+/** start から end までの時間帯。start > end なら日をまたぐ */
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeOfDay {
+    #[serde(deserialize_with = "deserialize_time")]
+    pub start: NaiveTime,
+    #[serde(deserialize_with = "deserialize_time")]
+    pub end: NaiveTime,
+    /** "Asia/Tokyo" の形式。夏時間も考慮する */
+    #[serde(default = "default_time_zone")]
+    pub time_zone: Tz,
+}
+
+impl TimeOfDay {
+    pub fn contains(&self, at: &DateTime<FixedOffset>) -> bool {
+        let time = at.with_timezone(&self.time_zone).time();
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RuleAction {
+    Include,
+    Exclude,
+}
+
+/**
+ * 指定した条件を全て満たす status に一致する。
+ * 配列の条件は、いずれかの要素に一致すれば満たす
+ */
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Rule {
+    pub action: RuleAction,
+    /** ログに出す名前 */
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_regex_option")]
+    pub content: Option<Regex>,
+    /** # を除いたハッシュタグ。大文字小文字は区別しない */
+    #[serde(default)]
+    pub hashtags: Vec<String>,
+    /** サブドメインにも一致する */
+    #[serde(default)]
+    pub link_domains: Vec<String>,
+    #[serde(default)]
+    pub has_media: Option<bool>,
+    #[serde(default)]
+    pub kinds: Vec<StatusKind>,
+    /** 元のサーバーでの公開範囲。repost には一致しない */
+    #[serde(default)]
+    pub visibilities: Vec<String>,
+    #[serde(default)]
+    pub time_of_day: Option<TimeOfDay>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Replacement {
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub pattern: Regex,
+    /** $1 などでキャプチャを参照できる */
+    pub replacement: String,
+}
+
+/** 宛先ごとの本文の書式 */
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Template {
+    /**
+     * {content} を置換後の本文に、{srcUri} を元の投稿の URL (Bluesky は bsky.app の URL) に置き換える。
+     * 省略時は本文のみ。本文は宛先の上限に収まるように切り詰める
+     */
+    #[serde(default)]
+    pub format: Option<String>,
+    /** 本文に順に適用する。リンクにかかる箇所は置換しない */
+    #[serde(default)]
+    pub replacements: Vec<Replacement>,
+}
+
+/** 宛先で新しく作る status の数の上限。repost も含む */
+#[derive(Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Quota {
+    /** 直近1時間での上限 */
+    #[serde(default)]
+    pub per_hour: Option<u32>,
+    /** 直近24時間での上限 */
+    #[serde(default)]
+    pub per_day: Option<u32>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Destination {
+    #[serde(flatten)]
+    pub account: Account,
+    #[serde(default)]
+    pub repost_fallback: RepostFallback,
+    /** 省略時は User の設定に従う */
+    #[serde(default)]
+    pub reply_policy: Option<ReplyPolicy>,
+    /**
+     * include のルールがあれば、いずれかに一致するものだけを送信する。
+     * exclude のルールに一つでも一致するものは送信しない
+     */
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    #[serde(default)]
+    pub template: Option<Template>,
+    /** 新しい投稿を送信するまでの猶予 (秒)。この間の削除や編集は送信前に反映する */
+    #[serde(default)]
+    pub delay_seconds: u64,
+    /**
+     * 0 以外なら、この秒数以内に他の取得元から送信された同じ内容の投稿があれば、送信せずにそれを対応付ける
+     */
+    #[serde(default)]
+    pub dedup_window_seconds: u64,
+    #[serde(default)]
+    pub media_failure: MediaFailure,
+    /** 上限を超える投稿は、失敗にせず次回以降に回す */
+    #[serde(default)]
+    pub quota: Quota,
+    /** この時間帯の投稿は次回以降に回す。削除などは送信する */
+    #[serde(default)]
+    pub quiet_hours: Vec<TimeOfDay>,
+}
+
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct User {
     pub src: Account,
-    pub dsts: Vec<Account>,
+    pub dsts: Vec<Destination>,
+    /** 新しい status は送らず、retract などで登録済みの operation だけを送信する宛先 */
+    #[serde(default)]
+    pub retired_dsts: Vec<Destination>,
+    #[serde(default)]
+    pub reply_policy: ReplyPolicy,
+    /** like も宛先に反映する */
+    #[serde(default)]
+    pub sync_likes: bool,
+    /** 表示名、自己紹介、アイコン、ヘッダー画像を宛先に反映する */
+    #[serde(default)]
+    pub sync_profile: bool,
+    /** 固定した投稿を宛先でも固定する */
+    #[serde(default)]
+    pub sync_pins: bool,
+}
+
+impl User {
+    pub fn reply_policy(&self, dst: &Destination) -> ReplyPolicy {
+        dst.reply_policy.unwrap_or(self.reply_policy)
+    }
+}
+
+fn default_max_concurrent_destinations() -> usize {
+    4
+}
+
+fn default_job_operations_per_run() -> usize {
+    5
+}
+
+fn default_reconcile_statuses_per_run() -> usize {
+    5
+}
+
+fn default_sweep_archived_posts_per_run() -> usize {
+    10
+}
+
+fn default_run_timeout_seconds() -> u64 {
+    80
+}
+
+fn default_commit_reserve_seconds() -> u64 {
+    15
+}
+
+fn default_http_timeout_seconds() -> u64 {
+    20
+}
+
+fn default_archive_retention_days() -> u32 {
+    365
+}
+
+fn default_media_cache_max_age_hours() -> u64 {
+    24
 }
 
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Config {
     pub users: Vec<User>,
+    /** 同時に送信処理を行う宛先の最大数 */
+    #[serde(default = "default_max_concurrent_destinations")]
+    pub max_concurrent_destinations: usize,
+    /** バックフィルなどのジョブから、1回の実行で AccountPair ごとに送信キューへ積む operation の数 */
+    #[serde(default = "default_job_operations_per_run")]
+    pub job_operations_per_run: usize,
+    /** 1回の実行で、宛先ごとに存在を確認する送信済みの status の数。0 なら確認しない */
+    #[serde(default = "default_reconcile_statuses_per_run")]
+    pub reconcile_statuses_per_run: usize,
+    /** 1回の実行で、取得元ごとに削除されていないか確認する archive の post の数。0 なら確認しない */
+    #[serde(default = "default_sweep_archived_posts_per_run")]
+    pub sweep_archived_posts_per_run: usize,
+    /** 取得範囲から外れた status との対応を archive に残す日数 */
+    #[serde(default = "default_archive_retention_days")]
+    pub archive_retention_days: u32,
+    /** 実行の制限時間。Lambda では呼び出しの期限を使う */
+    #[serde(default = "default_run_timeout_seconds")]
+    pub run_timeout_seconds: u64,
+    /** 制限時間のうち、store の commit のために残しておく秒数 */
+    #[serde(default = "default_commit_reserve_seconds")]
+    pub commit_reserve_seconds: u64,
+    /** 一つの通信の timeout */
+    #[serde(default = "default_http_timeout_seconds")]
+    pub http_timeout_seconds: u64,
+    /** 取得したメディアを実行をまたいで保持するディレクトリ。無ければ実行中だけ保持する */
+    #[serde(default)]
+    pub media_cache_dir: Option<String>,
+    /** ディスクに保持したメディアを、書き込んでからこの時間で消す */
+    #[serde(default = "default_media_cache_max_age_hours")]
+    pub media_cache_max_age_hours: u64,
+}
+
+impl Config {
+    pub fn http_timeout(&self) -> Duration {
+        Duration::from_secs(self.http_timeout_seconds)
+    }
+
+    pub fn media_cache_dir(&self) -> Option<PathBuf> {
+        self.media_cache_dir.as_ref().map(PathBuf::from)
+    }
+
+    pub fn media_cache_max_age(&self) -> Duration {
+        Duration::from_secs(self.media_cache_max_age_hours * 60 * 60)
+    }
 }
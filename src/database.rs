@@ -1,25 +1,104 @@
-use std::{collections::HashMap, time::Duration};
+pub mod sqlite;
+
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fmt,
+    io::ErrorKind,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime},
+};
 
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use aws_config::BehaviorVersion;
+use aws_sdk_dynamodb::{
+    operation::transact_write_items::TransactWriteItemsError,
+    types::{ConditionCheck, Delete, Put, TransactWriteItem},
+};
 use config::FileFormat;
 use serde::{Deserialize, Serialize};
 use serde_dynamo::{from_item, to_attribute_value, to_item};
 use tokio::{fs, time::sleep};
-use tracing::{error, info};
+use tracing::{error, info, warn};
+
+use crate::{
+    config::Config,
+    store::{
+        self,
+        archive::{Archive, ArchivedStatus},
+    },
+};
+
+/** 他の実行と store の更新が衝突した */
+#[derive(Debug)]
+pub struct Conflict;
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("store is updated by another run")
+    }
+}
 
-use crate::{config::Config, store};
+impl std::error::Error for Conflict {}
 
 #[async_trait]
 pub trait Database: Send + Sync + 'static {
     async fn config(&self) -> Result<Config>;
     async fn fetch(&self) -> Result<store::Store>;
     async fn commit(&self, store: &store::Store) -> Result<()>;
+    /** commit せずに終える */
+    async fn rollback(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 pub struct File;
 
+impl File {
+    const LOCK_FILE_PATH: &'static str = "store.json.lock";
+    const ARCHIVE_FILE_PATH: &'static str = "archive.json";
+    /** これより古いロックファイルは異常終了した実行の残骸とみなす */
+    const STALE_LOCK_DURATION: Duration = Duration::from_secs(10 * 60);
+
+    async fn is_stale_lock() -> Result<bool> {
+        let modified = fs::metadata(Self::LOCK_FILE_PATH).await?.modified()?;
+        Ok(SystemTime::now()
+            .duration_since(modified)
+            .is_ok_and(|elapsed| elapsed > Self::STALE_LOCK_DURATION))
+    }
+
+    async fn lock() -> Result<()> {
+        let result = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(Self::LOCK_FILE_PATH)
+            .await;
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                if !Self::is_stale_lock().await? {
+                    return Err(Conflict.into());
+                }
+                warn!("stale lock file found, take over it");
+                fs::write(Self::LOCK_FILE_PATH, "").await?;
+                Ok(())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn unlock() -> Result<()> {
+        match fs::remove_file(Self::LOCK_FILE_PATH).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
 #[async_trait]
 impl Database for File {
     async fn config(&self) -> Result<Config> {
@@ -30,12 +109,29 @@ impl Database for File {
     }
 
     async fn fetch(&self) -> Result<store::Store> {
-        let store = serde_json::from_str(&fs::read_to_string("store.json").await?)?;
+        Self::lock().await?;
+        let mut store: store::Store =
+            serde_json::from_str(&fs::read_to_string("store.json").await?)?;
+        store.archive = match fs::read_to_string(Self::ARCHIVE_FILE_PATH).await {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => Archive::default(),
+            Err(err) => return Err(err.into()),
+        };
         Ok(store)
     }
 
     async fn commit(&self, store: &store::Store) -> Result<()> {
-        Ok(fs::write("store.json", serde_json::to_string_pretty(store)?).await?)
+        fs::write("store.json", serde_json::to_string_pretty(store)?).await?;
+        fs::write(
+            Self::ARCHIVE_FILE_PATH,
+            serde_json::to_string_pretty(&store.archive)?,
+        )
+        .await?;
+        Self::unlock().await
+    }
+
+    async fn rollback(&self) -> Result<()> {
+        Self::unlock().await
     }
 }
 
@@ -51,17 +147,204 @@ pub struct DynamoDBConfig {
 pub struct DynamoDBStore {
     id: u64,
     store: String,
+    #[serde(default)]
+    version: u64,
+}
+
+/** 以前の、store と同じ table の一つの item に保存していた archive */
+#[derive(Serialize, Deserialize)]
+pub struct DynamoDBLegacyArchive {
+    id: u64,
+    archive: String,
+}
+
+/** item の大きさの上限に収まるように、AccountPair と月ごとに分けて保存する archive */
+#[derive(Serialize, Deserialize)]
+pub struct DynamoDBArchive {
+    id: String,
+    archive: String,
+}
+
+fn archive_item_id(archived: &ArchivedStatus) -> String {
+    let account_pair = &archived.account_pair;
+    format!(
+        "{} {} {} {} {}",
+        account_pair.src_origin,
+        account_pair.src_account_identifier,
+        account_pair.dst_origin,
+        account_pair.dst_account_identifier,
+        archived.archived_at.format("%Y-%m")
+    )
+}
+
+/** archive を item ごとに分ける。item の中では新しい順を保つ */
+fn split_archive(archive: &Archive) -> Result<HashMap<String, String>> {
+    let mut items: HashMap<_, Archive> = HashMap::new();
+    for archived in &archive.statuses {
+        items
+            .entry(archive_item_id(archived))
+            .or_default()
+            .statuses
+            .push(archived.clone());
+    }
+    items
+        .into_iter()
+        .map(|(id, archive)| Ok((id, serde_json::to_string(&archive)?)))
+        .collect()
 }
 
 pub struct DynamoDB {
     client: aws_sdk_dynamodb::Client,
+    /** fetch した時点の archive の item。変わったものだけを書き込むために使う */
+    archive_items: Mutex<HashMap<String, String>>,
+    /** 以前の形式の archive を読み込んだ */
+    has_legacy_archive: AtomicBool,
 }
 
 impl DynamoDB {
+    const LEGACY_ARCHIVE_ID: u64 = 1;
+    /** commit の制限時間に収まるように、再試行は数回に留める */
+    const MAX_COMMIT_RETRIES: usize = 2;
+    const COMMIT_RETRY_INTERVAL: Duration = Duration::from_secs(3);
+    /** TransactWriteItems で一度に書き込める item の数 */
+    const MAX_TRANSACT_ITEMS: usize = 100;
+
     pub async fn new() -> Self {
         let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
         let client = aws_sdk_dynamodb::Client::new(&config);
-        Self { client }
+        Self {
+            client,
+            archive_items: Mutex::default(),
+            has_legacy_archive: AtomicBool::new(false),
+        }
+    }
+
+    async fn fetch_legacy_archive(&self) -> Result<Option<Archive>> {
+        let output = self
+            .client
+            .get_item()
+            .table_name("Store")
+            .set_key(Some(HashMap::from([(
+                "id".into(),
+                to_attribute_value(Self::LEGACY_ARCHIVE_ID)?,
+            )])))
+            .send()
+            .await?;
+        let Some(item) = output.item() else {
+            return Ok(None);
+        };
+        let item: DynamoDBLegacyArchive = from_item(item.clone())?;
+        Ok(Some(serde_json::from_str(&item.archive)?))
+    }
+
+    async fn fetch_archive(&self) -> Result<Archive> {
+        let items = self
+            .client
+            .scan()
+            .table_name("Archive")
+            .into_paginator()
+            .items()
+            .send()
+            .try_collect()
+            .await?;
+        let mut archive_items = HashMap::new();
+        let mut archive = Archive::default();
+        for item in items {
+            let item: DynamoDBArchive = from_item(item)?;
+            let part: Archive = serde_json::from_str(&item.archive)?;
+            archive.statuses.extend(part.statuses);
+            archive_items.insert(item.id, item.archive);
+        }
+        let legacy_archive = self.fetch_legacy_archive().await?;
+        self.has_legacy_archive
+            .store(legacy_archive.is_some(), Ordering::Relaxed);
+        if let Some(legacy_archive) = legacy_archive {
+            info!("legacy archive found, it will be migrated on commit");
+            for archived in legacy_archive.statuses {
+                archive.insert(archived);
+            }
+        }
+        archive
+            .statuses
+            .sort_by_key(|archived| Reverse(archived.archived_at));
+        *self.archive_items.lock().unwrap() = archive_items;
+        Ok(archive)
+    }
+
+    /** archive の変わった item を書き込む操作と、書き込んだ後の item を返す */
+    fn archive_writes(
+        &self,
+        archive: &Archive,
+    ) -> Result<(Vec<TransactWriteItem>, HashMap<String, String>)> {
+        let items = split_archive(archive)?;
+        let fetched_items = self.archive_items.lock().unwrap().clone();
+        let mut writes = Vec::new();
+        for (id, json) in &items {
+            if fetched_items.get(id) == Some(json) {
+                continue;
+            }
+            let item: HashMap<_, _> = to_item(DynamoDBArchive {
+                id: id.clone(),
+                archive: json.clone(),
+            })?;
+            let put = Put::builder()
+                .table_name("Archive")
+                .set_item(Some(item))
+                .build()?;
+            writes.push(TransactWriteItem::builder().put(put).build());
+        }
+        for id in fetched_items.keys() {
+            if items.contains_key(id) {
+                continue;
+            }
+            let delete = Delete::builder()
+                .table_name("Archive")
+                .key("id", to_attribute_value(id)?)
+                .build()?;
+            writes.push(TransactWriteItem::builder().delete(delete).build());
+        }
+        if self.has_legacy_archive.load(Ordering::Relaxed) {
+            let delete = Delete::builder()
+                .table_name("Store")
+                .key("id", to_attribute_value(Self::LEGACY_ARCHIVE_ID)?)
+                .build()?;
+            writes.push(TransactWriteItem::builder().delete(delete).build());
+        }
+        Ok((writes, items))
+    }
+
+    /** store の version の条件を満たさなければ Conflict にする */
+    async fn transact_write(&self, items: Vec<TransactWriteItem>) -> Result<()> {
+        let mut retries = 0;
+        loop {
+            let res = self
+                .client
+                .transact_write_items()
+                .set_transact_items(Some(items.clone()))
+                .send()
+                .await;
+            let Err(err) = res else {
+                return Ok(());
+            };
+            if let Some(TransactWriteItemsError::TransactionCanceledException(err)) =
+                err.as_service_error()
+            {
+                if err
+                    .cancellation_reasons()
+                    .iter()
+                    .any(|reason| reason.code() == Some("ConditionalCheckFailed"))
+                {
+                    return Err(Conflict.into());
+                }
+            }
+            if retries >= Self::MAX_COMMIT_RETRIES {
+                return Err(err.into());
+            }
+            retries += 1;
+            error!("{:?}", err);
+            info!("sleep {:?}...", Self::COMMIT_RETRY_INTERVAL);
+            sleep(Self::COMMIT_RETRY_INTERVAL).await;
+        }
     }
 }
 
@@ -92,33 +375,53 @@ impl Database for DynamoDB {
             .await?;
         let item = output.item().ok_or_else(|| anyhow!("object not found"))?;
         let root: DynamoDBStore = from_item(item.clone())?;
-        Ok(serde_json::from_str(&root.store)?)
+        let mut store: store::Store = serde_json::from_str(&root.store)?;
+        store.version = root.version;
+        store.archive = self.fetch_archive().await?;
+        Ok(store)
     }
 
     #[tracing::instrument(name = "dynamodb::Database::commit", skip_all)]
     async fn commit(&self, store: &store::Store) -> Result<()> {
         info!("commit to dynamodb...");
+        let archive = &store.archive;
+        let version = store.version;
         let store = DynamoDBStore {
             id: 0,
             store: serde_json::to_string(&store)?,
+            version: store.version + 1,
         };
         let item: HashMap<_, _> = to_item(store)?;
-        loop {
-            let res = self
-                .client
-                .put_item()
+        let store_version_condition = "attribute_not_exists(version) OR version = :version";
+        let store_put = Put::builder()
+            .table_name("Store")
+            .set_item(Some(item))
+            .condition_expression(store_version_condition)
+            .expression_attribute_values(":version", to_attribute_value(version)?)
+            .build()?;
+        let (mut archive_writes, archive_items) = self.archive_writes(archive)?;
+        // store と archive を一つの transaction で書き込み、他の実行の archive を上書きしないようにする。
+        // 一度に書き込める数を超える分は、store が fetch した時点のままであることを条件に先に書き込む
+        while archive_writes.len() >= Self::MAX_TRANSACT_ITEMS {
+            let rest = archive_writes.split_off(Self::MAX_TRANSACT_ITEMS - 1);
+            let condition_check = ConditionCheck::builder()
                 .table_name("Store")
-                .set_item(Some(item.clone()))
-                .send()
-                .await;
-            if let Err(err) = res {
-                error!("{:?}", err);
-                info!("sleep 10 secs...");
-                sleep(Duration::from_secs(10)).await;
-                continue;
-            }
-            break;
+                .key("id", to_attribute_value(0)?)
+                .condition_expression(store_version_condition)
+                .expression_attribute_values(":version", to_attribute_value(version)?)
+                .build()?;
+            archive_writes.push(
+                TransactWriteItem::builder()
+                    .condition_check(condition_check)
+                    .build(),
+            );
+            self.transact_write(archive_writes).await?;
+            archive_writes = rest;
         }
+        archive_writes.push(TransactWriteItem::builder().put(store_put).build());
+        self.transact_write(archive_writes).await?;
+        self.has_legacy_archive.store(false, Ordering::Relaxed);
+        *self.archive_items.lock().unwrap() = archive_items;
         info!("commit succeeded");
         Ok(())
     }
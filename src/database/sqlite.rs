@@ -0,0 +1,742 @@
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset, SecondsFormat};
+use config::FileFormat;
+use rusqlite::{params, Connection, OptionalExtension, Row, Transaction, TransactionBehavior};
+use tokio::task::spawn_blocking;
+use tracing::info;
+
+use crate::{
+    config::Config,
+    store::{
+        self,
+        archive::{Archive, ArchivedStatus},
+        operations::AccountPair,
+        user::{
+            Destination, DestinationLike, DestinationPost, DestinationRepost, DestinationStatus,
+            Source, SourcePost, SourceRepost, SourceStatus, User,
+        },
+    },
+};
+
+use super::{Conflict, Database};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS config (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    json TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS store (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    version INTEGER NOT NULL,
+    jobs TEXT NOT NULL,
+    usages TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS sources (
+    id INTEGER PRIMARY KEY,
+    origin TEXT NOT NULL,
+    identifier TEXT NOT NULL,
+    likes TEXT NOT NULL,
+    profile_hash TEXT,
+    pinned_identifiers TEXT,
+    sweep_offset INTEGER NOT NULL,
+    UNIQUE (origin, identifier)
+);
+CREATE TABLE IF NOT EXISTS source_statuses (
+    source_id INTEGER NOT NULL REFERENCES sources (id),
+    position INTEGER NOT NULL,
+    type TEXT NOT NULL,
+    identifier TEXT NOT NULL,
+    content TEXT,
+    target_identifier TEXT,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (source_id, position)
+);
+CREATE TABLE IF NOT EXISTS destinations (
+    id INTEGER PRIMARY KEY,
+    source_id INTEGER NOT NULL REFERENCES sources (id),
+    origin TEXT NOT NULL,
+    identifier TEXT NOT NULL,
+    resolved_statuses TEXT NOT NULL,
+    fingerprints TEXT NOT NULL,
+    reconcile_offset INTEGER NOT NULL,
+    UNIQUE (source_id, origin, identifier)
+);
+CREATE TABLE IF NOT EXISTS destination_statuses (
+    destination_id INTEGER NOT NULL REFERENCES destinations (id),
+    position INTEGER NOT NULL,
+    type TEXT NOT NULL,
+    identifier TEXT NOT NULL,
+    src_identifier TEXT NOT NULL,
+    src_uri TEXT,
+    created_at TEXT,
+    is_fallback_post INTEGER NOT NULL,
+    degraded_media TEXT,
+    PRIMARY KEY (destination_id, position)
+);
+CREATE TABLE IF NOT EXISTS archived_statuses (
+    position INTEGER PRIMARY KEY,
+    src_origin TEXT NOT NULL,
+    src_account_identifier TEXT NOT NULL,
+    dst_origin TEXT NOT NULL,
+    dst_account_identifier TEXT NOT NULL,
+    type TEXT NOT NULL,
+    identifier TEXT NOT NULL,
+    src_identifier TEXT NOT NULL,
+    src_uri TEXT,
+    created_at TEXT,
+    is_fallback_post INTEGER NOT NULL,
+    degraded_media TEXT,
+    archived_at TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS operations (
+    position INTEGER PRIMARY KEY,
+    src_origin TEXT NOT NULL,
+    src_account_identifier TEXT NOT NULL,
+    dst_origin TEXT NOT NULL,
+    dst_account_identifier TEXT NOT NULL,
+    src_identifier TEXT NOT NULL,
+    operation TEXT NOT NULL
+);
+";
+
+fn format_date(date: &DateTime<FixedOffset>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn parse_date(date: &str) -> Result<DateTime<FixedOffset>> {
+    Ok(DateTime::parse_from_rfc3339(date)?)
+}
+
+/** destination_statuses と archived_statuses に共通する、宛先の status の列 */
+struct DestinationStatusRow {
+    kind: String,
+    identifier: String,
+    src_identifier: String,
+    src_uri: Option<String>,
+    created_at: Option<String>,
+    is_fallback_post: bool,
+    degraded_media: Option<String>,
+}
+
+impl DestinationStatusRow {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            kind: row.get("type")?,
+            identifier: row.get("identifier")?,
+            src_identifier: row.get("src_identifier")?,
+            src_uri: row.get("src_uri")?,
+            created_at: row.get("created_at")?,
+            is_fallback_post: row.get("is_fallback_post")?,
+            degraded_media: row.get("degraded_media")?,
+        })
+    }
+
+    fn from_status(status: &DestinationStatus) -> Result<Self> {
+        Ok(match status {
+            DestinationStatus::Post(post) => Self {
+                kind: "post".into(),
+                identifier: post.identifier.clone(),
+                src_identifier: post.src_identifier.clone(),
+                src_uri: Some(post.src_uri.clone()),
+                created_at: post.created_at.as_ref().map(format_date),
+                is_fallback_post: false,
+                degraded_media: (!post.degraded_media.is_empty())
+                    .then(|| serde_json::to_string(&post.degraded_media))
+                    .transpose()?,
+            },
+            DestinationStatus::Repost(repost) => Self {
+                kind: "repost".into(),
+                identifier: repost.identifier.clone(),
+                src_identifier: repost.src_identifier.clone(),
+                src_uri: None,
+                created_at: repost.created_at.as_ref().map(format_date),
+                is_fallback_post: repost.is_fallback_post,
+                degraded_media: None,
+            },
+            DestinationStatus::Like(like) => Self {
+                kind: "like".into(),
+                identifier: like.identifier.clone(),
+                src_identifier: like.src_identifier.clone(),
+                src_uri: None,
+                created_at: None,
+                is_fallback_post: false,
+                degraded_media: None,
+            },
+        })
+    }
+
+    fn into_status(self) -> Result<DestinationStatus> {
+        let created_at = self.created_at.as_deref().map(parse_date).transpose()?;
+        Ok(match self.kind.as_str() {
+            "post" => DestinationStatus::Post(DestinationPost {
+                identifier: self.identifier,
+                src_identifier: self.src_identifier,
+                src_uri: self.src_uri.unwrap_or_default(),
+                created_at,
+                degraded_media: self
+                    .degraded_media
+                    .as_deref()
+                    .map(serde_json::from_str)
+                    .transpose()?
+                    .unwrap_or_default(),
+            }),
+            "repost" => DestinationStatus::Repost(DestinationRepost {
+                identifier: self.identifier,
+                src_identifier: self.src_identifier,
+                is_fallback_post: self.is_fallback_post,
+                created_at,
+            }),
+            "like" => DestinationStatus::Like(DestinationLike {
+                identifier: self.identifier,
+                src_identifier: self.src_identifier,
+            }),
+            kind => bail!("unknown destination status type: {}", kind),
+        })
+    }
+}
+
+fn fetch_source_statuses(conn: &Connection, source_id: i64) -> Result<Vec<SourceStatus>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT type, identifier, content, target_identifier, created_at
+        FROM source_statuses WHERE source_id = ?1 ORDER BY position",
+    )?;
+    let rows = stmt.query_map([source_id], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, Option<String>>(2)?,
+            row.get::<_, Option<String>>(3)?,
+            row.get::<_, String>(4)?,
+        ))
+    })?;
+    rows.map(|row| {
+        let (kind, identifier, content, target_identifier, created_at) = row?;
+        let created_at = parse_date(&created_at)?;
+        Ok(match (kind.as_str(), content, target_identifier) {
+            ("post", Some(content), _) => SourceStatus::Post(SourcePost {
+                identifier,
+                content,
+                created_at,
+            }),
+            ("repost", _, Some(target_identifier)) => SourceStatus::Repost(SourceRepost {
+                identifier,
+                target_identifier,
+                created_at,
+            }),
+            _ => bail!("invalid source status: {}", identifier),
+        })
+    })
+    .collect()
+}
+
+fn fetch_destination_statuses(
+    conn: &Connection,
+    destination_id: i64,
+) -> Result<Vec<DestinationStatus>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT * FROM destination_statuses WHERE destination_id = ?1 ORDER BY position",
+    )?;
+    let rows = stmt.query_map([destination_id], DestinationStatusRow::from_row)?;
+    rows.map(|row| row?.into_status()).collect()
+}
+
+fn fetch_destinations(conn: &Connection, source_id: i64) -> Result<Vec<Destination>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT id, origin, identifier, resolved_statuses, fingerprints, reconcile_offset
+        FROM destinations WHERE source_id = ?1 ORDER BY id",
+    )?;
+    let rows = stmt.query_map([source_id], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, String>(4)?,
+            row.get::<_, usize>(5)?,
+        ))
+    })?;
+    rows.map(|row| {
+        let (id, origin, identifier, resolved_statuses, fingerprints, reconcile_offset) = row?;
+        Ok(Destination {
+            origin,
+            identifier,
+            statuses: fetch_destination_statuses(conn, id)?,
+            resolved_statuses: serde_json::from_str(&resolved_statuses)?,
+            fingerprints: serde_json::from_str(&fingerprints)?,
+            reconcile_offset,
+        })
+    })
+    .collect()
+}
+
+fn fetch_users(conn: &Connection) -> Result<Vec<User>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, origin, identifier, likes, profile_hash, pinned_identifiers, sweep_offset
+        FROM sources ORDER BY id",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, Option<String>>(4)?,
+            row.get::<_, Option<String>>(5)?,
+            row.get::<_, usize>(6)?,
+        ))
+    })?;
+    rows.map(|row| {
+        let (id, origin, identifier, likes, profile_hash, pinned_identifiers, sweep_offset) = row?;
+        Ok(User {
+            src: Source {
+                origin,
+                identifier,
+                statuses: fetch_source_statuses(conn, id)?,
+                likes: serde_json::from_str(&likes)?,
+                profile_hash,
+                pinned_identifiers: pinned_identifiers
+                    .as_deref()
+                    .map(serde_json::from_str)
+                    .transpose()?,
+                sweep_offset,
+            },
+            dsts: fetch_destinations(conn, id)?,
+        })
+    })
+    .collect()
+}
+
+fn fetch_archive(conn: &Connection) -> Result<Archive> {
+    let mut stmt = conn.prepare("SELECT * FROM archived_statuses ORDER BY position")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            AccountPair {
+                src_origin: row.get("src_origin")?,
+                src_account_identifier: row.get("src_account_identifier")?,
+                dst_origin: row.get("dst_origin")?,
+                dst_account_identifier: row.get("dst_account_identifier")?,
+            },
+            DestinationStatusRow::from_row(row)?,
+            row.get::<_, String>("archived_at")?,
+        ))
+    })?;
+    let statuses = rows
+        .map(|row| {
+            let (account_pair, status, archived_at) = row?;
+            Ok(ArchivedStatus {
+                account_pair,
+                status: status.into_status()?,
+                archived_at: parse_date(&archived_at)?,
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok(Archive { statuses })
+}
+
+fn fetch_store(conn: &Connection) -> Result<store::Store> {
+    let mut store = store::Store::default();
+    let root = conn
+        .query_row(
+            "SELECT version, jobs, usages FROM store WHERE id = 0",
+            [],
+            |row| {
+                Ok((
+                    row.get::<_, u64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            },
+        )
+        .optional()?;
+    if let Some((version, jobs, usages)) = root {
+        store.version = version;
+        store.jobs = serde_json::from_str(&jobs)?;
+        store.usages = serde_json::from_str(&usages)?;
+    }
+    store.users = fetch_users(conn)?;
+    let mut stmt = conn.prepare("SELECT operation FROM operations ORDER BY position")?;
+    store.operations = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .map(|operation| Ok(serde_json::from_str(&operation?)?))
+        .collect::<Result<_>>()?;
+    store.archive = fetch_archive(conn)?;
+    Ok(store)
+}
+
+fn insert_destination_status(
+    tx: &Transaction,
+    destination_id: i64,
+    position: usize,
+    status: &DestinationStatus,
+) -> Result<()> {
+    let row = DestinationStatusRow::from_status(status)?;
+    tx.prepare_cached(
+        "INSERT INTO destination_statuses (destination_id, position, type, identifier,
+        src_identifier, src_uri, created_at, is_fallback_post, degraded_media)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+    )?
+    .execute(params![
+        destination_id,
+        position,
+        row.kind,
+        row.identifier,
+        row.src_identifier,
+        row.src_uri,
+        row.created_at,
+        row.is_fallback_post,
+        row.degraded_media,
+    ])?;
+    Ok(())
+}
+
+fn insert_user(tx: &Transaction, user: &User) -> Result<()> {
+    let src = &user.src;
+    tx.prepare_cached(
+        "INSERT INTO sources (origin, identifier, likes, profile_hash, pinned_identifiers,
+        sweep_offset) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?
+    .execute(params![
+        src.origin,
+        src.identifier,
+        serde_json::to_string(&src.likes)?,
+        src.profile_hash,
+        src.pinned_identifiers
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()?,
+        src.sweep_offset,
+    ])?;
+    let source_id = tx.last_insert_rowid();
+    for (position, status) in src.statuses.iter().enumerate() {
+        let (kind, identifier, content, target_identifier) = match status {
+            SourceStatus::Post(post) => ("post", &post.identifier, Some(&post.content), None),
+            SourceStatus::Repost(repost) => (
+                "repost",
+                &repost.identifier,
+                None,
+                Some(&repost.target_identifier),
+            ),
+        };
+        tx.prepare_cached(
+            "INSERT INTO source_statuses (source_id, position, type, identifier, content,
+            target_identifier, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?
+        .execute(params![
+            source_id,
+            position,
+            kind,
+            identifier,
+            content,
+            target_identifier,
+            format_date(status.created_at()),
+        ])?;
+    }
+    for dst in &user.dsts {
+        tx.prepare_cached(
+            "INSERT INTO destinations (source_id, origin, identifier, resolved_statuses,
+            fingerprints, reconcile_offset) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?
+        .execute(params![
+            source_id,
+            dst.origin,
+            dst.identifier,
+            serde_json::to_string(&dst.resolved_statuses)?,
+            serde_json::to_string(&dst.fingerprints)?,
+            dst.reconcile_offset,
+        ])?;
+        let destination_id = tx.last_insert_rowid();
+        for (position, status) in dst.statuses.iter().enumerate() {
+            insert_destination_status(tx, destination_id, position, status)?;
+        }
+    }
+    Ok(())
+}
+
+fn insert_archived_status(
+    tx: &Transaction,
+    position: usize,
+    archived: &ArchivedStatus,
+) -> Result<()> {
+    let row = DestinationStatusRow::from_status(&archived.status)?;
+    let account_pair = &archived.account_pair;
+    tx.prepare_cached(
+        "INSERT INTO archived_statuses (position, src_origin, src_account_identifier,
+        dst_origin, dst_account_identifier, type, identifier, src_identifier, src_uri,
+        created_at, is_fallback_post, degraded_media, archived_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+    )?
+    .execute(params![
+        position,
+        account_pair.src_origin,
+        account_pair.src_account_identifier,
+        account_pair.dst_origin,
+        account_pair.dst_account_identifier,
+        row.kind,
+        row.identifier,
+        row.src_identifier,
+        row.src_uri,
+        row.created_at,
+        row.is_fallback_post,
+        row.degraded_media,
+        format_date(&archived.archived_at),
+    ])?;
+    Ok(())
+}
+
+/** version が fetch したときのままなら、全体を書き換える */
+fn commit_store(tx: &Transaction, store: &store::Store) -> Result<()> {
+    let updated = tx.execute(
+        "INSERT INTO store (id, version, jobs, usages) VALUES (0, ?1, ?3, ?4)
+        ON CONFLICT (id) DO UPDATE SET
+            version = excluded.version, jobs = excluded.jobs, usages = excluded.usages
+        WHERE store.version = ?2",
+        params![
+            store.version + 1,
+            store.version,
+            serde_json::to_string(&store.jobs)?,
+            serde_json::to_string(&store.usages)?,
+        ],
+    )?;
+    if updated == 0 {
+        return Err(Conflict.into());
+    }
+    tx.execute_batch(
+        "DELETE FROM destination_statuses;
+        DELETE FROM destinations;
+        DELETE FROM source_statuses;
+        DELETE FROM sources;
+        DELETE FROM operations;
+        DELETE FROM archived_statuses;",
+    )?;
+    for user in &store.users {
+        insert_user(tx, user)?;
+    }
+    for (position, operation) in store.operations.iter().enumerate() {
+        let account_pair = operation.account_pair();
+        tx.prepare_cached(
+            "INSERT INTO operations (position, src_origin, src_account_identifier, dst_origin,
+            dst_account_identifier, src_identifier, operation)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?
+        .execute(params![
+            position,
+            account_pair.src_origin,
+            account_pair.src_account_identifier,
+            account_pair.dst_origin,
+            account_pair.dst_account_identifier,
+            operation.src_identifier(),
+            serde_json::to_string(operation)?,
+        ])?;
+    }
+    for (position, archived) in store.archive.statuses.iter().enumerate() {
+        insert_archived_status(tx, position, archived)?;
+    }
+    Ok(())
+}
+
+/**
+ * SQLite のファイルに保存する。config も同じファイルの config テーブルに置く。
+ * DynamoDB と同じく version で楽観的排他制御をする
+ */
+pub struct Sqlite {
+    path: PathBuf,
+}
+
+impl Sqlite {
+    /** 他の実行が書き込み中のときに待つ時間 */
+    const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    fn open(path: &PathBuf) -> Result<Connection> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(Self::BUSY_TIMEOUT)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(conn)
+    }
+}
+
+#[async_trait]
+impl Database for Sqlite {
+    #[tracing::instrument(name = "sqlite::Database::config", skip_all)]
+    async fn config(&self) -> Result<Config> {
+        let path = self.path.clone();
+        let json = spawn_blocking(move || -> Result<Option<String>> {
+            let conn = Self::open(&path)?;
+            Ok(conn
+                .query_row("SELECT json FROM config WHERE id = 0", [], |row| row.get(0))
+                .optional()?)
+        })
+        .await??
+        .ok_or_else(|| anyhow!("config not found"))?;
+        Ok(::config::Config::builder()
+            .add_source(::config::File::from_str(&json, FileFormat::Json5))
+            .build()?
+            .try_deserialize()?)
+    }
+
+    #[tracing::instrument(name = "sqlite::Database::fetch", skip_all)]
+    async fn fetch(&self) -> Result<store::Store> {
+        let path = self.path.clone();
+        spawn_blocking(move || {
+            let mut conn = Self::open(&path)?;
+            // 他の実行の commit の途中を読まないように、一つの読み取りトランザクションで読む
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Deferred)?;
+            let store = fetch_store(&tx)?;
+            tx.commit()?;
+            Ok(store)
+        })
+        .await?
+    }
+
+    #[tracing::instrument(name = "sqlite::Database::commit", skip_all)]
+    async fn commit(&self, store: &store::Store) -> Result<()> {
+        info!("commit to sqlite...");
+        let path = self.path.clone();
+        let store = store.clone();
+        spawn_blocking(move || {
+            let mut conn = Self::open(&path)?;
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            commit_store(&tx, &store)?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+    use crate::store::user::Fingerprint;
+
+    /** テストごとに別のファイルを使い、終わったら消す */
+    struct TempDatabase(PathBuf);
+
+    impl TempDatabase {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("timelineecho-{}-{}.db", name, process::id()));
+            let _ = std::fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDatabase {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn date(date: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(date).unwrap()
+    }
+
+    fn account_pair() -> AccountPair {
+        AccountPair {
+            src_origin: "https://src.example".into(),
+            src_account_identifier: "src".into(),
+            dst_origin: "https://dst.example".into(),
+            dst_account_identifier: "dst".into(),
+        }
+    }
+
+    fn store() -> store::Store {
+        let mut store = store::Store::default();
+        let dst = store.get_or_create_dst_mut(&account_pair());
+        dst.statuses.push(DestinationStatus::Post(DestinationPost {
+            identifier: "dst-post".into(),
+            src_identifier: "src-post".into(),
+            src_uri: "https://src.example/post".into(),
+            created_at: Some(date("2024-01-01T00:00:00Z")),
+            degraded_media: vec!["https://src.example/image".into()],
+        }));
+        dst.statuses
+            .push(DestinationStatus::Repost(DestinationRepost {
+                identifier: "dst-repost".into(),
+                src_identifier: "src-repost".into(),
+                is_fallback_post: true,
+                created_at: None,
+            }));
+        dst.statuses.push(DestinationStatus::Like(DestinationLike {
+            identifier: "dst-like".into(),
+            src_identifier: "src-like".into(),
+        }));
+        dst.fingerprints.push(Fingerprint {
+            hash: "hash".into(),
+            identifier: "dst-post".into(),
+            created_at: date("2024-01-01T00:00:00Z"),
+        });
+        dst.reconcile_offset = 2;
+        let src = &mut store.users[0].src;
+        src.statuses.push(SourceStatus::Post(SourcePost {
+            identifier: "src-post".into(),
+            content: "hello".into(),
+            created_at: date("2024-01-01T00:00:00Z"),
+        }));
+        src.statuses.push(SourceStatus::Repost(SourceRepost {
+            identifier: "src-repost".into(),
+            target_identifier: "target".into(),
+            created_at: date("2024-01-02T00:00:00Z"),
+        }));
+        src.pinned_identifiers = Some(vec!["src-post".into()]);
+        src.sweep_offset = 1;
+        store.archive.insert(ArchivedStatus {
+            account_pair: account_pair(),
+            status: DestinationStatus::Post(DestinationPost {
+                identifier: "dst-archived".into(),
+                src_identifier: "src-archived".into(),
+                src_uri: "https://src.example/archived".into(),
+                created_at: None,
+                degraded_media: Vec::new(),
+            }),
+            archived_at: date("2024-01-03T00:00:00Z"),
+        });
+        store
+    }
+
+    fn to_json(store: &store::Store) -> (serde_json::Value, serde_json::Value) {
+        (
+            serde_json::to_value(store).unwrap(),
+            serde_json::to_value(&store.archive).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn commit_and_fetch_round_trip() {
+        let database = TempDatabase::new("round-trip");
+        let sqlite = Sqlite::new(database.0.clone());
+        let store = store();
+
+        sqlite.commit(&store).await.unwrap();
+        let fetched = sqlite.fetch().await.unwrap();
+
+        assert_eq!(fetched.version, store.version + 1);
+        assert_eq!(to_json(&fetched), to_json(&store));
+    }
+
+    #[tokio::test]
+    async fn commit_with_stale_version_conflicts() {
+        let database = TempDatabase::new("conflict");
+        let sqlite = Sqlite::new(database.0.clone());
+        let stale = sqlite.fetch().await.unwrap();
+        let mut store = sqlite.fetch().await.unwrap();
+        store.users = self::store().users;
+        sqlite.commit(&store).await.unwrap();
+
+        let err = sqlite.commit(&stale).await.unwrap_err();
+
+        assert!(err.is::<Conflict>());
+        assert_eq!(
+            to_json(&sqlite.fetch().await.unwrap()).0["users"],
+            to_json(&store).0["users"]
+        );
+    }
+}
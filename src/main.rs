@@ -35,43 +35,62 @@ fn default_subscriber_builder(
         .with_timer(NoTime)
 }
 
-#[cfg(not(target_os = "linux"))]
 mod local {
     use std::num::NonZeroU8;
 
     use anyhow::Result;
-    use time::format_description::well_known::{
-        iso8601::{self, EncodedConfig},
-        Iso8601,
+    use time::{
+        format_description::well_known::{
+            iso8601::{self, EncodedConfig},
+            Iso8601,
+        },
+        UtcOffset,
     };
-    use tracing_subscriber::fmt::time::LocalTime;
+    use tracing_subscriber::fmt::time::OffsetTime;
 
-    use crate::{app::app, database, default_subscriber_builder};
+    use crate::{
+        app::{app, Command},
+        database, default_subscriber_builder,
+    };
 
-    pub fn init_tracing() {
+    pub fn init_tracing(local_offset: UtcOffset) {
         const MY_CONFIG: EncodedConfig = iso8601::Config::DEFAULT
             .set_time_precision(iso8601::TimePrecision::Second {
                 decimal_digits: NonZeroU8::new(6),
             })
             .encode();
         default_subscriber_builder("trace")
-            .with_timer(LocalTime::new(Iso8601::<MY_CONFIG>))
+            .with_timer(OffsetTime::new(local_offset, Iso8601::<MY_CONFIG>))
             .compact()
             .init();
     }
 
-    pub async fn main() -> Result<()> {
-        init_tracing();
-
-        app(database::File).await
+    pub async fn main(local_offset: UtcOffset) -> Result<()> {
+        init_tracing(local_offset);
+
+        let json = std::env::args()
+            .nth(1)
+            .map(|arg| serde_json::from_str(&arg))
+            .transpose()?;
+        let command = Command::from_json(json)?;
+        // TIMELINEECHO_SQLITE があれば store.json の代わりに SQLite を使う
+        match std::env::var("TIMELINEECHO_SQLITE") {
+            Ok(path) => app(database::sqlite::Sqlite::new(path.into()), command, None).await,
+            Err(_) => app(database::File, command, None).await,
+        }
     }
 }
 
 mod lambda {
+    use std::time::{Duration, UNIX_EPOCH};
+
     use aws_lambda_events::event::cloudwatch_events::CloudWatchEvent;
     use lambda_runtime::{run, service_fn, LambdaEvent};
 
-    use crate::{app::app, database, default_subscriber_builder};
+    use crate::{
+        app::{app, Command},
+        database, default_subscriber_builder,
+    };
 
     pub fn init_tracing() {
         default_subscriber_builder("debug")
@@ -81,16 +100,18 @@ mod lambda {
     }
 
     pub async fn function_handler(
-        _event: LambdaEvent<CloudWatchEvent>,
+        event: LambdaEvent<CloudWatchEvent>,
     ) -> Result<(), lambda_runtime::Error> {
-        if let Err(err) = app(database::DynamoDB::new().await).await {
+        let command = Command::from_json(event.payload.detail)?;
+        // NOTE: deadline は UNIX epoch からのミリ秒
+        let deadline = UNIX_EPOCH + Duration::from_millis(event.context.deadline);
+        if let Err(err) = app(database::DynamoDB::new().await, command, Some(deadline)).await {
             tracing::error!("{:?}", err);
             return Err(err.into());
         }
         Ok(())
     }
 
-    #[allow(unused)]
     pub async fn main() -> Result<(), lambda_runtime::Error> {
         init_tracing();
 
@@ -98,14 +119,18 @@ mod lambda {
     }
 }
 
-#[cfg(not(target_os = "linux"))]
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    local::main().await
-}
-
-#[cfg(target_os = "linux")]
-#[tokio::main]
-async fn main() -> Result<(), lambda_runtime::Error> {
-    lambda::main().await
+/** Lambda の実行環境なら Lambda の関数として動き、それ以外では一度だけ実行する */
+fn main() -> anyhow::Result<()> {
+    // NOTE: Linux ではスレッドが一つのうちにしか地方時のオフセットを得られない
+    let local_offset = time::UtcOffset::current_local_offset().unwrap_or(time::UtcOffset::UTC);
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    if std::env::var_os("AWS_LAMBDA_RUNTIME_API").is_some() {
+        runtime
+            .block_on(lambda::main())
+            .map_err(|err| anyhow::anyhow!(err))
+    } else {
+        runtime.block_on(local::main(local_offset))
+    }
 }
@@ -1,6 +1,15 @@
+mod create_like;
 mod create_post;
 mod create_repost;
+mod delete_like;
 mod delete_post;
 mod delete_repost;
 pub mod destination;
+pub mod dry_run;
+mod fingerprint;
+mod pin_post;
+pub mod reconcile;
+mod template;
+mod truncate;
+mod unpin_post;
 mod utils;
@@ -0,0 +1,65 @@
+use std::sync::Mutex;
+
+use anyhow::Result;
+use tracing::info;
+
+use crate::{protocols::Client, store};
+
+use super::utils::{find_post_dst_identifier, find_post_dst_identifier_by_uri, resolve};
+
+pub async fn create_like(
+    store: &Mutex<&mut store::Store>,
+    dst_client: &mut dyn Client,
+    operation: store::operations::CreateLikeOperation,
+) -> Result<()> {
+    let target_dst_identifier = {
+        let store = store.lock().unwrap();
+        find_post_dst_identifier(
+            &store,
+            &operation.account_pair.src_origin,
+            &operation.status.target_src_identifier,
+            &operation.account_pair.dst_origin,
+        )
+        .or_else(|| {
+            find_post_dst_identifier_by_uri(
+                &store,
+                &operation.status.target_src_uri,
+                &operation.account_pair.dst_origin,
+            )
+        })
+        .map(str::to_owned)
+    };
+    let target_dst_identifier = match target_dst_identifier {
+        Some(target_dst_identifier) => Some(target_dst_identifier),
+        None => {
+            resolve(
+                store,
+                dst_client,
+                &operation.account_pair,
+                &operation.status.target_src_uri,
+            )
+            .await
+        }
+    };
+    let Some(target_dst_identifier) = target_dst_identifier else {
+        info!(
+            "target_dst_identifier not found, skip like (target_src_identifier={})",
+            operation.status.target_src_identifier
+        );
+        return Ok(());
+    };
+    let dst_identifier = dst_client.like(&target_dst_identifier).await?;
+    store
+        .lock()
+        .unwrap()
+        .get_or_create_dst_mut(&operation.account_pair)
+        .statuses
+        .insert(
+            0,
+            store::user::DestinationStatus::Like(store::user::DestinationLike {
+                identifier: dst_identifier,
+                src_identifier: operation.status.target_src_identifier,
+            }),
+        );
+    Ok(())
+}
@@ -1,42 +1,242 @@
+use std::sync::Mutex;
+
 use anyhow::Result;
+use chrono::Duration;
+use tracing::{info, warn};
 
-use crate::{protocols::Client, store};
+use crate::{
+    config::{self, MediaFailure, ReplyPolicy},
+    protocols::{
+        media::{MediaError, MediaFetcher},
+        Client,
+    },
+    store::{self, operations::Facet},
+};
 
-use super::utils::find_post_dst_identifier;
+use super::{
+    fingerprint::fingerprint,
+    template::render,
+    truncate::{text_weight, truncate, uri_weight},
+    utils::{find_fingerprint_dst_identifier, find_post_dst_identifier, resolve},
+};
 
-pub async fn create_post(
-    store: &mut store::Store,
-    dst_client: &mut dyn Client,
-    operation: store::operations::CreatePostOperation,
-) -> Result<()> {
-    let reply_identifier = operation.status.reply_src_identifier.and_then(|reply| {
-        find_post_dst_identifier(
-            &store.users,
-            &operation.account_pair.src_origin,
-            &reply,
-            &operation.account_pair.dst_origin,
-        )
+/** 返信先へのリンクを本文の末尾に付ける */
+pub fn append_reply_link(content: &mut String, facets: &mut Vec<Facet>, reply_src_uri: &str) {
+    content.push_str("\n\nin reply to ");
+    let start = content.len() as u32;
+    content.push_str(reply_src_uri);
+    facets.push(Facet::Link {
+        byte_slice: start..content.len() as u32,
+        uri: reply_src_uri.to_owned(),
     });
-    let dst_identifier = dst_client
-        .post(
-            &operation.status.content,
-            &operation.status.facets,
-            reply_identifier,
-            operation.status.media,
-            operation.status.external,
-            &operation.status.created_at,
-        )
-        .await?;
+}
+
+/**
+ * 転送できなかったメディアを投稿から除く。添付画像なら URL を本文の末尾に付け、
+ * 宛先の上限に収まるように本文を切り詰める。投稿に含まれないメディアなら false を返す
+ */
+fn drop_medium(
+    account: &config::Account,
+    status: &mut store::operations::CreatePostOperationStatus,
+    url: &str,
+) -> bool {
+    if let Some(idx) = status.media.iter().position(|medium| medium.url == url) {
+        status.media.remove(idx);
+        let reserved = text_weight(account, "\n\n") + uri_weight(account, url);
+        truncate(account, &mut status.content, &mut status.facets, reserved);
+        status.content.push_str("\n\n");
+        let start = status.content.len() as u32;
+        status.content.push_str(url);
+        status.facets.push(Facet::Link {
+            byte_slice: start..status.content.len() as u32,
+            uri: url.to_owned(),
+        });
+        return true;
+    }
+    if let Some(external) = &mut status.external {
+        if external.thumb_url.as_deref() == Some(url) {
+            external.thumb_url = None;
+            return true;
+        }
+    }
+    false
+}
+
+fn insert_dst_post(
+    store: &Mutex<&mut store::Store>,
+    operation: &store::operations::CreatePostOperation,
+    dst_identifier: String,
+    degraded_media: Vec<String>,
+) {
     store
+        .lock()
+        .unwrap()
         .get_or_create_dst_mut(&operation.account_pair)
         .statuses
         .insert(
             0,
             store::user::DestinationStatus::Post(store::user::DestinationPost {
                 identifier: dst_identifier,
-                src_identifier: operation.status.src_identifier,
-                src_uri: operation.status.src_uri,
+                src_identifier: operation.status.src_identifier.clone(),
+                src_uri: operation.status.src_uri.clone(),
+                created_at: Some(operation.status.created_at),
+                degraded_media,
             }),
         );
-    Ok(())
+}
+
+/** 宛先に新しく status を作ったときだけ true を返す */
+pub async fn create_post(
+    media_fetcher: &MediaFetcher,
+    store: &Mutex<&mut store::Store>,
+    dst_client: &mut dyn Client,
+    dst_config: &config::Destination,
+    reply_policy: ReplyPolicy,
+    mut operation: store::operations::CreatePostOperation,
+) -> Result<bool> {
+    // 送信しない返信は、メディアの取得やテンプレートの展開より前に除く
+    let reply_identifier = match &operation.status.reply_src_identifier {
+        Some(reply_src_identifier) => {
+            let reply_identifier = find_post_dst_identifier(
+                &store.lock().unwrap(),
+                &operation.account_pair.src_origin,
+                reply_src_identifier,
+                &operation.account_pair.dst_origin,
+            )
+            .map(str::to_owned);
+            match (
+                reply_identifier,
+                &operation.status.reply_src_uri,
+                reply_policy,
+            ) {
+                (Some(reply_identifier), _, _) => Some(reply_identifier),
+                // 返信先が無いまま投稿するとスレッドが切れてしまうので、投稿を取り消す
+                (None, None, _) => {
+                    warn!(
+                        "reply target is not posted, cancelled (src_identifier={}, reply_src_identifier={})",
+                        operation.status.src_identifier, reply_src_identifier
+                    );
+                    return Ok(false);
+                }
+                (None, Some(_), ReplyPolicy::Skip) => {
+                    info!(
+                        "reply to out of range post is skipped (src_identifier={}, reply_src_identifier={})",
+                        operation.status.src_identifier, reply_src_identifier
+                    );
+                    return Ok(false);
+                }
+                (None, Some(_), ReplyPolicy::Resolve | ReplyPolicy::Standalone) => None,
+            }
+        }
+        None => None,
+    };
+    let dedup_window = Duration::seconds(dst_config.dedup_window_seconds as i64);
+    let drop_and_link = matches!(dst_config.media_failure, MediaFailure::DropAndLink);
+    let hash = if dedup_window > Duration::zero() {
+        match fingerprint(media_fetcher, &operation.status).await {
+            Ok(hash) => Some(hash),
+            // 取得できないメディアは除いて投稿するので、重複は確認しない
+            Err(err) if drop_and_link && err.is::<MediaError>() => {
+                warn!("dedup is skipped: {:?}", err);
+                None
+            }
+            Err(err) => return Err(err),
+        }
+    } else {
+        None
+    };
+    if let Some(hash) = &hash {
+        let dst_identifier = find_fingerprint_dst_identifier(
+            &store.lock().unwrap().users,
+            &operation.account_pair,
+            hash,
+            &operation.status.created_at,
+            dedup_window,
+        )
+        .map(str::to_owned);
+        if let Some(dst_identifier) = dst_identifier {
+            info!(
+                "same content is already posted, map to it (src_identifier={}, dst_identifier={})",
+                operation.status.src_identifier, dst_identifier
+            );
+            insert_dst_post(store, &operation, dst_identifier, vec![]);
+            return Ok(false);
+        }
+    }
+    if let Some(template) = &dst_config.template {
+        let status = &mut operation.status;
+        (status.content, status.facets) = render(
+            &dst_config.account,
+            template,
+            &status.content,
+            &status.facets,
+            &status.src_uri,
+        );
+    }
+    let reply_identifier = match (
+        reply_identifier,
+        operation.status.reply_src_uri.clone(),
+        reply_policy,
+    ) {
+        (None, Some(reply_src_uri), ReplyPolicy::Resolve) => {
+            let reply_identifier =
+                resolve(store, dst_client, &operation.account_pair, &reply_src_uri).await;
+            if reply_identifier.is_none() {
+                let status = &mut operation.status;
+                append_reply_link(&mut status.content, &mut status.facets, &reply_src_uri);
+            }
+            reply_identifier
+        }
+        (None, Some(reply_src_uri), ReplyPolicy::Standalone) => {
+            let status = &mut operation.status;
+            append_reply_link(&mut status.content, &mut status.facets, &reply_src_uri);
+            None
+        }
+        (reply_identifier, _, _) => reply_identifier,
+    };
+    let mut degraded_media = Vec::new();
+    let dst_identifier = loop {
+        let result = dst_client
+            .post(
+                &operation.status.content,
+                &operation.status.facets,
+                reply_identifier.as_deref(),
+                operation.status.media.clone(),
+                operation.status.external.clone(),
+                &operation.status.created_at,
+            )
+            .await;
+        let err = match result {
+            Ok(dst_identifier) => break dst_identifier,
+            Err(err) if drop_and_link => err,
+            Err(err) => return Err(err),
+        };
+        let Some(media_error) = err.downcast_ref::<MediaError>() else {
+            return Err(err);
+        };
+        if !drop_medium(&dst_config.account, &mut operation.status, &media_error.url) {
+            return Err(err);
+        }
+        warn!(
+            "media is dropped (src_identifier={}): {:?}",
+            operation.status.src_identifier, err
+        );
+        degraded_media.push(media_error.url.clone());
+    };
+    if let Some(hash) = hash {
+        store
+            .lock()
+            .unwrap()
+            .get_or_create_dst_mut(&operation.account_pair)
+            .insert_fingerprint(
+                store::user::Fingerprint {
+                    hash,
+                    identifier: dst_identifier.clone(),
+                    created_at: operation.status.created_at,
+                },
+                dedup_window,
+            );
+    }
+    insert_dst_post(store, &operation, dst_identifier, degraded_media);
+    Ok(true)
 }
@@ -1,36 +1,147 @@
+use std::sync::Mutex;
+
 use anyhow::Result;
-use tracing::warn;
+use tracing::{info, warn};
+
+use crate::{
+    config::{self, Account, RepostFallback},
+    protocols::Client,
+    sources::operation_factory::create_external,
+    store::{
+        self,
+        operations::{CreateRepostOperationStatus, Facet},
+    },
+};
 
-use crate::{protocols::Client, store};
+use super::{
+    truncate::{text_weight, truncate, uri_weight},
+    utils::{find_post_dst_identifier, find_post_dst_identifier_by_uri, resolve},
+};
 
-use super::utils::{find_post_dst_identifier, find_post_dst_identifier_by_uri};
+fn to_fallback_content(
+    account: &Account,
+    repost_fallback: RepostFallback,
+    status: &CreateRepostOperationStatus,
+) -> Option<(String, Vec<Facet>)> {
+    let uri = &status.target_src_uri;
+    let content = match repost_fallback {
+        RepostFallback::Skip => return None,
+        RepostFallback::Link => uri.clone(),
+        RepostFallback::Quote => match &status.target_content {
+            Some(target_content) => {
+                // 末尾の URL が宛先の上限に収まるように、引用する本文を切り詰める
+                let mut target_content = target_content.clone();
+                let reserved = text_weight(account, "RT: \n\n") + uri_weight(account, uri);
+                truncate(account, &mut target_content, &mut vec![], reserved);
+                format!("RT: {}\n\n{}", target_content, uri)
+            }
+            None => format!("RT: {}", uri),
+        },
+    };
+    let facets = vec![Facet::Link {
+        byte_slice: (content.len() - uri.len()) as u32..content.len() as u32,
+        uri: uri.clone(),
+    }];
+    Some((content, facets))
+}
 
+/** repost の対象が転送されていない場合、宛先の設定に応じて代わりの投稿をする */
+async fn create_fallback_post(
+    http_client: &reqwest::Client,
+    store: &Mutex<&mut store::Store>,
+    dst_client: &mut dyn Client,
+    dst_config: &config::Destination,
+    operation: store::operations::CreateRepostOperation,
+) -> Result<bool> {
+    let Some((content, facets)) = to_fallback_content(
+        &dst_config.account,
+        dst_config.repost_fallback,
+        &operation.status,
+    ) else {
+        warn!(
+            "target_dst_identifier not found (target_src_identifier={})",
+            operation.status.target_src_identifier
+        );
+        return Ok(false);
+    };
+    info!(
+        "target_dst_identifier not found, post instead (target_src_identifier={})",
+        operation.status.target_src_identifier
+    );
+    let external = create_external(&facets, http_client).await?;
+    let dst_identifier = dst_client
+        .post(
+            &content,
+            &facets,
+            None,
+            vec![],
+            external,
+            &operation.status.created_at,
+        )
+        .await?;
+    store
+        .lock()
+        .unwrap()
+        .get_or_create_dst_mut(&operation.account_pair)
+        .statuses
+        .insert(
+            0,
+            store::user::DestinationStatus::Repost(store::user::DestinationRepost {
+                identifier: dst_identifier,
+                src_identifier: operation.status.src_identifier,
+                is_fallback_post: true,
+                created_at: Some(operation.status.created_at),
+            }),
+        );
+    Ok(true)
+}
+
+/** 宛先に新しく status を作ったときだけ true を返す */
 pub async fn create_repost(
-    store: &mut store::Store,
+    http_client: &reqwest::Client,
+    store: &Mutex<&mut store::Store>,
     dst_client: &mut dyn Client,
+    dst_config: &config::Destination,
     operation: store::operations::CreateRepostOperation,
-) -> Result<()> {
-    let target_dst_identifier = find_post_dst_identifier(
-        &store.users,
-        &operation.account_pair.src_origin,
-        &operation.status.target_src_identifier,
-        &operation.account_pair.dst_origin,
-    )
-    .or_else(|| {
-        find_post_dst_identifier_by_uri(
-            &store.users,
-            &operation.status.target_src_uri,
+) -> Result<bool> {
+    let target_dst_identifier = {
+        let store = store.lock().unwrap();
+        find_post_dst_identifier(
+            &store,
+            &operation.account_pair.src_origin,
+            &operation.status.target_src_identifier,
             &operation.account_pair.dst_origin,
         )
-    });
+        .or_else(|| {
+            find_post_dst_identifier_by_uri(
+                &store,
+                &operation.status.target_src_uri,
+                &operation.account_pair.dst_origin,
+            )
+        })
+        .map(str::to_owned)
+    };
+    let target_dst_identifier = match target_dst_identifier {
+        Some(target_dst_identifier) => Some(target_dst_identifier),
+        None => {
+            resolve(
+                store,
+                dst_client,
+                &operation.account_pair,
+                &operation.status.target_src_uri,
+            )
+            .await
+        }
+    };
     let Some(target_dst_identifier) = target_dst_identifier else {
-        warn!("target_dst_identifier not found (target_src_identifier={})", operation.status.target_src_identifier);
-        return Ok(());
+        return create_fallback_post(http_client, store, dst_client, dst_config, operation).await;
     };
     let dst_identifier = dst_client
-        .repost(target_dst_identifier, &operation.status.created_at)
+        .repost(&target_dst_identifier, &operation.status.created_at)
         .await?;
     store
+        .lock()
+        .unwrap()
         .get_or_create_dst_mut(&operation.account_pair)
         .statuses
         .insert(
@@ -38,7 +149,9 @@ pub async fn create_repost(
             store::user::DestinationStatus::Repost(store::user::DestinationRepost {
                 identifier: dst_identifier,
                 src_identifier: operation.status.src_identifier,
+                is_fallback_post: false,
+                created_at: Some(operation.status.created_at),
             }),
         );
-    Ok(())
+    Ok(true)
 }
@@ -0,0 +1,42 @@
+use std::sync::Mutex;
+
+use anyhow::Result;
+use tracing::debug;
+
+use crate::{protocols::Client, store};
+
+use super::utils::find_dst_like;
+
+pub async fn delete_like(
+    store: &Mutex<&mut store::Store>,
+    dst_client: &mut dyn Client,
+    operation: store::operations::DeleteLikeOperation,
+) -> Result<()> {
+    let dst_identifier = find_dst_like(
+        &store.lock().unwrap(),
+        &operation.account_pair.src_origin,
+        &operation.status.target_src_identifier,
+        &operation.account_pair.dst_origin,
+    )
+    .map(|dst_like| dst_like.identifier.clone());
+    // 宛先で解決できずに like しなかったものは、取り消しも不要
+    let Some(dst_identifier) = dst_identifier else {
+        debug!(
+            "dst_identifier not found (target_src_identifier={})",
+            operation.status.target_src_identifier
+        );
+        return Ok(());
+    };
+    dst_client.unlike(&dst_identifier).await?;
+    let is_deleted = |status: &store::user::DestinationStatus| {
+        matches!(status, store::user::DestinationStatus::Like(like)
+            if like.identifier == dst_identifier)
+    };
+    let mut store = store.lock().unwrap();
+    store
+        .get_or_create_dst_mut(&operation.account_pair)
+        .statuses
+        .retain(|status| !is_deleted(status));
+    store.archive.remove(&operation.account_pair, is_deleted);
+    Ok(())
+}
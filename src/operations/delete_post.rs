@@ -1,25 +1,142 @@
+use std::sync::Mutex;
+
 use anyhow::Result;
-use tracing::warn;
+use tracing::{info, warn};
 
 use crate::{protocols::Client, store};
 
-use super::utils::find_post_dst_identifier;
+use super::utils::{find_post_dst_identifier, is_dst_post_shared};
 
 pub async fn delete_post(
-    store: &mut store::Store,
+    store: &Mutex<&mut store::Store>,
     dst_client: &mut dyn Client,
     operation: store::operations::DeletePostOperation,
 ) -> Result<()> {
     let dst_identifier = find_post_dst_identifier(
-        &store.users,
+        &store.lock().unwrap(),
         &operation.account_pair.src_origin,
         &operation.status.src_identifier,
         &operation.account_pair.dst_origin,
-    );
+    )
+    .map(str::to_owned);
     let Some(dst_identifier) = dst_identifier else {
-        warn!("dst_identifier not found (src_identifier={})", operation.status.src_identifier);
+        warn!(
+            "dst_identifier not found (src_identifier={})",
+            operation.status.src_identifier
+        );
         return Ok(());
     };
-    dst_client.delete_post(dst_identifier).await?;
+    let is_shared = is_dst_post_shared(
+        &store.lock().unwrap(),
+        &operation.account_pair,
+        &dst_identifier,
+    );
+    if is_shared {
+        info!(
+            "dst post is shared with another source, only drop the mapping (src_identifier={}, dst_identifier={})",
+            operation.status.src_identifier, dst_identifier
+        );
+    } else {
+        dst_client.delete_post(&dst_identifier).await?;
+    }
+    let is_deleted = |status: &store::user::DestinationStatus| {
+        matches!(status, store::user::DestinationStatus::Post(post)
+            if post.identifier == dst_identifier)
+    };
+    let mut store = store.lock().unwrap();
+    store
+        .get_or_create_dst_mut(&operation.account_pair)
+        .statuses
+        .retain(|status| !is_deleted(status));
+    store.archive.remove(&operation.account_pair, is_deleted);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        protocols::test_client,
+        store::{
+            operations::{AccountPair, DeletePostOperationStatus},
+            user::{DestinationPost, DestinationStatus},
+        },
+    };
+
+    use super::*;
+
+    fn account_pair(src_origin: &str) -> AccountPair {
+        AccountPair {
+            src_origin: src_origin.to_owned(),
+            src_account_identifier: "src".to_owned(),
+            dst_origin: "https://dst.example".to_owned(),
+            dst_account_identifier: "dst".to_owned(),
+        }
+    }
+
+    fn insert_post(store: &mut store::Store, account_pair: &AccountPair, src_identifier: &str) {
+        store
+            .get_or_create_dst_mut(account_pair)
+            .statuses
+            .push(DestinationStatus::Post(DestinationPost {
+                identifier: "shared".to_owned(),
+                src_identifier: src_identifier.to_owned(),
+                src_uri: String::new(),
+                created_at: None,
+                degraded_media: vec![],
+            }));
+    }
+
+    fn operation(
+        account_pair: &AccountPair,
+        src_identifier: &str,
+    ) -> store::operations::DeletePostOperation {
+        store::operations::DeletePostOperation {
+            account_pair: account_pair.clone(),
+            status: DeletePostOperationStatus {
+                src_identifier: src_identifier.to_owned(),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn delete_post_keeps_post_shared_with_another_source() {
+        let a = account_pair("https://a.example");
+        let b = account_pair("https://b.example");
+        let mut store = store::Store::default();
+        insert_post(&mut store, &a, "a1");
+        insert_post(&mut store, &b, "b1");
+        let store = Mutex::new(&mut store);
+        let mut dst_client = test_client::Client::new("https://dst.example", "dst");
+
+        delete_post(&store, &mut dst_client, operation(&b, "b1"))
+            .await
+            .unwrap();
+        assert!(dst_client.calls.is_empty());
+        assert!(store
+            .lock()
+            .unwrap()
+            .get_or_create_dst_mut(&b)
+            .statuses
+            .is_empty());
+        assert_eq!(
+            store
+                .lock()
+                .unwrap()
+                .get_or_create_dst_mut(&a)
+                .statuses
+                .len(),
+            1
+        );
+
+        delete_post(&store, &mut dst_client, operation(&a, "a1"))
+            .await
+            .unwrap();
+        assert_eq!(dst_client.calls, ["delete_post shared"]);
+        assert!(store
+            .lock()
+            .unwrap()
+            .get_or_create_dst_mut(&a)
+            .statuses
+            .is_empty());
+    }
+}
@@ -1,25 +1,45 @@
+use std::sync::Mutex;
+
 use anyhow::Result;
 use tracing::warn;
 
 use crate::{protocols::Client, store};
 
-use super::utils::find_repost_dst_identifier;
+use super::utils::find_dst_repost;
 
 pub async fn delete_repost(
-    store: &mut store::Store,
+    store: &Mutex<&mut store::Store>,
     dst_client: &mut dyn Client,
     operation: store::operations::DeleteRepostOperation,
 ) -> Result<()> {
-    let dst_identifier = find_repost_dst_identifier(
-        &store.users,
+    let dst_repost = find_dst_repost(
+        &store.lock().unwrap(),
         &operation.account_pair.src_origin,
         &operation.status.src_identifier,
         &operation.account_pair.dst_origin,
-    );
-    let Some(dst_identifier) = dst_identifier else {
-        warn!("dst_identifier not found (src_identifier={})", operation.status.src_identifier);
+    )
+    .cloned();
+    let Some(dst_repost) = dst_repost else {
+        warn!(
+            "dst_identifier not found (src_identifier={})",
+            operation.status.src_identifier
+        );
         return Ok(());
     };
-    dst_client.delete_repost(dst_identifier).await?;
+    if dst_repost.is_fallback_post {
+        dst_client.delete_post(&dst_repost.identifier).await?;
+    } else {
+        dst_client.delete_repost(&dst_repost.identifier).await?;
+    }
+    let is_deleted = |status: &store::user::DestinationStatus| {
+        matches!(status, store::user::DestinationStatus::Repost(repost)
+            if repost.identifier == dst_repost.identifier)
+    };
+    let mut store = store.lock().unwrap();
+    store
+        .get_or_create_dst_mut(&operation.account_pair)
+        .statuses
+        .retain(|status| !is_deleted(status));
+    store.archive.remove(&operation.account_pair, is_deleted);
     Ok(())
 }
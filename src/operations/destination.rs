@@ -1,71 +1,470 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Mutex};
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use futures::{stream, StreamExt};
+use tokio::time::{timeout_at, Instant};
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, trace, warn};
 
 use crate::{
     app::AccountKey,
-    protocols::Client,
+    config,
+    protocols::{
+        media::{MediaError, MediaFetcher},
+        DstClient,
+    },
     store::{
         self,
         operations::{
-            AccountPair,
-            Operation::{CreatePost, CreateRepost, DeletePost, DeleteRepost, UpdatePost},
+            AccountPair, Operation,
+            Operation::{
+                CreateLike, CreatePost, CreateRepost, DeleteLike, DeletePost, DeleteRepost,
+                PinPost, UnpinPost, UpdatePost, UpdateProfile,
+            },
         },
     },
 };
 
 use super::{
-    create_post::create_post, create_repost::create_repost, delete_post::delete_post,
-    delete_repost::delete_repost,
+    create_like::create_like, create_post::create_post, create_repost::create_repost,
+    delete_like::delete_like, delete_post::delete_post, delete_repost::delete_repost,
+    pin_post::pin_post, unpin_post::unpin_post,
 };
 
-fn find_dst_client<'a>(
-    dst_clients_map: &'a mut HashMap<AccountKey, Vec<Box<dyn Client>>>,
-    account_pair: &AccountPair,
-) -> Option<&'a mut dyn Client> {
-    Some(
-        dst_clients_map
-            .get_mut(&account_pair.to_src_key())?
-            .iter_mut()
-            .find(|dst_client| dst_client.to_account_key() == account_pair.to_dst_key())?
-            .as_mut(),
-    )
+/** 元のキューでの位置と operation */
+type Queue = Vec<(usize, Operation)>;
+
+/** メディアを転送できなかった投稿を再送する回数の上限 */
+const MEDIA_RETRY_LIMIT: usize = 5;
+/** メディアを転送できなかった投稿を最初に再送するまでの間隔。失敗するたびに倍にする */
+const MEDIA_RETRY_INTERVAL_MINUTES: i64 = 10;
+
+fn group_dst_clients<'a, 'b>(
+    dst_clients_map: &'a mut HashMap<AccountKey, Vec<DstClient<'b>>>,
+) -> HashMap<AccountKey, Vec<(&'a AccountKey, &'a mut DstClient<'b>)>> {
+    let mut map: HashMap<_, Vec<_>> = HashMap::new();
+    for (src_account_key, dst_clients) in dst_clients_map.iter_mut() {
+        for dst_client in dst_clients.iter_mut() {
+            map.entry(dst_client.client.to_account_key())
+                .or_default()
+                .push((src_account_key, dst_client));
+        }
+    }
+    map
 }
 
-pub async fn post(
+/** 失敗した投稿に依存している operation を、推移的に取り消す */
+fn cancel_dependents(queue: &mut Queue, account_pair: AccountPair, src_identifier: String) {
+    let mut failed = vec![(account_pair, src_identifier)];
+    while let Some((account_pair, src_identifier)) = failed.pop() {
+        queue.retain(|(_, operation)| {
+            if operation.account_pair() != &account_pair
+                || operation.dependency() != Some(src_identifier.as_str())
+            {
+                return true;
+            }
+            warn!(
+                "cancel operation because its dependency failed (src_identifier={}, dependency={})",
+                operation.src_identifier(),
+                src_identifier
+            );
+            if let CreatePost(operation) = operation {
+                failed.push((
+                    operation.account_pair.clone(),
+                    operation.status.src_identifier.clone(),
+                ));
+            }
+            false
+        });
+    }
+}
+
+/** メディアを転送できなかった投稿を、上限に達するまでは間隔を空けて再送するようにする */
+fn retry_after_media_error(operation: &Operation, now: DateTime<FixedOffset>) -> Option<Operation> {
+    let CreatePost(operation) = operation else {
+        return None;
+    };
+    if operation.media_failures >= MEDIA_RETRY_LIMIT {
+        return None;
+    }
+    let mut operation = operation.clone();
+    let interval = MEDIA_RETRY_INTERVAL_MINUTES << operation.media_failures;
+    operation.media_failures += 1;
+    operation.not_before = Some(now + Duration::minutes(interval));
+    Some(CreatePost(operation))
+}
+
+/** 静かな時間帯か送信数の上限に達していれば、その理由を返す */
+fn throttle_reason(
+    dst_config: &config::Destination,
+    store: &store::Store,
+    dst_account_key: &AccountKey,
+    now: &DateTime<FixedOffset>,
+) -> Option<&'static str> {
+    if dst_config
+        .quiet_hours
+        .iter()
+        .any(|quiet_hours| quiet_hours.contains(now))
+    {
+        return Some("quiet hours");
+    }
+    let quota = &dst_config.quota;
+    let usage = store.usages.iter().find(|usage| {
+        usage.origin == dst_account_key.origin && usage.identifier == dst_account_key.identifier
+    })?;
+    if quota
+        .per_hour
+        .is_some_and(|per_hour| usage.count_in_hour(now) >= per_hour)
+    {
+        return Some("hourly quota exceeded");
+    }
+    if quota
+        .per_day
+        .is_some_and(|per_day| usage.count_in_day(now) >= per_day)
+    {
+        return Some("daily quota exceeded");
+    }
+    None
+}
+
+/** 一つの宛先のキューを順番に処理し、未処理の operation を返す */
+async fn post_to_destination(
     cancellation_token: &CancellationToken,
-    store: &mut store::Store,
-    dst_clients_map: &mut HashMap<AccountKey, Vec<Box<dyn Client>>>,
-) -> Result<()> {
-    trace!("post");
+    send_deadline: Instant,
+    http_client: &reqwest::Client,
+    media_fetcher: &MediaFetcher,
+    store: &Mutex<&mut store::Store>,
+    mut dst_clients: Vec<(&AccountKey, &mut DstClient<'_>)>,
+    mut queue: Queue,
+) -> (Queue, Result<()>) {
+    // 依存先が未処理のため次回以降に回す operation
+    let mut deferred = Queue::new();
     loop {
         trace!("post loop");
         if cancellation_token.is_cancelled() {
             debug!("cancel accepted");
-            return Ok(());
+            queue.append(&mut deferred);
+            return (queue, Ok(()));
         }
-        let Some(operation) = store.operations.pop() else {
-            trace!("post completed");
-            return Ok(());
+        let Some((idx, operation)) = queue.pop() else {
+            return (deferred, Ok(()));
         };
+        if operation
+            .not_before()
+            .is_some_and(|not_before| *not_before > Utc::now())
+        {
+            trace!(
+                "not due yet (src_identifier={})",
+                operation.src_identifier()
+            );
+            deferred.push((idx, operation));
+            continue;
+        }
+        // キューは末尾から処理するので、位置が後ろのものほど先に処理されるべきもの。
+        // 取り消しは、次回以降に回された同じ status の作成を追い越さない
+        if queue.iter().chain(&deferred).any(|(pending_idx, pending)| {
+            operation.depends_on(pending) || (*pending_idx > idx && operation.undoes(pending))
+        }) {
+            debug!(
+                "wait for dependency (src_identifier={})",
+                operation.src_identifier()
+            );
+            deferred.push((idx, operation));
+            continue;
+        }
 
-        let dst_client = find_dst_client(dst_clients_map, operation.account_pair()).unwrap();
+        let src_account_key = operation.account_pair().to_src_key();
+        let Some((_, dst_client)) = dst_clients
+            .iter_mut()
+            .find(|(key, _)| **key == src_account_key)
+        else {
+            // 設定から外れた宛先への operation は、残すとキューが空にならないので捨てる。
+            // 送信を続けるには retiredDsts に移す
+            warn!(
+                "dst client not found, drop (src_identifier={})",
+                operation.src_identifier()
+            );
+            continue;
+        };
+        let dst_config = dst_client.config;
+        let reply_policy = dst_client.reply_policy;
+        let dst_client = dst_client.client.as_mut();
 
-        let result = match operation {
-            CreatePost(operation) => create_post(store, dst_client, operation).await,
-            CreateRepost(operation) => create_repost(store, dst_client, operation).await,
-            UpdatePost(_) => {
-                warn!("Update is not supported yet");
-                Ok(())
+        let dst_account_key = operation.account_pair().to_dst_key();
+        let is_post = operation.is_post();
+        if is_post {
+            let now = Utc::now().fixed_offset();
+            let reason =
+                throttle_reason(dst_config, &store.lock().unwrap(), &dst_account_key, &now);
+            if let Some(reason) = reason {
+                debug!(
+                    "{}, defer (src_identifier={})",
+                    reason,
+                    operation.src_identifier()
+                );
+                deferred.push((idx, operation));
+                continue;
+            }
+        }
+
+        let failed_post = match &operation {
+            CreatePost(operation) => Some((
+                operation.account_pair.clone(),
+                operation.status.src_identifier.clone(),
+            )),
+            CreateRepost(_) | UpdatePost(_) | DeletePost(_) | DeleteRepost(_) | CreateLike(_)
+            | DeleteLike(_) | UpdateProfile(_) | PinPost(_) | UnpinPost(_) => None,
+        };
+        let pending = (idx, operation.clone());
+        let dispatch = async {
+            match operation {
+                CreatePost(operation) => {
+                    create_post(
+                        media_fetcher,
+                        store,
+                        dst_client,
+                        dst_config,
+                        reply_policy,
+                        operation,
+                    )
+                    .await
+                }
+                CreateRepost(operation) => {
+                    create_repost(http_client, store, dst_client, dst_config, operation).await
+                }
+                UpdatePost(_) => {
+                    warn!("Update is not supported yet");
+                    Ok(false)
+                }
+                DeletePost(operation) => delete_post(store, dst_client, operation)
+                    .await
+                    .map(|()| false),
+                DeleteRepost(operation) => delete_repost(store, dst_client, operation)
+                    .await
+                    .map(|()| false),
+                CreateLike(operation) => create_like(store, dst_client, operation)
+                    .await
+                    .map(|()| false),
+                DeleteLike(operation) => delete_like(store, dst_client, operation)
+                    .await
+                    .map(|()| false),
+                UpdateProfile(operation) => dst_client
+                    .update_profile(&operation.status.profile)
+                    .await
+                    .map(|()| false),
+                PinPost(operation) => pin_post(store, dst_client, operation).await.map(|()| false),
+                UnpinPost(operation) => unpin_post(store, dst_client, operation)
+                    .await
+                    .map(|()| false),
+            }
+        };
+        // 打ち切りの後は次の operation を取り出さない。送信中のものも、commit の時間を残すために
+        // 打ち切りの時刻で中断する。途中まで送信されている場合があるので警告する
+        let result = match timeout_at(send_deadline, dispatch).await {
+            Ok(result) => result,
+            Err(_) => {
+                warn!(
+                    "timed out at the run budget, it may have been sent partially (src_identifier={})",
+                    pending.1.src_identifier()
+                );
+                queue.push(pending);
+                queue.append(&mut deferred);
+                return (queue, Ok(()));
+            }
+        };
+        let created = match result {
+            Ok(created) => created,
+            Err(err) => {
+                let retry = err
+                    .is::<MediaError>()
+                    .then(|| retry_after_media_error(&pending.1, Utc::now().fixed_offset()))
+                    .flatten();
+                if let Some(operation) = retry {
+                    warn!(
+                        "media is not available, retry later (src_identifier={}): {:?}",
+                        operation.src_identifier(),
+                        err
+                    );
+                    deferred.push((idx, operation));
+                    continue;
+                }
+                error!("{:?}", err);
+                if let Some((account_pair, src_identifier)) = failed_post {
+                    cancel_dependents(&mut queue, account_pair, src_identifier);
+                }
+                queue.append(&mut deferred);
+                return (queue, Err(anyhow!("post failed")));
             }
-            DeletePost(operation) => delete_post(store, dst_client, operation).await,
-            DeleteRepost(operation) => delete_repost(store, dst_client, operation).await,
         };
-        if let Err(err) = result {
-            error!("{:?}", err);
-            bail!("post failed");
+        // 既存の投稿への対応付けや、取り消し・スキップは数えない
+        if created {
+            store
+                .lock()
+                .unwrap()
+                .get_or_create_usage_mut(&dst_account_key)
+                .add(Utc::now().fixed_offset(), 1);
         }
     }
 }
+
+pub async fn post(
+    cancellation_token: &CancellationToken,
+    send_deadline: Instant,
+    config: &config::Config,
+    http_client: &reqwest::Client,
+    media_fetcher: &MediaFetcher,
+    store: &mut store::Store,
+    dst_clients_map: &mut HashMap<AccountKey, Vec<DstClient<'_>>>,
+) -> Result<()> {
+    trace!("post");
+    // 宛先ごとにキューを分ける。キュー内では元の順序を保つので AccountPair ごとの順序も保たれる
+    let mut queues: HashMap<AccountKey, Queue> = HashMap::new();
+    for (idx, operation) in store.operations.drain(..).enumerate() {
+        queues
+            .entry(operation.account_pair().to_dst_key())
+            .or_default()
+            .push((idx, operation));
+    }
+    let mut dst_clients_map = group_dst_clients(dst_clients_map);
+
+    let store = Mutex::new(store);
+    let results: Vec<_> = stream::iter(queues.into_iter().map(|(dst_account_key, queue)| {
+        let dst_clients = dst_clients_map.remove(&dst_account_key).unwrap_or_default();
+        post_to_destination(
+            cancellation_token,
+            send_deadline,
+            http_client,
+            media_fetcher,
+            &store,
+            dst_clients,
+            queue,
+        )
+    }))
+    .buffer_unordered(config.max_concurrent_destinations.max(1))
+    .collect()
+    .await;
+    let store = store.into_inner().unwrap();
+
+    let mut remaining = Vec::new();
+    let mut failed = false;
+    for (queue, result) in results {
+        remaining.extend(queue);
+        failed |= result.is_err();
+    }
+    remaining.sort_by_key(|(idx, _)| *idx);
+    store.operations = remaining
+        .into_iter()
+        .map(|(_, operation)| operation)
+        .collect();
+    if failed {
+        bail!("post failed");
+    }
+    trace!("post completed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use crate::{
+        protocols::test_client,
+        store::operations::{
+            CreatePostOperation, CreatePostOperationStatus, DeletePostOperation,
+            DeletePostOperationStatus,
+        },
+    };
+
+    use super::*;
+
+    fn account_pair() -> AccountPair {
+        AccountPair {
+            src_origin: "https://src.example".to_owned(),
+            src_account_identifier: "src".to_owned(),
+            dst_origin: "https://dst.example".to_owned(),
+            dst_account_identifier: "dst".to_owned(),
+        }
+    }
+
+    fn create_post(media_failures: usize) -> Operation {
+        let created_at = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap();
+        CreatePost(CreatePostOperation {
+            account_pair: account_pair(),
+            status: CreatePostOperationStatus {
+                src_identifier: "post".to_owned(),
+                src_uri: String::new(),
+                content: String::new(),
+                facets: vec![],
+                reply_src_identifier: None,
+                reply_src_uri: None,
+                media: vec![],
+                external: None,
+                created_at,
+            },
+            not_before: None,
+            media_failures,
+        })
+    }
+
+    #[test]
+    fn retry_after_media_error_backs_off_until_the_limit() {
+        let now = DateTime::parse_from_rfc3339("2024-01-02T00:00:00Z").unwrap();
+
+        let Some(CreatePost(retry)) = retry_after_media_error(&create_post(1), now) else {
+            panic!("not retried");
+        };
+        assert_eq!(retry.media_failures, 2);
+        assert_eq!(retry.not_before, Some(now + Duration::minutes(20)));
+
+        assert!(retry_after_media_error(&create_post(MEDIA_RETRY_LIMIT), now).is_none());
+    }
+
+    #[tokio::test]
+    async fn delete_waits_for_deferred_create() {
+        let http_client = reqwest::Client::new();
+        let media_fetcher = MediaFetcher::new(Arc::new(http_client.clone()), None);
+        let mut store = store::Store::default();
+        let store = Mutex::new(&mut store);
+        let dst_config: config::Destination = serde_json::from_value(json!({
+            "protocol": "mastodon",
+            "origin": "https://dst.example",
+            "accessToken": "",
+        }))
+        .unwrap();
+        let src_account_key = account_pair().to_src_key();
+        let mut dst_client = DstClient {
+            client: Box::new(test_client::Client::new("https://dst.example", "dst")),
+            config: &dst_config,
+            reply_policy: config::ReplyPolicy::Skip,
+        };
+        let mut create = create_post(0);
+        if let CreatePost(create) = &mut create {
+            create.not_before = Some(Utc::now().fixed_offset() + Duration::hours(1));
+        }
+        let delete = DeletePost(DeletePostOperation {
+            account_pair: account_pair(),
+            status: DeletePostOperationStatus {
+                src_identifier: "post".to_owned(),
+            },
+        });
+
+        let (queue, result) = post_to_destination(
+            &CancellationToken::new(),
+            Instant::now() + std::time::Duration::from_secs(60),
+            &http_client,
+            &media_fetcher,
+            &store,
+            vec![(&src_account_key, &mut dst_client)],
+            vec![(0, delete), (1, create)],
+        )
+        .await;
+
+        result.unwrap();
+        let mut remaining: Vec<_> = queue.iter().map(|(idx, _)| *idx).collect();
+        remaining.sort();
+        assert_eq!(remaining, [0, 1]);
+    }
+}
@@ -0,0 +1,234 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset};
+use futures::future::join_all;
+use serde_json::Value;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{
+    config,
+    protocols::{create_http_client, media::MediaFetcher, Client, DstClient, Request},
+    sources::source::{self, get},
+    store,
+};
+
+use super::destination::post;
+
+/**
+ * 取得は元の client に任せ、書き込みは送信せずに、元の client が組み立てる要求をログに出す。
+ * 作成したものには仮の identifier を返す
+ */
+struct DryRunClient {
+    inner: Box<dyn Client>,
+    count: usize,
+}
+
+impl DryRunClient {
+    fn new(inner: Box<dyn Client>) -> Self {
+        Self { inner, count: 0 }
+    }
+
+    fn log(&mut self, request: Request<'_>) -> String {
+        match self.inner.render(&request) {
+            Ok(payload) => info!(
+                "{} ({}): {}",
+                self.inner.origin(),
+                self.inner.identifier(),
+                serde_json::to_string_pretty(&payload).unwrap_or_default()
+            ),
+            // 仮の identifier を宛先の形式で解釈できない場合など
+            Err(err) => warn!(
+                "{} ({}): failed to render: {:?}",
+                self.inner.origin(),
+                self.inner.identifier(),
+                err
+            ),
+        }
+        self.count += 1;
+        format!("dry-run-{}", self.count)
+    }
+}
+
+#[async_trait]
+impl Client for DryRunClient {
+    fn origin(&self) -> &str {
+        self.inner.origin()
+    }
+
+    fn identifier(&self) -> &str {
+        self.inner.identifier()
+    }
+
+    async fn fetch_statuses(&mut self) -> Result<Vec<source::LiveStatus>> {
+        self.inner.fetch_statuses().await
+    }
+
+    async fn fetch_status_uri(&mut self, identifier: &str) -> Result<Option<String>> {
+        self.inner.fetch_status_uri(identifier).await
+    }
+
+    async fn fetch_statuses_page(
+        &mut self,
+        cursor: Option<&str>,
+    ) -> Result<(Vec<source::LiveStatus>, Option<String>)> {
+        self.inner.fetch_statuses_page(cursor).await
+    }
+
+    async fn post(
+        &mut self,
+        content: &str,
+        facets: &[store::operations::Facet],
+        reply_identifier: Option<&str>,
+        images: Vec<store::operations::Medium>,
+        external: Option<store::operations::External>,
+        created_at: &DateTime<FixedOffset>,
+    ) -> Result<String> {
+        Ok(self.log(Request::Post {
+            content,
+            facets,
+            reply_identifier,
+            images: &images,
+            external: external.as_ref(),
+            created_at,
+        }))
+    }
+
+    fn render(&self, request: &Request<'_>) -> Result<Value> {
+        self.inner.render(request)
+    }
+
+    async fn repost(
+        &mut self,
+        target_identifier: &str,
+        created_at: &DateTime<FixedOffset>,
+    ) -> Result<String> {
+        Ok(self.log(Request::Repost {
+            target_identifier,
+            created_at,
+        }))
+    }
+
+    async fn delete_post(&mut self, identifier: &str) -> Result<()> {
+        self.log(Request::DeletePost { identifier });
+        Ok(())
+    }
+
+    async fn delete_repost(&mut self, identifier: &str) -> Result<()> {
+        self.log(Request::DeleteRepost { identifier });
+        Ok(())
+    }
+
+    async fn fetch_likes(&mut self) -> Result<Vec<store::operations::CreateLikeOperationStatus>> {
+        self.inner.fetch_likes().await
+    }
+
+    async fn like(&mut self, target_identifier: &str) -> Result<String> {
+        Ok(self.log(Request::Like { target_identifier }))
+    }
+
+    async fn unlike(&mut self, identifier: &str) -> Result<()> {
+        self.log(Request::Unlike { identifier });
+        Ok(())
+    }
+
+    async fn fetch_profile(&mut self) -> Result<Option<store::operations::Profile>> {
+        self.inner.fetch_profile().await
+    }
+
+    async fn update_profile(&mut self, profile: &store::operations::Profile) -> Result<()> {
+        self.log(Request::UpdateProfile { profile });
+        Ok(())
+    }
+
+    async fn exists_post(&mut self, identifier: &str) -> Result<Option<bool>> {
+        self.inner.exists_post(identifier).await
+    }
+
+    async fn exists_repost(&mut self, identifier: &str) -> Result<Option<bool>> {
+        self.inner.exists_repost(identifier).await
+    }
+
+    async fn fetch_deleted_post_identifiers(
+        &mut self,
+        posts: &[(String, String)],
+    ) -> Result<Option<Vec<String>>> {
+        self.inner.fetch_deleted_post_identifiers(posts).await
+    }
+
+    async fn fetch_pinned_identifiers(&mut self) -> Result<Option<Vec<String>>> {
+        self.inner.fetch_pinned_identifiers().await
+    }
+
+    async fn pin(&mut self, identifier: &str) -> Result<()> {
+        self.log(Request::Pin { identifier });
+        Ok(())
+    }
+
+    async fn unpin(&mut self, identifier: &str) -> Result<()> {
+        self.log(Request::Unpin { identifier });
+        Ok(())
+    }
+
+    async fn resolve(&mut self, uri: &str) -> Result<Option<String>> {
+        self.inner.resolve(uri).await
+    }
+}
+
+/**
+ * 取得と送信キューの処理を通常の実行と同じ経路で行い、宛先に送る要求をログに出す。
+ * store は commit しない前提で書き換える
+ */
+pub async fn dry_run(
+    cancellation_token: &CancellationToken,
+    send_deadline: Instant,
+    config: &config::Config,
+    store: &mut store::Store,
+) -> Result<()> {
+    let http_client = Arc::new(create_http_client(config.http_timeout())?);
+    let media_fetcher = Arc::new(MediaFetcher::new(
+        http_client.clone(),
+        config.media_cache_dir(),
+    ));
+    let mut dst_client_map = HashMap::new();
+    let store = Mutex::new(store);
+    let futures = config.users.iter().map(|config_user| {
+        get(
+            &http_client,
+            &media_fetcher,
+            config_user,
+            config.sweep_archived_posts_per_run,
+            &store,
+        )
+    });
+    for result in join_all(futures).await {
+        if let Some((src_account_key, dst_clients)) = result? {
+            let dst_clients: Vec<_> = dst_clients
+                .into_iter()
+                .map(|dst_client: DstClient| DstClient {
+                    client: Box::new(DryRunClient::new(dst_client.client)),
+                    ..dst_client
+                })
+                .collect();
+            dst_client_map.insert(src_account_key, dst_clients);
+        }
+    }
+    let store = store.into_inner().unwrap();
+
+    post(
+        cancellation_token,
+        send_deadline,
+        config,
+        &http_client,
+        &media_fetcher,
+        store,
+        &mut dst_client_map,
+    )
+    .await
+}
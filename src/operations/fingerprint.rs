@@ -0,0 +1,25 @@
+use anyhow::Result;
+
+use crate::{protocols::media::MediaFetcher, store, utils::fnv1a};
+
+/** 空白の違いと大文字小文字を無視する */
+fn normalize(content: &str) -> String {
+    content
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/** 本文とメディアの内容から、投稿元に依らない指紋を作る */
+pub async fn fingerprint(
+    media_fetcher: &MediaFetcher,
+    status: &store::operations::CreatePostOperationStatus,
+) -> Result<String> {
+    let mut hash = fnv1a(None, normalize(&status.content).as_bytes());
+    for medium in &status.media {
+        let media = media_fetcher.fetch(&medium.url).await?;
+        hash = fnv1a(Some(hash), media.hash.as_bytes());
+    }
+    Ok(format!("{:016x}", hash))
+}
@@ -0,0 +1,163 @@
+use std::{collections::HashMap, mem::discriminant};
+
+use anyhow::Result;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::{
+    app::AccountKey,
+    config,
+    protocols::{Client, DstClient},
+    store::{
+        self,
+        operations::AccountPair,
+        user::{DestinationLike, DestinationPost, DestinationRepost, DestinationStatus},
+    },
+};
+
+fn src_identifier(status: &DestinationStatus) -> &str {
+    match status {
+        DestinationStatus::Post(DestinationPost { src_identifier, .. })
+        | DestinationStatus::Repost(DestinationRepost { src_identifier, .. })
+        | DestinationStatus::Like(DestinationLike { src_identifier, .. }) => src_identifier,
+    }
+}
+
+fn is_same(a: &DestinationStatus, b: &DestinationStatus) -> bool {
+    discriminant(a) == discriminant(b) && a.identifier() == b.identifier()
+}
+
+async fn exists(dst_client: &mut dyn Client, status: &DestinationStatus) -> Result<Option<bool>> {
+    match status {
+        DestinationStatus::Post(DestinationPost { identifier, .. })
+        | DestinationStatus::Repost(DestinationRepost {
+            identifier,
+            is_fallback_post: true,
+            ..
+        }) => dst_client.exists_post(identifier).await,
+        DestinationStatus::Repost(DestinationRepost { identifier, .. }) => {
+            dst_client.exists_repost(identifier).await
+        }
+        DestinationStatus::Like(_) => Ok(None),
+    }
+}
+
+/**
+ * 送信済みの status を少しずつ宛先で確認し、消えているものの対応を捨てる。
+ * キューに残っている operation が参照しているものを先に確認する
+ */
+async fn reconcile_destination(
+    store: &mut store::Store,
+    dst_client: &mut dyn Client,
+    account_pair: &AccountPair,
+    count: usize,
+) {
+    let pending_src_identifiers: Vec<_> = store
+        .operations
+        .iter()
+        .filter(|operation| operation.account_pair() == account_pair)
+        .flat_map(|operation| [Some(operation.src_identifier()), operation.dependency()])
+        .flatten()
+        .map(str::to_owned)
+        .collect();
+    let (offset, pending, samples) = {
+        let dst = store.get_or_create_dst_mut(account_pair);
+        let len = dst.statuses.len();
+        if len == 0 {
+            return;
+        }
+        let pending: Vec<_> = dst
+            .statuses
+            .iter()
+            .filter(|status| {
+                pending_src_identifiers
+                    .iter()
+                    .any(|pending| pending == src_identifier(status))
+            })
+            .take(count)
+            .cloned()
+            .collect();
+        let offset = dst.reconcile_offset % len;
+        let samples: Vec<_> = (0..(count - pending.len()).min(len))
+            .map(|i| dst.statuses[(offset + i) % len].clone())
+            .collect();
+        (offset, pending, samples)
+    };
+
+    let mut checked = 0usize;
+    let mut missing = Vec::new();
+    for status in pending.iter().chain(&samples) {
+        match exists(dst_client, status).await {
+            Ok(Some(true)) | Ok(None) => {}
+            Ok(Some(false)) => missing.push(status.clone()),
+            Err(err) => {
+                warn!(
+                    "reconcile failed (identifier={}): {:?}",
+                    status.identifier(),
+                    err
+                );
+                break;
+            }
+        }
+        checked += 1;
+    }
+    let checked_samples = checked.saturating_sub(pending.len());
+
+    let dst = store.get_or_create_dst_mut(account_pair);
+    // 確認済みの範囲から消した分だけ、後ろの status が詰まる
+    let pruned_samples = samples[..checked_samples]
+        .iter()
+        .filter(|status| missing.iter().any(|missing| is_same(missing, status)))
+        .count();
+    dst.reconcile_offset = offset + checked_samples - pruned_samples;
+    if missing.is_empty() {
+        debug!(
+            "reconciled {} statuses on {}",
+            checked, account_pair.dst_origin
+        );
+        return;
+    }
+    for status in &missing {
+        info!(
+            "dst status is missing, prune mapping (identifier={}, src_identifier={})",
+            status.identifier(),
+            src_identifier(status)
+        );
+    }
+    warn!(
+        "drift detected on {}: {} of {} checked statuses are missing",
+        account_pair.dst_origin,
+        missing.len(),
+        checked
+    );
+    dst.statuses
+        .retain(|status| !missing.iter().any(|missing| is_same(missing, status)));
+}
+
+pub async fn reconcile(
+    cancellation_token: &CancellationToken,
+    config: &config::Config,
+    store: &mut store::Store,
+    dst_clients_map: &mut HashMap<AccountKey, Vec<DstClient<'_>>>,
+) {
+    if config.reconcile_statuses_per_run == 0 {
+        return;
+    }
+    for (src_account_key, dst_clients) in dst_clients_map.iter_mut() {
+        for dst_client in dst_clients.iter_mut() {
+            if cancellation_token.is_cancelled() {
+                debug!("cancel accepted");
+                return;
+            }
+            let account_pair =
+                AccountPair::from_keys(src_account_key.clone(), dst_client.client.to_account_key());
+            reconcile_destination(
+                store,
+                dst_client.client.as_mut(),
+                &account_pair,
+                config.reconcile_statuses_per_run,
+            )
+            .await;
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use std::ops::Range;
+
+use crate::{
+    config::{Account, Replacement, Template},
+    protocols::at_proto_client::to_web_uri,
+    store::operations::Facet,
+};
+
+use super::truncate::{text_weight, truncate, uri_weight};
+
+fn map_facet(facet: &Facet, f: impl Fn(u32) -> u32) -> Facet {
+    match facet {
+        Facet::Link { byte_slice, uri } => Facet::Link {
+            byte_slice: f(byte_slice.start)..f(byte_slice.end),
+            uri: uri.clone(),
+        },
+    }
+}
+
+fn overlaps(facets: &[Facet], range: &Range<usize>) -> bool {
+    facets.iter().any(|facet| match facet {
+        Facet::Link { byte_slice, .. } => {
+            (byte_slice.start as usize) < range.end && range.start < byte_slice.end as usize
+        }
+    })
+}
+
+/** facet にかからない一致箇所のみを置換し、facet の位置をずらす */
+fn replace(content: &str, facets: &[Facet], replacement: &Replacement) -> (String, Vec<Facet>) {
+    let mut text = String::new();
+    // 置換した箇所の元の終端と、そこまでの長さの増減
+    let mut shifts: Vec<(usize, isize)> = Vec::new();
+    let mut delta = 0isize;
+    let mut last = 0;
+    for captures in replacement.pattern.captures_iter(content) {
+        let m = captures.get(0).unwrap();
+        if m.range().is_empty() || overlaps(facets, &m.range()) {
+            continue;
+        }
+        text.push_str(&content[last..m.start()]);
+        let start = text.len();
+        captures.expand(&replacement.replacement, &mut text);
+        delta += (text.len() - start) as isize - m.len() as isize;
+        shifts.push((m.end(), delta));
+        last = m.end();
+    }
+    text.push_str(&content[last..]);
+
+    let facets = facets
+        .iter()
+        .map(|facet| {
+            map_facet(facet, |pos| {
+                let delta = shifts
+                    .iter()
+                    .rev()
+                    .find(|(end, _)| *end <= pos as usize)
+                    .map_or(0, |(_, delta)| *delta);
+                (pos as isize + delta) as u32
+            })
+        })
+        .collect();
+    (text, facets)
+}
+
+/** テンプレートの書式の一部分 */
+enum Piece<'a> {
+    Text(&'a str),
+    Content,
+    SrcUri,
+}
+
+fn parse(format: &str) -> Vec<Piece<'_>> {
+    let mut pieces = Vec::new();
+    let mut rest = format;
+    while let Some(idx) = rest.find('{') {
+        pieces.push(Piece::Text(&rest[..idx]));
+        rest = &rest[idx..];
+        if let Some(next) = rest.strip_prefix("{content}") {
+            pieces.push(Piece::Content);
+            rest = next;
+        } else if let Some(next) = rest.strip_prefix("{srcUri}") {
+            pieces.push(Piece::SrcUri);
+            rest = next;
+        } else {
+            pieces.push(Piece::Text(&rest[..1]));
+            rest = &rest[1..];
+        }
+    }
+    pieces.push(Piece::Text(rest));
+    pieces
+}
+
+/**
+ * テンプレートに従って本文を組み立て、facet の位置を合わせる。
+ * 本文は置換の後、書式の残りの部分を足しても宛先の上限に収まるように切り詰める
+ */
+pub fn render(
+    account: &Account,
+    template: &Template,
+    content: &str,
+    facets: &[Facet],
+    src_uri: &str,
+) -> (String, Vec<Facet>) {
+    let (mut content, mut facets) = template.replacements.iter().fold(
+        (content.to_owned(), facets.to_vec()),
+        |(content, facets), replacement| replace(&content, &facets, replacement),
+    );
+    let pieces = template.format.as_deref().map(parse).unwrap_or_default();
+    let src_uri = to_web_uri(src_uri);
+    let reserved = pieces
+        .iter()
+        .map(|piece| match piece {
+            Piece::Text(text) => text_weight(account, text),
+            Piece::Content => 0,
+            Piece::SrcUri => uri_weight(account, &src_uri),
+        })
+        .sum();
+    truncate(account, &mut content, &mut facets, reserved);
+    if template.format.is_none() {
+        return (content, facets);
+    }
+
+    let mut text = String::new();
+    let mut rendered_facets = Vec::new();
+    for piece in pieces {
+        match piece {
+            Piece::Text(piece) => text.push_str(piece),
+            Piece::Content => {
+                let offset = text.len() as u32;
+                text.push_str(&content);
+                rendered_facets.extend(
+                    facets
+                        .iter()
+                        .map(|facet| map_facet(facet, |pos| pos + offset)),
+                );
+            }
+            Piece::SrcUri => {
+                let start = text.len() as u32;
+                text.push_str(&src_uri);
+                rendered_facets.push(Facet::Link {
+                    byte_slice: start..text.len() as u32,
+                    uri: src_uri.clone(),
+                });
+            }
+        }
+    }
+    (text, rendered_facets)
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use super::*;
+
+    fn twitter() -> Account {
+        Account::Twitter {
+            api_key: String::new(),
+            api_key_secret: String::new(),
+            access_token: String::new(),
+            access_token_secret: String::new(),
+        }
+    }
+
+    fn link(byte_slice: Range<u32>) -> Facet {
+        Facet::Link {
+            byte_slice,
+            uri: "https://example.com/".to_owned(),
+        }
+    }
+
+    /** facet の範囲にある文字列 */
+    fn linked_texts<'a>(text: &'a str, facets: &[Facet]) -> Vec<&'a str> {
+        facets
+            .iter()
+            .map(|facet| match facet {
+                Facet::Link { byte_slice, .. } => {
+                    &text[byte_slice.start as usize..byte_slice.end as usize]
+                }
+            })
+            .collect()
+    }
+
+    fn replacement(pattern: &str, replacement: &str) -> Replacement {
+        Replacement {
+            pattern: Regex::new(pattern).unwrap(),
+            replacement: replacement.to_owned(),
+        }
+    }
+
+    #[test]
+    fn replace_shifts_facets_after_the_match() {
+        let (text, facets) = replace("aa link aa", &[link(3..7)], &replacement("a+", "xxxx"));
+        assert_eq!(text, "xxxx link xxxx");
+        assert_eq!(linked_texts(&text, &facets), ["link"]);
+    }
+
+    #[test]
+    fn replace_shifts_facets_by_bytes() {
+        let (text, facets) = replace("ああ link", &[link(7..11)], &replacement("あ", "a"));
+        assert_eq!(text, "aa link");
+        assert_eq!(linked_texts(&text, &facets), ["link"]);
+    }
+
+    #[test]
+    fn replace_skips_matches_on_facets() {
+        let (text, facets) = replace("link link", &[link(5..9)], &replacement("link", "LINK"));
+        assert_eq!(text, "LINK link");
+        assert_eq!(linked_texts(&text, &facets), ["link"]);
+    }
+
+    #[test]
+    fn render_offsets_facets_by_format() {
+        let template = Template {
+            format: Some("【転載】{content}\n\n{srcUri}".to_owned()),
+            replacements: vec![replacement("a", "bb")],
+        };
+        let (text, facets) = render(
+            &twitter(),
+            &template,
+            "a link",
+            &[link(2..6)],
+            "https://example.com/1",
+        );
+        assert_eq!(text, "【転載】bb link\n\nhttps://example.com/1");
+        assert_eq!(
+            linked_texts(&text, &facets),
+            ["link", "https://example.com/1"]
+        );
+    }
+
+    #[test]
+    fn render_truncates_content_to_fit_the_format() {
+        let template = Template {
+            format: Some("{content}\n\n{srcUri}".to_owned()),
+            replacements: vec![],
+        };
+        let (text, _) = render(
+            &twitter(),
+            &template,
+            &"a".repeat(300),
+            &[],
+            "https://example.com/1",
+        );
+        // URL は 23 文字、"…" は 2 文字と数える
+        assert_eq!(text, "a".repeat(253) + "…\n\nhttps://example.com/1");
+    }
+
+    #[test]
+    fn render_expands_bluesky_src_uri_to_web_url() {
+        let template = Template {
+            format: Some("{srcUri}".to_owned()),
+            replacements: vec![],
+        };
+        let (text, _) = render(
+            &twitter(),
+            &template,
+            "",
+            &[],
+            "at://did:plc:abc/app.bsky.feed.post/xyz",
+        );
+        assert_eq!(text, "https://bsky.app/profile/did:plc:abc/post/xyz");
+    }
+}
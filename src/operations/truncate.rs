@@ -0,0 +1,120 @@
+use crate::{config::Account, store::operations::Facet};
+
+const ELLIPSIS: char = '…';
+/** Twitter と Mastodon は URL を長さによらずこの文字数で数える */
+const URI_WEIGHT: usize = 23;
+
+/** 宛先の本文の上限。Bluesky の上限は書記素の数だが、文字数で数えて超えない方に倒す */
+fn max_weight(account: &Account) -> usize {
+    match account {
+        Account::AtProtocol { .. } => 300,
+        Account::Mastodon { .. } => 500,
+        Account::Misskey { .. } => 3000,
+        Account::Twitter { .. } => 280,
+    }
+}
+
+/** Twitter は一部の範囲の外の文字を2文字と数える */
+fn char_weight(account: &Account, c: char) -> usize {
+    match account {
+        Account::Twitter { .. } => match c as u32 {
+            0..=4351 | 8192..=8205 | 8208..=8223 | 8242..=8247 => 1,
+            _ => 2,
+        },
+        Account::AtProtocol { .. } | Account::Mastodon { .. } | Account::Misskey { .. } => 1,
+    }
+}
+
+pub fn text_weight(account: &Account, text: &str) -> usize {
+    text.chars().map(|c| char_weight(account, c)).sum()
+}
+
+/** 短い URL も短縮後の長さで数えられるので、少なくとも URI_WEIGHT とする */
+pub fn uri_weight(account: &Account, uri: &str) -> usize {
+    text_weight(account, uri).max(URI_WEIGHT)
+}
+
+/**
+ * 末尾に reserved の長さを足しても宛先の上限に収まるように、本文を切り詰めて末尾に "…" を付ける。
+ * リンクの途中では切らず、切り捨てた範囲にかかる facet は除く
+ */
+pub fn truncate(account: &Account, content: &mut String, facets: &mut Vec<Facet>, reserved: usize) {
+    let max_weight = max_weight(account);
+    if text_weight(account, content) + reserved <= max_weight {
+        return;
+    }
+    let budget = max_weight.saturating_sub(reserved + char_weight(account, ELLIPSIS));
+    let mut weight = 0;
+    let mut end = content
+        .char_indices()
+        .find(|(_, c)| {
+            weight += char_weight(account, *c);
+            weight > budget
+        })
+        .map_or(content.len(), |(idx, _)| idx);
+    while let Some(start) = facets.iter().find_map(|facet| match facet {
+        Facet::Link { byte_slice, .. } => {
+            let range = byte_slice.start as usize..byte_slice.end as usize;
+            (range.start < end && end < range.end).then_some(range.start)
+        }
+    }) {
+        end = start;
+    }
+    content.truncate(end);
+    content.truncate(content.trim_end().len());
+    content.push(ELLIPSIS);
+    let end = content.len() as u32;
+    facets.retain(|facet| match facet {
+        Facet::Link { byte_slice, .. } => byte_slice.end <= end,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn twitter() -> Account {
+        Account::Twitter {
+            api_key: String::new(),
+            api_key_secret: String::new(),
+            access_token: String::new(),
+            access_token_secret: String::new(),
+        }
+    }
+
+    #[test]
+    fn truncate_keeps_short_content() {
+        let mut content = "hello".to_owned();
+        let mut facets = vec![];
+        truncate(&twitter(), &mut content, &mut facets, 30);
+        assert_eq!(content, "hello");
+    }
+
+    #[test]
+    fn truncate_counts_wide_characters_twice_on_twitter() {
+        let mut content = "あ".repeat(200);
+        let mut facets = vec![];
+        truncate(&twitter(), &mut content, &mut facets, 30);
+        assert!(text_weight(&twitter(), &content) + 30 <= 280);
+        assert!(content.ends_with(ELLIPSIS));
+    }
+
+    #[test]
+    fn truncate_does_not_cut_links() {
+        let uri = "https://example.com/".to_owned() + &"a".repeat(30);
+        let mut content = "a".repeat(230) + &uri + " tail";
+        let mut facets = vec![
+            Facet::Link {
+                byte_slice: 0..1,
+                uri: "https://example.com/head".to_owned(),
+            },
+            Facet::Link {
+                byte_slice: 230..(230 + uri.len()) as u32,
+                uri,
+            },
+        ];
+        truncate(&twitter(), &mut content, &mut facets, 30);
+        assert_eq!(content, "a".repeat(230) + "…");
+        assert_eq!(facets.len(), 1);
+    }
+}
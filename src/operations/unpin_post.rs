@@ -0,0 +1,30 @@
+use std::sync::Mutex;
+
+use anyhow::Result;
+use tracing::debug;
+
+use crate::{protocols::Client, store};
+
+use super::utils::find_post_dst_identifier;
+
+pub async fn unpin_post(
+    store: &Mutex<&mut store::Store>,
+    dst_client: &mut dyn Client,
+    operation: store::operations::UnpinPostOperation,
+) -> Result<()> {
+    let dst_identifier = find_post_dst_identifier(
+        &store.lock().unwrap(),
+        &operation.account_pair.src_origin,
+        &operation.status.src_identifier,
+        &operation.account_pair.dst_origin,
+    )
+    .map(str::to_owned);
+    let Some(dst_identifier) = dst_identifier else {
+        debug!(
+            "dst_identifier not found (src_identifier={})",
+            operation.status.src_identifier
+        );
+        return Ok(());
+    };
+    dst_client.unpin(&dst_identifier).await
+}
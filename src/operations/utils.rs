@@ -1,31 +1,43 @@
-use crate::store;
+use std::sync::Mutex;
 
+use chrono::{DateTime, Duration, FixedOffset};
+use tracing::{info, warn};
+
+use crate::{
+    protocols::Client,
+    store::{self, operations::AccountPair},
+};
+
+/** 作業用の statuses に無ければ archive から探す */
 fn destination_statuses<'a>(
-    users: &'a [store::user::User],
-    src_origin: &str,
-    dst_origin: &str,
+    store: &'a store::Store,
+    src_origin: &'a str,
+    dst_origin: &'a str,
 ) -> Vec<&'a store::user::DestinationStatus> {
-    users
+    store
+        .users
         .iter()
         .filter(|user| user.src.origin == src_origin)
         .flat_map(|user| &user.dsts)
         .filter(|dst| dst.origin == dst_origin)
         .flat_map(|dst| &dst.statuses)
+        .chain(store.archive.statuses(src_origin, dst_origin))
         .collect()
 }
 
 pub fn find_post_dst_identifier<'a>(
-    users: &'a [store::user::User],
-    src_origin: &str,
+    store: &'a store::Store,
+    src_origin: &'a str,
     src_identifier: &str,
-    dst_origin: &str,
+    dst_origin: &'a str,
 ) -> Option<&'a str> {
     Some(
-        destination_statuses(users, src_origin, dst_origin)
+        destination_statuses(store, src_origin, dst_origin)
             .iter()
             .filter_map(|dst_status| match dst_status {
                 store::user::DestinationStatus::Post(post) => Some(post),
-                store::user::DestinationStatus::Repost(_) => None,
+                store::user::DestinationStatus::Repost(_)
+                | store::user::DestinationStatus::Like(_) => None,
             })
             .find(|dst_post| dst_post.src_identifier == src_identifier)?
             .identifier
@@ -34,19 +46,28 @@ pub fn find_post_dst_identifier<'a>(
 }
 
 pub fn find_post_dst_identifier_by_uri<'a>(
-    users: &'a [store::user::User],
+    store: &'a store::Store,
     src_uri: &str,
     dst_origin: &str,
 ) -> Option<&'a str> {
+    let archived = store
+        .archive
+        .statuses
+        .iter()
+        .filter(|archived| archived.account_pair.dst_origin == dst_origin)
+        .map(|archived| &archived.status);
     Some(
-        users
+        store
+            .users
             .iter()
             .flat_map(|user| &user.dsts)
             .filter(|dst| dst.origin == dst_origin)
             .flat_map(|dst| &dst.statuses)
+            .chain(archived)
             .filter_map(|dst_status| match dst_status {
                 store::user::DestinationStatus::Post(post) => Some(post),
-                store::user::DestinationStatus::Repost(_) => None,
+                store::user::DestinationStatus::Repost(_)
+                | store::user::DestinationStatus::Like(_) => None,
             })
             .find(|dst_post| dst_post.src_uri == src_uri)?
             .identifier
@@ -54,21 +75,155 @@ pub fn find_post_dst_identifier_by_uri<'a>(
     )
 }
 
-pub fn find_repost_dst_identifier<'a>(
-    users: &'a [store::user::User],
-    src_origin: &str,
+pub fn find_dst_repost<'a>(
+    store: &'a store::Store,
+    src_origin: &'a str,
     src_identifier: &str,
+    dst_origin: &'a str,
+) -> Option<&'a store::user::DestinationRepost> {
+    destination_statuses(store, src_origin, dst_origin)
+        .into_iter()
+        .filter_map(|dst_status| match dst_status {
+            store::user::DestinationStatus::Post(_) | store::user::DestinationStatus::Like(_) => {
+                None
+            }
+            store::user::DestinationStatus::Repost(repost) => Some(repost),
+        })
+        .find(|dst_repost| dst_repost.src_identifier == src_identifier)
+}
+
+pub fn find_resolved_dst_identifier<'a>(
+    users: &'a [store::user::User],
+    uri: &str,
     dst_origin: &str,
 ) -> Option<&'a str> {
     Some(
-        destination_statuses(users, src_origin, dst_origin)
+        users
             .iter()
-            .filter_map(|dst_status| match dst_status {
-                store::user::DestinationStatus::Post(_) => None,
-                store::user::DestinationStatus::Repost(repost) => Some(repost),
+            .flat_map(|user| &user.dsts)
+            .filter(|dst| dst.origin == dst_origin)
+            .flat_map(|dst| &dst.resolved_statuses)
+            .find(|resolved_status| resolved_status.uri == uri)?
+            .identifier
+            .as_str(),
+    )
+}
+
+/**
+ * 宛先のアカウントに、他の取得元から window 以内に送信された同じ指紋の投稿を探す。
+ * 指紋は取得元の User ごとに持つので、同じ取得元のものは対象にしない
+ */
+pub fn find_fingerprint_dst_identifier<'a>(
+    users: &'a [store::user::User],
+    account_pair: &AccountPair,
+    hash: &str,
+    created_at: &DateTime<FixedOffset>,
+    window: Duration,
+) -> Option<&'a str> {
+    let dst_account_key = account_pair.to_dst_key();
+    Some(
+        users
+            .iter()
+            .filter(|user| {
+                user.src.origin != account_pair.src_origin
+                    || user.src.identifier != account_pair.src_account_identifier
             })
-            .find(|dst_post| dst_post.src_identifier == src_identifier)?
+            .flat_map(|user| &user.dsts)
+            .filter(|dst| {
+                dst.origin == dst_account_key.origin && dst.identifier == dst_account_key.identifier
+            })
+            .flat_map(|dst| &dst.fingerprints)
+            .find(|fingerprint| {
+                fingerprint.hash == hash && (fingerprint.created_at - *created_at).abs() <= window
+            })?
             .identifier
             .as_str(),
     )
 }
+
+/**
+ * 宛先の post に、他の取得元の status も重複として対応付けているか。
+ * その場合、宛先の post は最後の対応が消えるまで残す
+ */
+pub fn is_dst_post_shared(
+    store: &store::Store,
+    account_pair: &AccountPair,
+    dst_identifier: &str,
+) -> bool {
+    let is_other_src = |src_origin: &str, src_account_identifier: &str| {
+        src_origin != account_pair.src_origin
+            || src_account_identifier != account_pair.src_account_identifier
+    };
+    let is_same_dst = |dst_origin: &str, dst_account_identifier: &str| {
+        dst_origin == account_pair.dst_origin
+            && dst_account_identifier == account_pair.dst_account_identifier
+    };
+    let refers = |status: &store::user::DestinationStatus| {
+        matches!(status, store::user::DestinationStatus::Post(post)
+            if post.identifier == dst_identifier)
+    };
+    let in_statuses = store
+        .users
+        .iter()
+        .filter(|user| is_other_src(&user.src.origin, &user.src.identifier))
+        .flat_map(|user| &user.dsts)
+        .filter(|dst| is_same_dst(&dst.origin, &dst.identifier))
+        .flat_map(|dst| &dst.statuses)
+        .any(refers);
+    in_statuses
+        || store.archive.statuses.iter().any(|archived| {
+            let pair = &archived.account_pair;
+            is_other_src(&pair.src_origin, &pair.src_account_identifier)
+                && is_same_dst(&pair.dst_origin, &pair.dst_account_identifier)
+                && refers(&archived.status)
+        })
+}
+
+/** 他のサーバーの status を宛先のサーバーで解決する。結果は store に保持する */
+pub async fn resolve(
+    store: &Mutex<&mut store::Store>,
+    dst_client: &mut dyn Client,
+    account_pair: &AccountPair,
+    uri: &str,
+) -> Option<String> {
+    let cached =
+        find_resolved_dst_identifier(&store.lock().unwrap().users, uri, &account_pair.dst_origin)
+            .map(str::to_owned);
+    if cached.is_some() {
+        return cached;
+    }
+    let identifier = match dst_client.resolve(uri).await {
+        Ok(identifier) => identifier?,
+        Err(err) => {
+            warn!("resolve failed (uri={}): {:?}", uri, err);
+            return None;
+        }
+    };
+    info!("resolved (uri={}, identifier={})", uri, identifier);
+    store
+        .lock()
+        .unwrap()
+        .get_or_create_dst_mut(account_pair)
+        .insert_resolved_status(store::user::ResolvedStatus {
+            uri: uri.to_owned(),
+            identifier: identifier.clone(),
+        });
+    Some(identifier)
+}
+
+pub fn find_dst_like<'a>(
+    store: &'a store::Store,
+    src_origin: &'a str,
+    src_identifier: &str,
+    dst_origin: &'a str,
+) -> Option<&'a store::user::DestinationLike> {
+    destination_statuses(store, src_origin, dst_origin)
+        .into_iter()
+        .filter_map(|dst_status| match dst_status {
+            store::user::DestinationStatus::Post(_) | store::user::DestinationStatus::Repost(_) => {
+                None
+            }
+            store::user::DestinationStatus::Like(like) => Some(like),
+        })
+        .find(|dst_like| dst_like.src_identifier == src_identifier)
+}
@@ -1,12 +1,15 @@
 mod at_proto;
 pub mod at_proto_client;
 mod from_megalodon;
+pub mod media;
 pub mod megalodon_client;
 mod misskey_client;
+#[cfg(test)]
+pub mod test_client;
 mod twitter_api;
 pub mod twitter_client;
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use anyhow::Result;
 use async_trait::async_trait;
@@ -15,6 +18,45 @@ use futures::future::join_all;
 
 use crate::{app::AccountKey, config, sources::source, store};
 
+use self::media::MediaFetcher;
+
+/** 宛先を書き換える要求。Client の同名のメソッドの引数と同じ */
+pub enum Request<'a> {
+    Post {
+        content: &'a str,
+        facets: &'a [store::operations::Facet],
+        reply_identifier: Option<&'a str>,
+        images: &'a [store::operations::Medium],
+        external: Option<&'a store::operations::External>,
+        created_at: &'a DateTime<FixedOffset>,
+    },
+    Repost {
+        target_identifier: &'a str,
+        created_at: &'a DateTime<FixedOffset>,
+    },
+    DeletePost {
+        identifier: &'a str,
+    },
+    DeleteRepost {
+        identifier: &'a str,
+    },
+    Like {
+        target_identifier: &'a str,
+    },
+    Unlike {
+        identifier: &'a str,
+    },
+    UpdateProfile {
+        profile: &'a store::operations::Profile,
+    },
+    Pin {
+        identifier: &'a str,
+    },
+    Unpin {
+        identifier: &'a str,
+    },
+}
+
 #[async_trait]
 pub trait Client: Send + Sync {
     fn to_account_key(&self) -> AccountKey {
@@ -29,6 +71,17 @@ pub trait Client: Send + Sync {
 
     async fn fetch_statuses(&mut self) -> Result<Vec<source::LiveStatus>>;
 
+    /** status の uri を取得する。LiveStatus に uri を含められない場合のみ実装する */
+    async fn fetch_status_uri(&mut self, _identifier: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /** cursor より古い status を新しい順に取得し、次のページの cursor と共に返す */
+    async fn fetch_statuses_page(
+        &mut self,
+        cursor: Option<&str>,
+    ) -> Result<(Vec<source::LiveStatus>, Option<String>)>;
+
     async fn post(
         &mut self,
         content: &str,
@@ -39,6 +92,12 @@ pub trait Client: Send + Sync {
         created_at: &DateTime<FixedOffset>,
     ) -> Result<String>;
 
+    /**
+     * 送信するときと同じ方法で要求を組み立てて、送信せずに返す。
+     * メディアはアップロードせず、アップロード後の id の代わりに URL を入れる
+     */
+    fn render(&self, request: &Request<'_>) -> Result<serde_json::Value>;
+
     async fn repost(
         &mut self,
         target_identifier: &str,
@@ -48,10 +107,75 @@ pub trait Client: Send + Sync {
     async fn delete_post(&mut self, identifier: &str) -> Result<()>;
 
     async fn delete_repost(&mut self, identifier: &str) -> Result<()>;
+
+    /** like した status を新しい順に取得する */
+    async fn fetch_likes(&mut self) -> Result<Vec<store::operations::CreateLikeOperationStatus>> {
+        Ok(Vec::new())
+    }
+
+    async fn like(&mut self, target_identifier: &str) -> Result<String>;
+
+    async fn unlike(&mut self, identifier: &str) -> Result<()>;
+
+    /** 自分のプロフィールを取得する。対応していなければ None */
+    async fn fetch_profile(&mut self) -> Result<Option<store::operations::Profile>> {
+        Ok(None)
+    }
+
+    async fn update_profile(&mut self, profile: &store::operations::Profile) -> Result<()>;
+
+    /** 送信した post が宛先に残っているか。確認できなければ None */
+    async fn exists_post(&mut self, _identifier: &str) -> Result<Option<bool>> {
+        Ok(None)
+    }
+
+    /** 送信した repost が宛先に残っているか。確認できなければ None */
+    async fn exists_repost(&mut self, _identifier: &str) -> Result<Option<bool>> {
+        Ok(None)
+    }
+
+    /**
+     * 取得元の post のうち、削除されたものの identifier を返す。
+     * posts は identifier と uri の組。確認できなければ None
+     */
+    async fn fetch_deleted_post_identifiers(
+        &mut self,
+        posts: &[(String, String)],
+    ) -> Result<Option<Vec<String>>> {
+        let mut deleted = Vec::new();
+        for (identifier, _) in posts {
+            match self.exists_post(identifier).await? {
+                Some(true) => {}
+                Some(false) => deleted.push(identifier.clone()),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(deleted))
+    }
+
+    /** 固定している post の identifier を取得する。対応していなければ None */
+    async fn fetch_pinned_identifiers(&mut self) -> Result<Option<Vec<String>>> {
+        Ok(None)
+    }
+
+    async fn pin(&mut self, identifier: &str) -> Result<()>;
+
+    async fn unpin(&mut self, identifier: &str) -> Result<()>;
+
+    /** 他のサーバーの status の uri から、この宛先での identifier を取得する */
+    async fn resolve(&mut self, _uri: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+/** 応答が無いまま実行時間を使い切らないように、全ての通信に timeout を設ける */
+pub fn create_http_client(timeout: Duration) -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder().timeout(timeout).build()?)
 }
 
 pub async fn create_client(
     http_client: Arc<reqwest::Client>,
+    media_fetcher: Arc<MediaFetcher>,
     account: &config::Account,
 ) -> Result<Box<dyn Client>> {
     match account {
@@ -63,6 +187,7 @@ pub async fn create_client(
             at_proto_client::Client::new(
                 origin.into(),
                 http_client,
+                media_fetcher,
                 identifier.into(),
                 password.into(),
             )
@@ -72,13 +197,25 @@ pub async fn create_client(
             origin,
             access_token,
         } => Ok(Box::new(
-            megalodon_client::Client::new_mastodon(origin.clone(), access_token.clone()).await?,
+            megalodon_client::Client::new_mastodon(
+                http_client,
+                media_fetcher,
+                origin.clone(),
+                access_token.clone(),
+            )
+            .await?,
         )),
         config::Account::Misskey {
             origin,
             access_token,
         } => Ok(Box::new(
-            misskey_client::Client::new(http_client, origin.clone(), access_token.clone()).await?,
+            misskey_client::Client::new(
+                http_client,
+                media_fetcher,
+                origin.clone(),
+                access_token.clone(),
+            )
+            .await?,
         )),
         config::Account::Twitter {
             api_key,
@@ -88,6 +225,7 @@ pub async fn create_client(
         } => Ok(Box::new(
             twitter_client::Client::new(
                 http_client,
+                media_fetcher,
                 api_key.clone(),
                 api_key_secret.clone(),
                 access_token.clone(),
@@ -98,15 +236,51 @@ pub async fn create_client(
     }
 }
 
-pub async fn create_clients(
+/** 宛先の client と、その宛先の設定 */
+pub struct DstClient<'a> {
+    pub client: Box<dyn Client>,
+    pub config: &'a config::Destination,
+    pub reply_policy: config::ReplyPolicy,
+}
+
+async fn create_clients_for<'a>(
     http_client: &Arc<reqwest::Client>,
-    accounts: &[config::Account],
-) -> Result<Vec<Box<dyn Client>>> {
-    let clients = accounts
-        .iter()
-        .map(|dst| create_client(http_client.clone(), dst));
+    media_fetcher: &Arc<MediaFetcher>,
+    config_user: &'a config::User,
+    dsts: &'a [config::Destination],
+) -> Result<Vec<DstClient<'a>>> {
+    let clients = dsts.iter().map(|dst| async move {
+        Ok(DstClient {
+            client: create_client(http_client.clone(), media_fetcher.clone(), &dst.account).await?,
+            config: dst,
+            reply_policy: config_user.reply_policy(dst),
+        })
+    });
     join_all(clients)
         .await
         .into_iter()
         .collect::<Result<Vec<_>>>()
 }
+
+pub async fn create_dst_clients<'a>(
+    http_client: &Arc<reqwest::Client>,
+    media_fetcher: &Arc<MediaFetcher>,
+    config_user: &'a config::User,
+) -> Result<Vec<DstClient<'a>>> {
+    create_clients_for(http_client, media_fetcher, config_user, &config_user.dsts).await
+}
+
+/** 登録済みの operation を処理するためだけの、取り外した宛先の client */
+pub async fn create_retired_dst_clients<'a>(
+    http_client: &Arc<reqwest::Client>,
+    media_fetcher: &Arc<MediaFetcher>,
+    config_user: &'a config::User,
+) -> Result<Vec<DstClient<'a>>> {
+    create_clients_for(
+        http_client,
+        media_fetcher,
+        config_user,
+        &config_user.retired_dsts,
+    )
+    .await
+}
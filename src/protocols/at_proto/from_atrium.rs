@@ -60,15 +60,19 @@ impl From<Box<app::bsky::embed::external::View>> for source::LiveExternal {
 }
 
 fn to_external_uri(at_uri: &str) -> String {
+    to_web_uri(at_uri).unwrap()
+}
+
+/** 投稿の at:// の uri を bsky.app の URL にする。投稿の uri でなければ None */
+pub fn to_web_uri(at_uri: &str) -> Option<String> {
     let m = Regex::new(r"^at://(.+?)/app.bsky.feed.post/(.+?)$")
         .unwrap()
-        .captures(at_uri)
-        .unwrap();
-    format!(
+        .captures(at_uri)?;
+    Some(format!(
         "https://bsky.app/profile/{}/post/{}",
         m.get(1).unwrap().as_str(),
         m.get(2).unwrap().as_str(),
-    )
+    ))
 }
 
 fn rewrite_content(
@@ -165,6 +169,15 @@ fn parse_embed(
     }
 }
 
+impl From<app::bsky::feed::defs::PostView> for store::operations::CreateLikeOperationStatus {
+    fn from(value: app::bsky::feed::defs::PostView) -> Self {
+        Self {
+            target_src_identifier: value.cid.as_ref().to_string(),
+            target_src_uri: to_external_uri(&value.uri),
+        }
+    }
+}
+
 impl TryFrom<app::bsky::feed::defs::FeedViewPost> for source::LiveStatus {
     type Error = anyhow::Error;
 
@@ -181,6 +194,7 @@ impl TryFrom<app::bsky::feed::defs::FeedViewPost> for source::LiveStatus {
                     src_identifier: value.post.cid.as_ref().to_string(),
                     target_src_identifier: value.post.cid.as_ref().to_string(),
                     target_src_uri: to_external_uri(&value.post.uri),
+                    target_content: Some(record.text.clone()),
                     created_at: DateTime::parse_from_rfc3339(
                         &reason.indexed_at.as_ref().to_rfc3339(),
                     )?,
@@ -201,7 +215,13 @@ impl TryFrom<app::bsky::feed::defs::FeedViewPost> for source::LiveStatus {
                         quote.as_deref(),
                     ),
                     facets,
-                    reply_src_identifier: record.reply.map(|x| x.parent.cid.as_ref().to_string()),
+                    reply_src_identifier: record
+                        .reply
+                        .as_ref()
+                        .map(|x| x.parent.cid.as_ref().to_string()),
+                    // 取得範囲内の返信先なら fill_reply_src_uris で外す
+                    reply_src_uri: record.reply.map(|x| to_external_uri(&x.parent.uri)),
+                    visibility: Some("public".to_owned()),
                     media,
                     external,
                     created_at: DateTime::parse_from_rfc3339(
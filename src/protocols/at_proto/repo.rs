@@ -1,7 +1,7 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use atrium_api::{app::bsky::feed::post::ReplyRef, com};
 use chrono::{DateTime, FixedOffset};
-use reqwest::{header::CONTENT_TYPE, Body};
+use reqwest::{header::CONTENT_TYPE, Body, StatusCode};
 use serde::Serialize;
 use serde_json::{json, Value};
 use tracing::error;
@@ -124,6 +124,81 @@ impl Repo {
         query(client, &self.origin, token, lexicon_id, query_params).await
     }
 
+    pub async fn exists_record(
+        &self,
+        client: &reqwest::Client,
+        session: &com::atproto::server::create_session::Output,
+        collection: &str,
+        rkey: &str,
+    ) -> Result<bool> {
+        let lexicon_id = "com.atproto.repo.getRecord";
+        let resp = client
+            .get(format!("{}/xrpc/{}", self.origin, lexicon_id))
+            .query(&[
+                ("repo", session.did.as_str()),
+                ("collection", collection),
+                ("rkey", rkey),
+            ])
+            .bearer_auth(&session.access_jwt)
+            .send()
+            .await?;
+        if resp.status() == StatusCode::BAD_REQUEST {
+            let json: Value = resp.json().await?;
+            if json.get("error").and_then(Value::as_str) == Some("RecordNotFound") {
+                return Ok(false);
+            }
+            bail!("getRecord failed: {}", json);
+        }
+        resp.error_for_status()?;
+        Ok(true)
+    }
+
+    /** プロフィールのレコード。未作成の場合は None */
+    pub async fn get_profile_record(
+        &self,
+        client: &reqwest::Client,
+        session: &com::atproto::server::create_session::Output,
+    ) -> Result<Option<Value>> {
+        let lexicon_id = "com.atproto.repo.getRecord";
+        let resp = client
+            .get(format!("{}/xrpc/{}", self.origin, lexicon_id))
+            .query(&[
+                ("repo", session.did.as_str()),
+                ("collection", "app.bsky.actor.profile"),
+                ("rkey", "self"),
+            ])
+            .bearer_auth(&session.access_jwt)
+            .send()
+            .await?;
+        if resp.status().is_client_error() {
+            return Ok(None);
+        }
+        let mut json: Value = resp.error_for_status()?.json().await?;
+        Ok(json.get_mut("value").map(Value::take))
+    }
+
+    pub async fn put_profile_record(
+        &self,
+        client: &reqwest::Client,
+        session: &com::atproto::server::create_session::Output,
+        record: &Value,
+    ) -> Result<Value> {
+        let lexicon_id = "com.atproto.repo.putRecord";
+        procedure(
+            client,
+            &self.origin,
+            &session.access_jwt,
+            lexicon_id,
+            &json!({
+                "repo": &session.did,
+                "collection": "app.bsky.actor.profile",
+                "rkey": "self",
+                "record": record,
+            }),
+        )
+        .await
+    }
+
     #[allow(unused)]
     pub async fn list_records(&self, client: &reqwest::Client, session: &Session) -> Result<Value> {
         let token = &session.access_jwt;
@@ -6,10 +6,12 @@ use atrium_api::{
 };
 use chrono::{DateTime, FixedOffset};
 use regex::Regex;
-use reqwest::header::CONTENT_TYPE;
-use serde_json::json;
+use serde_json::{json, Value};
 
-use crate::store::{self, operations::Facet::Link};
+use crate::{
+    protocols::media::{MediaError, MediaFetcher},
+    store::{self, operations::Facet::Link},
+};
 
 use super::{
     repo::{Embed, External, Image, Record},
@@ -87,64 +89,91 @@ pub fn uri_to_repost_rkey(uri: &str) -> Result<String> {
         .to_owned())
 }
 
-pub async fn to_embed(
+pub fn uri_to_like_rkey(uri: &str) -> Result<String> {
+    Ok(Regex::new(r"at://did:plc:.+?/app.bsky.feed.like/(.+)")
+        .unwrap()
+        .captures(uri)
+        .ok_or_else(|| anyhow!("invalid uri format"))?[1]
+        .to_owned())
+}
+
+/** 画像を取得して blob としてアップロードし、blob を返す */
+pub async fn upload_image(
     api: &Api,
     http_client: &reqwest::Client,
+    media_fetcher: &MediaFetcher,
     session: &com::atproto::server::create_session::Output,
-    images: Vec<store::operations::Medium>,
-    external: Option<store::operations::External>,
-) -> Result<Option<Embed>> {
+    url: &str,
+) -> Result<Value> {
+    let media = media_fetcher.fetch(url).await?;
+    let mut res = api
+        .repo
+        .upload_blob(http_client, session, media.content_type, media.bytes)
+        .await?;
+    Ok(res
+        .get_mut("blob")
+        .ok_or_else(|| anyhow!("blob not found"))?
+        .take())
+}
+
+/** 埋め込むメディアの URL。画像があれば画像、無ければリンクカードのサムネイル */
+pub fn to_embed_media_urls<'a>(
+    images: &'a [store::operations::Medium],
+    external: Option<&'a store::operations::External>,
+) -> Vec<&'a str> {
     if !images.is_empty() {
-        let mut array = Vec::new();
-        for image in images {
-            let resp = http_client.get(&image.url).send().await?;
-            let content_type = resp
-                .headers()
-                .get(CONTENT_TYPE)
-                .ok_or_else(|| anyhow!("no content-type"))?
-                .to_str()?
-                .to_owned();
+        return images.iter().map(|image| image.url.as_str()).collect();
+    }
+    external
+        .and_then(|external| external.thumb_url.as_deref())
+        .into_iter()
+        .collect()
+}
 
-            let mut res = api
-                .repo
-                .upload_blob(http_client, session, content_type, resp)
-                .await?;
-            let alt = image.alt;
-            let image = res
-                .get_mut("blob")
-                .ok_or_else(|| anyhow!("blob not found"))?
-                .take();
-            array.push(Image { image, alt });
-        }
-        return Ok(Some(Embed::Images(array)));
+/** to_embed_media_urls の順に並べた blob で埋め込みを組み立てる */
+pub fn build_embed(
+    images: &[store::operations::Medium],
+    external: Option<&store::operations::External>,
+    blobs: Vec<Value>,
+) -> Option<Embed> {
+    if !images.is_empty() {
+        return Some(Embed::Images(
+            images
+                .iter()
+                .zip(blobs)
+                .map(|(image, blob)| Image {
+                    image: blob,
+                    alt: image.alt.clone(),
+                })
+                .collect(),
+        ));
     }
-    if let Some(external) = external {
-        if let Some(thumb_url) = &external.thumb_url {
-            let resp = http_client.get(thumb_url).send().await?;
-            let content_type = resp
-                .headers()
-                .get(CONTENT_TYPE)
-                .ok_or_else(|| anyhow!("no content-type"))?
-                .to_str()?
-                .to_owned();
+    let external = external?;
+    let thumb = blobs.into_iter().next()?;
+    Some(Embed::External(External {
+        uri: external.uri.clone(),
+        title: external.title.clone(),
+        description: external.description.clone(),
+        thumb,
+    }))
+}
 
-            let mut res = api
-                .repo
-                .upload_blob(http_client, session, content_type, resp)
-                .await?;
-            let thumb = res
-                .get_mut("blob")
-                .ok_or_else(|| anyhow!("blob not found"))?
-                .take();
-            return Ok(Some(Embed::External(External {
-                uri: external.uri,
-                title: external.title,
-                description: external.description,
-                thumb,
-            })));
-        }
+pub async fn to_embed(
+    api: &Api,
+    http_client: &reqwest::Client,
+    media_fetcher: &MediaFetcher,
+    session: &com::atproto::server::create_session::Output,
+    images: &[store::operations::Medium],
+    external: Option<&store::operations::External>,
+) -> Result<Option<Embed>> {
+    let mut blobs = Vec::new();
+    for url in to_embed_media_urls(images, external) {
+        let blob = upload_image(api, http_client, media_fetcher, session, url)
+            .await
+            .map_err(|err| MediaError::wrap(url, err))?;
+        blobs.push(blob);
     }
-    Ok(None)
+    Ok(build_embed(images, external, blobs))
 }
 
 pub async fn find_reply_root(
@@ -165,7 +194,7 @@ pub async fn find_reply_root(
     Ok(Some(reply.root))
 }
 
-pub async fn to_reply<'a>(
+pub async fn to_reply(
     api: &Api,
     http_client: &reqwest::Client,
     session: &com::atproto::server::create_session::Output,
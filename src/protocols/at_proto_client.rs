@@ -13,19 +13,142 @@ use atrium_api::{
 };
 use atrium_xrpc_client::reqwest::ReqwestClient;
 use chrono::{DateTime, FixedOffset};
-use serde_json::Value;
+use serde_json::{json, Value};
 
 use crate::{sources::source, store};
 
-use super::at_proto::{
-    utils::{to_embed, to_record, to_reply, uri_to_post_rkey, uri_to_repost_rkey},
-    Api,
+use super::{
+    at_proto::{
+        from_atrium,
+        utils::{
+            build_embed, to_embed, to_embed_media_urls, to_record, to_reply, upload_image,
+            uri_to_like_rkey, uri_to_post_rkey, uri_to_repost_rkey,
+        },
+        Api,
+    },
+    media::MediaFetcher,
+    Request,
 };
 
+/** 投稿の at:// の uri を bsky.app の URL にする。それ以外の uri はそのまま返す */
+pub fn to_web_uri(uri: &str) -> String {
+    from_atrium::to_web_uri(uri).unwrap_or_else(|| uri.to_owned())
+}
+
+fn to_strong_ref(identifier: &str) -> Result<com::atproto::repo::strong_ref::Main> {
+    let identifier: com::atproto::repo::create_record::Output = serde_json::from_str(identifier)?;
+    Ok(com::atproto::repo::strong_ref::Main {
+        cid: identifier.cid,
+        uri: identifier.uri,
+    })
+}
+
+/** repost と like で作る record の collection と内容 */
+fn to_create_record(request: &Request<'_>) -> Result<(&'static str, atrium_api::records::Record)> {
+    match *request {
+        Request::Repost {
+            target_identifier,
+            created_at,
+        } => Ok((
+            "app.bsky.feed.repost",
+            atrium_api::records::Record::Known(KnownRecord::AppBskyFeedRepost(Box::new(
+                app::bsky::feed::repost::Record {
+                    created_at: Datetime::new(created_at.to_owned()),
+                    subject: to_strong_ref(target_identifier)?,
+                },
+            ))),
+        )),
+        Request::Like { target_identifier } => Ok((
+            "app.bsky.feed.like",
+            atrium_api::records::Record::Known(KnownRecord::AppBskyFeedLike(Box::new(
+                app::bsky::feed::like::Record {
+                    created_at: Datetime::now(),
+                    subject: to_strong_ref(target_identifier)?,
+                },
+            ))),
+        )),
+        _ => unreachable!(),
+    }
+}
+
+/** 削除する record の collection と rkey */
+fn to_delete_target(request: &Request<'_>) -> Result<(&'static str, String)> {
+    match *request {
+        Request::DeletePost { identifier } => {
+            let json: Value = serde_json::from_str(identifier)?;
+            let uri = json
+                .get("uri")
+                .ok_or_else(|| anyhow!("uri not found ({})", identifier))?
+                .as_str()
+                .ok_or_else(|| anyhow!("uri is not string"))?;
+            Ok(("app.bsky.feed.post", uri_to_post_rkey(uri)?))
+        }
+        Request::DeleteRepost { identifier } => {
+            let output: com::atproto::repo::put_record::Output = serde_json::from_str(identifier)?;
+            Ok(("app.bsky.feed.repost", uri_to_repost_rkey(&output.uri)?))
+        }
+        Request::Unlike { identifier } => {
+            let output: com::atproto::repo::create_record::Output =
+                serde_json::from_str(identifier)?;
+            Ok(("app.bsky.feed.like", uri_to_like_rkey(&output.uri)?))
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn empty_profile_record() -> Value {
+    json!({ "$type": "app.bsky.actor.profile" })
+}
+
+/**
+ * プロフィールの record を書き換える。固定を外すときに、別の post に置き換わっていれば false を返す。
+ * 画像はアップロードするまで blob が無いので、代わりに URL を入れておく
+ */
+fn apply_to_profile_record(record: &mut Value, request: &Request<'_>) -> Result<bool> {
+    match *request {
+        Request::UpdateProfile { profile } => {
+            if let Some(display_name) = &profile.display_name {
+                record["displayName"] = display_name.as_str().into();
+            }
+            if let Some(description) = &profile.description {
+                record["description"] = description.as_str().into();
+            }
+            if let Some(avatar_url) = &profile.avatar_url {
+                record["avatar"] = avatar_url.as_str().into();
+            }
+            if let Some(banner_url) = &profile.banner_url {
+                record["banner"] = banner_url.as_str().into();
+            }
+        }
+        Request::Pin { identifier } => {
+            let identifier = to_strong_ref(identifier)?;
+            record["pinnedPost"] = json!({ "uri": identifier.uri, "cid": identifier.cid });
+        }
+        Request::Unpin { identifier } => {
+            let identifier = to_strong_ref(identifier)?;
+            // 固定できるのは一つだけなので、別の post に置き換わっていれば何もしない
+            let is_pinned = record
+                .get("pinnedPost")
+                .and_then(|pinned_post| pinned_post.get("uri"))
+                .and_then(Value::as_str)
+                == Some(identifier.uri.as_str());
+            if !is_pinned {
+                return Ok(false);
+            }
+            if let Some(record) = record.as_object_mut() {
+                record.remove("pinnedPost");
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(true)
+}
+
 pub struct Client {
     agent: AtpAgent<MemorySessionStore, ReqwestClient>,
     api: Api,
     http_client: Arc<reqwest::Client>,
+    media_fetcher: Arc<MediaFetcher>,
     session: Option<com::atproto::server::create_session::Output>,
     pub identifier: String,
     password: String,
@@ -36,6 +159,7 @@ impl Client {
     pub async fn new(
         origin: String,
         http_client: Arc<reqwest::Client>,
+        media_fetcher: Arc<MediaFetcher>,
         identifier: String,
         password: String,
     ) -> Result<Self> {
@@ -48,6 +172,7 @@ impl Client {
             agent,
             api: Api::new(origin),
             http_client,
+            media_fetcher,
             session: None,
             identifier,
             password,
@@ -87,6 +212,14 @@ impl super::Client for Client {
 
     #[tracing::instrument(name = "at_proto_client::Client::fetch_statuses", skip_all)]
     async fn fetch_statuses(&mut self) -> Result<Vec<source::LiveStatus>> {
+        Ok(self.fetch_statuses_page(None).await?.0)
+    }
+
+    #[tracing::instrument(name = "at_proto_client::Client::fetch_statuses_page", skip_all)]
+    async fn fetch_statuses_page(
+        &mut self,
+        cursor: Option<&str>,
+    ) -> Result<(Vec<source::LiveStatus>, Option<String>)> {
         let session = match &self.session {
             Some(some) => some,
             None => {
@@ -97,7 +230,7 @@ impl super::Client for Client {
 
         let params = app::bsky::feed::get_author_feed::Parameters {
             actor: session.did.clone().into(),
-            cursor: None,
+            cursor: cursor.map(str::to_owned),
             filter: None,
             limit: Some(LimitedNonZeroU8::try_from(50).unwrap()),
         };
@@ -110,7 +243,12 @@ impl super::Client for Client {
             .get_author_feed(params)
             .await
             .map_err(|err| anyhow::anyhow!("{:?}", err))?;
-        output.feed.into_iter().map(|x| x.try_into()).collect()
+        let statuses = output
+            .feed
+            .into_iter()
+            .map(|x| x.try_into())
+            .collect::<Result<_>>()?;
+        Ok((statuses, output.cursor))
     }
 
     #[tracing::instrument(name = "at_proto_client::Client::post", skip_all)]
@@ -131,7 +269,15 @@ impl super::Client for Client {
             }
         };
         let reply = to_reply(&self.api, &self.http_client, session, reply_identifier).await?;
-        let embed = to_embed(&self.api, &self.http_client, session, images, external).await?;
+        let embed = to_embed(
+            &self.api,
+            &self.http_client,
+            &self.media_fetcher,
+            session,
+            &images,
+            external.as_ref(),
+        )
+        .await?;
         let record = to_record(content, facets, reply, embed, created_at);
 
         let output = self
@@ -142,6 +288,61 @@ impl super::Client for Client {
         Ok(serde_json::to_string(&output)?)
     }
 
+    /**
+     * 作成や削除は、送信するときと同じ collection と rkey、record にする。
+     * プロフィールの record は今の内容を取得せず、書き換える項目だけを示す
+     */
+    fn render(&self, request: &Request<'_>) -> Result<Value> {
+        match *request {
+            Request::Post {
+                content,
+                facets,
+                reply_identifier,
+                images,
+                external,
+                created_at,
+            } => {
+                // NOTE: root の取得には通信が要るので、parent で代用する
+                let reply = reply_identifier
+                    .map(|reply_identifier| {
+                        let parent: com::atproto::repo::strong_ref::Main =
+                            serde_json::from_str(reply_identifier)?;
+                        anyhow::Ok(app::bsky::feed::post::ReplyRef {
+                            root: parent.clone(),
+                            parent,
+                        })
+                    })
+                    .transpose()?;
+                let blobs = to_embed_media_urls(images, external)
+                    .into_iter()
+                    .map(|url| json!(url))
+                    .collect();
+                let embed = build_embed(images, external, blobs);
+                Ok(json!({
+                    "collection": "app.bsky.feed.post",
+                    "record": to_record(content, facets, reply, embed, created_at),
+                }))
+            }
+            Request::Repost { .. } | Request::Like { .. } => {
+                let (collection, record) = to_create_record(request)?;
+                Ok(json!({ "collection": collection, "record": record }))
+            }
+            Request::DeletePost { .. } | Request::DeleteRepost { .. } | Request::Unlike { .. } => {
+                let (collection, rkey) = to_delete_target(request)?;
+                Ok(json!({ "collection": collection, "rkey": rkey }))
+            }
+            Request::UpdateProfile { .. } | Request::Pin { .. } | Request::Unpin { .. } => {
+                let mut record = empty_profile_record();
+                apply_to_profile_record(&mut record, request)?;
+                Ok(json!({
+                    "collection": "app.bsky.actor.profile",
+                    "rkey": "self",
+                    "record": record,
+                }))
+            }
+        }
+    }
+
     #[tracing::instrument(name = "at_proto_client::Client::repost", skip_all)]
     async fn repost(
         &mut self,
@@ -156,17 +357,10 @@ impl super::Client for Client {
             }
         };
 
-        let identifier: com::atproto::repo::create_record::Output =
-            serde_json::from_str(target_identifier)?;
-        let record = atrium_api::records::Record::Known(KnownRecord::AppBskyFeedRepost(Box::new(
-            app::bsky::feed::repost::Record {
-                created_at: Datetime::new(created_at.to_owned()),
-                subject: com::atproto::repo::strong_ref::Main {
-                    cid: identifier.cid,
-                    uri: identifier.uri,
-                },
-            },
-        )));
+        let (collection, record) = to_create_record(&Request::Repost {
+            target_identifier,
+            created_at,
+        })?;
         let res = self
             .agent
             .api
@@ -174,7 +368,7 @@ impl super::Client for Client {
             .atproto
             .repo
             .create_record(com::atproto::repo::create_record::Input {
-                collection: Nsid::from_str("app.bsky.feed.repost").unwrap(),
+                collection: Nsid::from_str(collection).unwrap(),
                 record,
                 repo: session.did.clone().into(),
                 rkey: None,
@@ -188,13 +382,7 @@ impl super::Client for Client {
 
     #[tracing::instrument(name = "at_proto_client::Client::delete_post", skip_all)]
     async fn delete_post(&mut self, identifier: &str) -> Result<()> {
-        let json: Value = serde_json::from_str(identifier)?;
-        let uri = json
-            .get("uri")
-            .ok_or_else(|| anyhow!("uri not found ({})", identifier))?
-            .as_str()
-            .ok_or_else(|| anyhow!("uri is not string"))?;
-        let rkey = uri_to_post_rkey(uri)?;
+        let (_, rkey) = to_delete_target(&Request::DeletePost { identifier })?;
 
         let session = match &self.session {
             Some(some) => some,
@@ -213,8 +401,95 @@ impl super::Client for Client {
 
     #[tracing::instrument(name = "at_proto_client::Client::delete_repost", skip_all)]
     async fn delete_repost(&mut self, identifier: &str) -> Result<()> {
-        let output: com::atproto::repo::put_record::Output = serde_json::from_str(identifier)?;
-        let rkey = uri_to_repost_rkey(&output.uri)?;
+        let (collection, rkey) = to_delete_target(&Request::DeleteRepost { identifier })?;
+
+        let session = match &self.session {
+            Some(some) => some,
+            None => {
+                self.init_session().await?;
+                self.session.as_ref().unwrap()
+            }
+        };
+
+        let input = com::atproto::repo::delete_record::Input {
+            collection: Nsid::from_str(collection).unwrap(),
+            repo: session.did.clone().into(),
+            rkey,
+            swap_commit: None,
+            swap_record: None,
+        };
+        self.agent
+            .api
+            .com
+            .atproto
+            .repo
+            .delete_record(input)
+            .await
+            .map_err(|err| anyhow::anyhow!("{:?}", err))?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "at_proto_client::Client::fetch_likes", skip_all)]
+    async fn fetch_likes(&mut self) -> Result<Vec<store::operations::CreateLikeOperationStatus>> {
+        let session = match &self.session {
+            Some(some) => some,
+            None => {
+                self.init_session().await?;
+                self.session.as_ref().unwrap()
+            }
+        };
+
+        let params = app::bsky::feed::get_actor_likes::Parameters {
+            actor: session.did.clone().into(),
+            cursor: None,
+            limit: Some(LimitedNonZeroU8::try_from(50).unwrap()),
+        };
+        let output = self
+            .agent
+            .api
+            .app
+            .bsky
+            .feed
+            .get_actor_likes(params)
+            .await
+            .map_err(|err| anyhow::anyhow!("{:?}", err))?;
+        Ok(output.feed.into_iter().map(|x| x.post.into()).collect())
+    }
+
+    #[tracing::instrument(name = "at_proto_client::Client::like", skip_all)]
+    async fn like(&mut self, target_identifier: &str) -> Result<String> {
+        let session = match &self.session {
+            Some(some) => some,
+            None => {
+                self.init_session().await?;
+                self.session.as_ref().unwrap()
+            }
+        };
+
+        let (collection, record) = to_create_record(&Request::Like { target_identifier })?;
+        let res = self
+            .agent
+            .api
+            .com
+            .atproto
+            .repo
+            .create_record(com::atproto::repo::create_record::Input {
+                collection: Nsid::from_str(collection).unwrap(),
+                record,
+                repo: session.did.clone().into(),
+                rkey: None,
+                swap_commit: None,
+                validate: None,
+            })
+            .await
+            .map_err(|err| anyhow::anyhow!("{:?}", err))?;
+        Ok(serde_json::to_string(&res)?)
+    }
+
+    #[tracing::instrument(name = "at_proto_client::Client::unlike", skip_all)]
+    async fn unlike(&mut self, identifier: &str) -> Result<()> {
+        let (collection, rkey) = to_delete_target(&Request::Unlike { identifier })?;
 
         let session = match &self.session {
             Some(some) => some,
@@ -225,7 +500,7 @@ impl super::Client for Client {
         };
 
         let input = com::atproto::repo::delete_record::Input {
-            collection: Nsid::from_str("app.bsky.feed.repost").unwrap(),
+            collection: Nsid::from_str(collection).unwrap(),
             repo: session.did.clone().into(),
             rkey,
             swap_commit: None,
@@ -242,4 +517,238 @@ impl super::Client for Client {
 
         Ok(())
     }
+
+    #[tracing::instrument(name = "at_proto_client::Client::fetch_profile", skip_all)]
+    async fn fetch_profile(&mut self) -> Result<Option<store::operations::Profile>> {
+        let session = match &self.session {
+            Some(some) => some,
+            None => {
+                self.init_session().await?;
+                self.session.as_ref().unwrap()
+            }
+        };
+
+        let output = self
+            .agent
+            .api
+            .app
+            .bsky
+            .actor
+            .get_profile(app::bsky::actor::get_profile::Parameters {
+                actor: session.did.clone().into(),
+            })
+            .await
+            .map_err(|err| anyhow::anyhow!("{:?}", err))?;
+        Ok(Some(store::operations::Profile {
+            display_name: output.display_name,
+            description: output.description,
+            avatar_url: output.avatar,
+            banner_url: output.banner,
+        }))
+    }
+
+    #[tracing::instrument(name = "at_proto_client::Client::update_profile", skip_all)]
+    async fn update_profile(&mut self, profile: &store::operations::Profile) -> Result<()> {
+        let session = match &self.session {
+            Some(some) => some,
+            None => {
+                self.init_session().await?;
+                self.session.as_ref().unwrap()
+            }
+        };
+
+        // 同期しない項目を消さないように、今のレコードを書き換える
+        let mut record = self
+            .api
+            .repo
+            .get_profile_record(&self.http_client, session)
+            .await?
+            .unwrap_or_else(empty_profile_record);
+        apply_to_profile_record(&mut record, &Request::UpdateProfile { profile })?;
+        if let Some(avatar_url) = &profile.avatar_url {
+            record["avatar"] = upload_image(
+                &self.api,
+                &self.http_client,
+                &self.media_fetcher,
+                session,
+                avatar_url,
+            )
+            .await?;
+        }
+        if let Some(banner_url) = &profile.banner_url {
+            record["banner"] = upload_image(
+                &self.api,
+                &self.http_client,
+                &self.media_fetcher,
+                session,
+                banner_url,
+            )
+            .await?;
+        }
+        let _: Value = self
+            .api
+            .repo
+            .put_profile_record(&self.http_client, session, &record)
+            .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "at_proto_client::Client::fetch_pinned_identifiers", skip_all)]
+    async fn fetch_pinned_identifiers(&mut self) -> Result<Option<Vec<String>>> {
+        let session = match &self.session {
+            Some(some) => some,
+            None => {
+                self.init_session().await?;
+                self.session.as_ref().unwrap()
+            }
+        };
+
+        let record = self
+            .api
+            .repo
+            .get_profile_record(&self.http_client, session)
+            .await?;
+        // post の identifier は cid
+        Ok(Some(
+            record
+                .as_ref()
+                .and_then(|record| record.get("pinnedPost"))
+                .and_then(|pinned_post| pinned_post.get("cid"))
+                .and_then(Value::as_str)
+                .map(str::to_owned)
+                .into_iter()
+                .collect(),
+        ))
+    }
+
+    #[tracing::instrument(name = "at_proto_client::Client::pin", skip_all)]
+    async fn pin(&mut self, identifier: &str) -> Result<()> {
+        let session = match &self.session {
+            Some(some) => some,
+            None => {
+                self.init_session().await?;
+                self.session.as_ref().unwrap()
+            }
+        };
+
+        let mut record = self
+            .api
+            .repo
+            .get_profile_record(&self.http_client, session)
+            .await?
+            .unwrap_or_else(empty_profile_record);
+        apply_to_profile_record(&mut record, &Request::Pin { identifier })?;
+        let _: Value = self
+            .api
+            .repo
+            .put_profile_record(&self.http_client, session, &record)
+            .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "at_proto_client::Client::unpin", skip_all)]
+    async fn unpin(&mut self, identifier: &str) -> Result<()> {
+        let session = match &self.session {
+            Some(some) => some,
+            None => {
+                self.init_session().await?;
+                self.session.as_ref().unwrap()
+            }
+        };
+
+        let Some(mut record) = self
+            .api
+            .repo
+            .get_profile_record(&self.http_client, session)
+            .await?
+        else {
+            return Ok(());
+        };
+        if !apply_to_profile_record(&mut record, &Request::Unpin { identifier })? {
+            return Ok(());
+        }
+        let _: Value = self
+            .api
+            .repo
+            .put_profile_record(&self.http_client, session, &record)
+            .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "at_proto_client::Client::exists_post", skip_all)]
+    async fn exists_post(&mut self, identifier: &str) -> Result<Option<bool>> {
+        let output: com::atproto::repo::create_record::Output = serde_json::from_str(identifier)?;
+        let rkey = uri_to_post_rkey(&output.uri)?;
+        let session = match &self.session {
+            Some(some) => some,
+            None => {
+                self.init_session().await?;
+                self.session.as_ref().unwrap()
+            }
+        };
+
+        let exists = self
+            .api
+            .repo
+            .exists_record(&self.http_client, session, "app.bsky.feed.post", &rkey)
+            .await?;
+        Ok(Some(exists))
+    }
+
+    /** identifier は cid なので、uri で問い合わせる */
+    #[tracing::instrument(
+        name = "at_proto_client::Client::fetch_deleted_post_identifiers",
+        skip_all
+    )]
+    async fn fetch_deleted_post_identifiers(
+        &mut self,
+        posts: &[(String, String)],
+    ) -> Result<Option<Vec<String>>> {
+        if self.session.is_none() {
+            self.init_session().await?;
+        }
+        // NOTE: getPosts は一度に25件まで
+        let mut deleted = Vec::new();
+        for chunk in posts.chunks(25) {
+            let params = app::bsky::feed::get_posts::Parameters {
+                uris: chunk.iter().map(|(_, uri)| uri.clone()).collect(),
+            };
+            let output = self
+                .agent
+                .api
+                .app
+                .bsky
+                .feed
+                .get_posts(params)
+                .await
+                .map_err(|err| anyhow::anyhow!("{:?}", err))?;
+            deleted.extend(
+                chunk
+                    .iter()
+                    .filter(|(_, uri)| !output.posts.iter().any(|post| &post.uri == uri))
+                    .map(|(identifier, _)| identifier.clone()),
+            );
+        }
+        Ok(Some(deleted))
+    }
+
+    #[tracing::instrument(name = "at_proto_client::Client::exists_repost", skip_all)]
+    async fn exists_repost(&mut self, identifier: &str) -> Result<Option<bool>> {
+        let output: com::atproto::repo::create_record::Output = serde_json::from_str(identifier)?;
+        let rkey = uri_to_repost_rkey(&output.uri)?;
+        let session = match &self.session {
+            Some(some) => some,
+            None => {
+                self.init_session().await?;
+                self.session.as_ref().unwrap()
+            }
+        };
+
+        let exists = self
+            .api
+            .repo
+            .exists_record(&self.http_client, session, "app.bsky.feed.repost", &rkey)
+            .await?;
+        Ok(Some(exists))
+    }
 }
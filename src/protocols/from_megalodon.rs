@@ -4,7 +4,7 @@ use crate::{sources::source, store};
 
 fn link(current_idx: usize, uri: &str) -> store::operations::Facet {
     store::operations::Facet::Link {
-        byte_slice: (current_idx as u32)..(current_idx as u32) + (uri.as_bytes().len() as u32),
+        byte_slice: (current_idx as u32)..(current_idx as u32) + (uri.len() as u32),
         uri: uri.to_owned(),
     }
 }
@@ -23,7 +23,7 @@ fn html_to_content_facets(html: &str) -> (String, Vec<store::operations::Facet>)
             if let RichAnnotation::Link(_) = &string.tag[0] {
                 // NOTE: ハッシュタグは未対応
                 if !string.s.starts_with('#') {
-                    facets.push(link(text.as_bytes().len(), &string.s));
+                    facets.push(link(text.len(), &string.s));
                 }
                 text += &string.s;
                 continue;
@@ -42,6 +42,7 @@ impl From<megalodon::entities::Status> for source::LiveStatus {
                 src_identifier: value.id,
                 target_src_identifier: reblog.id,
                 target_src_uri: reblog.uri,
+                target_content: Some(html_to_content_facets(&reblog.content).0),
                 created_at: value.created_at.into(),
             })
         } else {
@@ -52,6 +53,8 @@ impl From<megalodon::entities::Status> for source::LiveStatus {
                 content,
                 facets,
                 reply_src_identifier: value.in_reply_to_id,
+                reply_src_uri: None, // 返信先の取得が必要なので後で埋める
+                visibility: Some(value.visibility.to_string()),
                 media: value
                     .media_attachments
                     .into_iter()
@@ -0,0 +1,222 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, sync::OnceCell};
+use tracing::{debug, warn};
+
+use crate::utils::sha256_hex;
+
+/** 取得したメディア */
+#[derive(Clone)]
+pub struct Media {
+    pub content_type: String,
+    pub bytes: Bytes,
+    /** 内容の sha256 */
+    pub hash: String,
+}
+
+/** メディアの取得かアップロードに失敗した */
+#[derive(Debug)]
+pub struct MediaError {
+    pub url: String,
+    source: anyhow::Error,
+}
+
+impl MediaError {
+    /** url のメディアの転送の失敗にする。既に MediaError ならそのまま返す */
+    pub fn wrap(url: &str, err: anyhow::Error) -> anyhow::Error {
+        if err.is::<MediaError>() {
+            return err;
+        }
+        MediaError {
+            url: url.to_owned(),
+            source: err,
+        }
+        .into()
+    }
+}
+
+impl fmt::Display for MediaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "media transfer failed (url={}): {}",
+            self.url, self.source
+        )
+    }
+}
+
+impl std::error::Error for MediaError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+/** ディスクに保存する、URL ごとの取得結果 */
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CacheEntry {
+    content_type: String,
+    hash: String,
+}
+
+/** content-type が返されなかった場合に、先頭のバイト列から推測する */
+fn sniff_content_type(bytes: &[u8]) -> &'static str {
+    match bytes {
+        [0xff, 0xd8, 0xff, ..] => "image/jpeg",
+        [0x89, b'P', b'N', b'G', ..] => "image/png",
+        [b'G', b'I', b'F', b'8', ..] => "image/gif",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+/**
+ * メディアを実行ごとに一度だけ取得し、全ての宛先で使い回す。
+ * URL ごとの取得結果と、内容の hash ごとの中身を保持する。
+ * cache_dir があれば実行をまたいでディスクにも保持する
+ */
+pub struct MediaFetcher {
+    http_client: Arc<reqwest::Client>,
+    cache_dir: Option<PathBuf>,
+    by_url: Mutex<HashMap<String, Arc<OnceCell<Media>>>>,
+    by_hash: Mutex<HashMap<String, Bytes>>,
+}
+
+impl MediaFetcher {
+    pub fn new(http_client: Arc<reqwest::Client>, cache_dir: Option<PathBuf>) -> Self {
+        Self {
+            http_client,
+            cache_dir,
+            by_url: Mutex::default(),
+            by_hash: Mutex::default(),
+        }
+    }
+
+    pub async fn fetch(&self, url: &str) -> Result<Media> {
+        let cell = self
+            .by_url
+            .lock()
+            .unwrap()
+            .entry(url.to_owned())
+            .or_default()
+            .clone();
+        cell.get_or_try_init(|| self.load(url))
+            .await
+            .cloned()
+            .map_err(|err| MediaError::wrap(url, err))
+    }
+
+    async fn load(&self, url: &str) -> Result<Media> {
+        if let Some(media) = self.load_from_disk(url).await {
+            debug!("media cache hit (url={})", url);
+            return Ok(media);
+        }
+        let resp = self.http_client.get(url).send().await?.error_for_status()?;
+        let content_type = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+        let bytes = resp.bytes().await?;
+        let content_type = content_type.unwrap_or_else(|| sniff_content_type(&bytes).to_owned());
+        let hash = sha256_hex(&bytes);
+        debug!(
+            "media fetched (url={}, content-type={}, size={})",
+            url,
+            content_type,
+            bytes.len()
+        );
+        let media = Media {
+            content_type,
+            bytes: self.dedup(&hash, bytes),
+            hash,
+        };
+        if let Err(err) = self.save_to_disk(url, &media).await {
+            warn!("media cache write failed (url={}): {:?}", url, err);
+        }
+        Ok(media)
+    }
+
+    /** 同じ内容のメディアは一つの Bytes を共有する */
+    fn dedup(&self, hash: &str, bytes: Bytes) -> Bytes {
+        self.by_hash
+            .lock()
+            .unwrap()
+            .entry(hash.to_owned())
+            .or_insert(bytes)
+            .clone()
+    }
+
+    fn entry_path(&self, url: &str) -> Option<PathBuf> {
+        let cache_dir = self.cache_dir.as_ref()?;
+        Some(cache_dir.join(format!("{}.json", sha256_hex(url.as_bytes()))))
+    }
+
+    async fn load_from_disk(&self, url: &str) -> Option<Media> {
+        let entry_path = self.entry_path(url)?;
+        let entry: CacheEntry =
+            serde_json::from_str(&fs::read_to_string(entry_path).await.ok()?).ok()?;
+        let bytes = fs::read(self.cache_dir.as_ref()?.join(&entry.hash))
+            .await
+            .ok()?;
+        Some(Media {
+            content_type: entry.content_type,
+            bytes: self.dedup(&entry.hash, bytes.into()),
+            hash: entry.hash,
+        })
+    }
+
+    async fn save_to_disk(&self, url: &str, media: &Media) -> Result<()> {
+        let (Some(cache_dir), Some(entry_path)) = (&self.cache_dir, self.entry_path(url)) else {
+            return Ok(());
+        };
+        fs::create_dir_all(cache_dir).await?;
+        fs::write(cache_dir.join(&media.hash), &media.bytes).await?;
+        let entry = CacheEntry {
+            content_type: media.content_type.clone(),
+            hash: media.hash.clone(),
+        };
+        fs::write(entry_path, serde_json::to_string(&entry)?).await?;
+        Ok(())
+    }
+
+    /** ディスクのキャッシュのうち、max_age より前に書き込んだものを消す */
+    pub async fn prune(&self, max_age: Duration) {
+        let Some(cache_dir) = &self.cache_dir else {
+            return;
+        };
+        match prune_dir(cache_dir, max_age).await {
+            Ok(removed) => debug!("media cache pruned: {} files", removed),
+            Err(err) => warn!("media cache prune failed: {:?}", err),
+        }
+    }
+}
+
+async fn prune_dir(cache_dir: &Path, max_age: Duration) -> Result<usize> {
+    let mut entries = match fs::read_dir(cache_dir).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+    let now = SystemTime::now();
+    let mut removed = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let modified = entry.metadata().await?.modified()?;
+        if now.duration_since(modified).is_ok_and(|age| age > max_age) {
+            fs::remove_file(entry.path()).await?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
@@ -1,17 +1,28 @@
+use std::sync::Arc;
+
 use anyhow::Result;
 use async_trait::async_trait;
 use chrono::{DateTime, FixedOffset};
 use futures::future::join_all;
 use http::header::ACCEPT;
 use megalodon::{
-    megalodon::{GetAccountStatusesInputOptions, PostStatusInputOptions, PostStatusOutput},
+    megalodon::{
+        GetAccountStatusesInputOptions, GetFavouritesInputOptions, PostStatusInputOptions,
+        PostStatusOutput, SearchInputOptions, SearchType,
+    },
     Megalodon,
 };
-use reqwest::{header::HeaderMap, multipart::Part, Body};
+use reqwest::{header::HeaderMap, multipart::Part, StatusCode};
+use serde_json::{json, Value};
 use tracing::{debug, event_enabled, trace, Level};
 
 use crate::{sources::source, store};
 
+use super::{
+    media::{MediaError, MediaFetcher},
+    Request,
+};
+
 fn trace_header(header: &HeaderMap) {
     if !event_enabled!(Level::TRACE) {
         return;
@@ -32,17 +43,23 @@ fn trace_header(header: &HeaderMap) {
         });
 }
 
+async fn fetch_part(media_fetcher: &MediaFetcher, src_url: &str) -> Result<Part> {
+    let media = media_fetcher.fetch(src_url).await?;
+    Ok(Part::stream(media.bytes)
+        .file_name("_")
+        .mime_str(&media.content_type)?)
+}
+
 async fn upload_media(
+    http_client: &reqwest::Client,
+    media_fetcher: &MediaFetcher,
     origin: &str,
     access_token: &str,
     src_url: &str,
 ) -> Result<megalodon::response::Response<megalodon::entities::Attachment>> {
-    let resp = reqwest::get(src_url).await?;
-
-    let body = Body::from(resp);
-    let part = Part::stream(body).file_name("_");
+    let part = fetch_part(media_fetcher, src_url).await?;
     let form = reqwest::multipart::Form::new().part("file", part);
-    let resp = reqwest::Client::new()
+    let resp = http_client
         .post(format!("{}{}", origin, "/api/v2/media"))
         .bearer_auth(access_token)
         .multipart(form)
@@ -64,13 +81,17 @@ async fn upload_media(
 }
 
 async fn upload_media_list(
+    http_client: &reqwest::Client,
+    media_fetcher: &MediaFetcher,
     origin: &str,
     access_token: &str,
     images: &[store::operations::Medium],
 ) -> Result<Vec<String>> {
-    let upload_media_futures = images
-        .iter()
-        .map(|image| upload_media(origin, access_token, &image.url));
+    let upload_media_futures = images.iter().map(|image| async {
+        upload_media(http_client, media_fetcher, origin, access_token, &image.url)
+            .await
+            .map_err(|err| MediaError::wrap(&image.url, err))
+    });
     Ok(join_all(upload_media_futures)
         .await
         .into_iter()
@@ -80,6 +101,11 @@ async fn upload_media_list(
         .collect())
 }
 
+/** 画像が未設定の場合のデフォルト画像 */
+fn is_missing_image(url: &str) -> bool {
+    url.is_empty() || url.ends_with("/missing.png")
+}
+
 fn to_megalodon_post_status_input_options(
     media_ids: Vec<String>,
     reply_identifier: Option<&str>,
@@ -101,7 +127,65 @@ fn to_megalodon_post_status_input_options(
     }
 }
 
+/** megalodon の post_status が送る params と同じもの */
+fn post_status_params(status: &str, options: &PostStatusInputOptions) -> Result<Value> {
+    let mut params = json!({ "status": status });
+    if let Some(media_ids) = &options.media_ids {
+        params["media_ids"] = media_ids.clone().into();
+    }
+    if let Some(in_reply_to_id) = &options.in_reply_to_id {
+        params["in_reply_to_id"] = in_reply_to_id.as_str().into();
+    }
+    if let Some(sensitive) = options.sensitive {
+        params["sensitive"] = sensitive.to_string().into();
+    }
+    if let Some(spoiler_text) = &options.spoiler_text {
+        params["spoiler_text"] = spoiler_text.as_str().into();
+    }
+    if let Some(visibility) = &options.visibility {
+        params["visibility"] = visibility.to_string().into();
+    }
+    if let Some(scheduled_at) = options.scheduled_at {
+        params["scheduled_at"] = scheduled_at.to_rfc3339().into();
+    }
+    if let Some(language) = &options.language {
+        params["language"] = language.as_str().into();
+    }
+    if let Some(quote_id) = &options.quote_id {
+        params["quote_id"] = quote_id.as_str().into();
+    }
+    if let Some(poll) = &options.poll {
+        params["poll"] = serde_json::to_value(poll)?;
+    }
+    Ok(params)
+}
+
+/** form の項目名と値 */
+type Fields<'a> = Vec<(&'static str, &'a str)>;
+
+/** update_credentials の form の、文字列の項目と画像の項目 (画像は URL) */
+fn update_credentials_fields(profile: &store::operations::Profile) -> (Fields<'_>, Fields<'_>) {
+    fn present<'a>(fields: [(&'static str, &'a Option<String>); 2]) -> Fields<'a> {
+        fields
+            .into_iter()
+            .filter_map(|(name, value)| value.as_deref().map(|value| (name, value)))
+            .collect()
+    }
+    (
+        present([
+            ("display_name", &profile.display_name),
+            ("note", &profile.description),
+        ]),
+        present([
+            ("avatar", &profile.avatar_url),
+            ("header", &profile.banner_url),
+        ]),
+    )
+}
+
 pub struct Client {
+    http_client: Arc<reqwest::Client>,
+    media_fetcher: Arc<MediaFetcher>,
     origin: String,
     access_token: String,
     megalodon: Box<dyn Megalodon + Send + Sync>,
@@ -110,7 +194,12 @@ pub struct Client {
 
 impl Client {
     #[tracing::instrument(name = "megalodon_client::Client::new", skip_all)]
-    pub async fn new_mastodon(origin: String, access_token: String) -> Result<Self> {
+    pub async fn new_mastodon(
+        http_client: Arc<reqwest::Client>,
+        media_fetcher: Arc<MediaFetcher>,
+        origin: String,
+        access_token: String,
+    ) -> Result<Self> {
         let megalodon = megalodon::generator(
             megalodon::SNS::Mastodon,
             origin.clone(),
@@ -122,6 +211,8 @@ impl Client {
         let account_id = resp.json().id;
 
         Ok(Self {
+            http_client,
+            media_fetcher,
             origin,
             access_token,
             megalodon,
@@ -142,25 +233,39 @@ impl super::Client for Client {
 
     #[tracing::instrument(name = "megalodon_client::Client::fetch_statuses", skip_all)]
     async fn fetch_statuses(&mut self) -> Result<Vec<source::LiveStatus>> {
+        Ok(self.fetch_statuses_page(None).await?.0)
+    }
+
+    #[tracing::instrument(name = "megalodon_client::Client::fetch_status_uri", skip_all)]
+    async fn fetch_status_uri(&mut self, identifier: &str) -> Result<Option<String>> {
+        let resp = self.megalodon.get_status(identifier.to_owned()).await?;
+        trace_header(&resp.header);
+        Ok(Some(resp.json().uri))
+    }
+
+    #[tracing::instrument(name = "megalodon_client::Client::fetch_statuses_page", skip_all)]
+    async fn fetch_statuses_page(
+        &mut self,
+        cursor: Option<&str>,
+    ) -> Result<(Vec<source::LiveStatus>, Option<String>)> {
         let resp = self
             .megalodon
             .get_account_statuses(
                 self.account_id.clone(),
                 Some(&GetAccountStatusesInputOptions {
                     limit: Some(40),
+                    max_id: cursor.map(str::to_owned),
                     // exclude_replies: Some(true), // TODO: include self replies
                     ..Default::default()
                 }),
             )
             .await?;
         trace_header(&resp.header);
-        let statuses: Vec<_> = resp
-            .json()
-            .into_iter()
-            .map(|status| status.into())
-            .collect();
+        let statuses = resp.json();
+        let next_cursor = statuses.last().map(|status| status.id.clone());
+        let statuses: Vec<_> = statuses.into_iter().map(|status| status.into()).collect();
 
-        Ok(statuses)
+        Ok((statuses, next_cursor))
     }
 
     #[tracing::instrument(name = "megalodon_client::Client::post", skip_all)]
@@ -173,7 +278,14 @@ impl super::Client for Client {
         _external: Option<store::operations::External>,
         _created_at: &DateTime<FixedOffset>,
     ) -> Result<String> {
-        let media_ids = upload_media_list(&self.origin, &self.access_token, &images).await?;
+        let media_ids = upload_media_list(
+            &self.http_client,
+            &self.media_fetcher,
+            &self.origin,
+            &self.access_token,
+            &images,
+        )
+        .await?;
         if let PostStatusOutput::Status(status) = self
             .megalodon
             .post_status(
@@ -192,6 +304,60 @@ impl super::Client for Client {
         }
     }
 
+    /** megalodon を通す要求は、megalodon が組み立てるものと同じ method、path、params にする */
+    fn render(&self, request: &Request<'_>) -> Result<Value> {
+        let status_path = |identifier: &str, action: &str| {
+            format!("{}/api/v1/statuses/{}{}", self.origin, identifier, action)
+        };
+        let (method, url, params) = match *request {
+            Request::Post {
+                content,
+                reply_identifier,
+                images,
+                ..
+            } => {
+                // アップロードしないので、media_ids には画像の URL を入れる
+                let media_ids = images.iter().map(|image| image.url.clone()).collect();
+                let options = to_megalodon_post_status_input_options(media_ids, reply_identifier);
+                (
+                    "POST",
+                    format!("{}/api/v1/statuses", self.origin),
+                    post_status_params(content, &options)?,
+                )
+            }
+            Request::Repost {
+                target_identifier, ..
+            } => ("POST", status_path(target_identifier, "/reblog"), json!({})),
+            Request::DeletePost { identifier } | Request::DeleteRepost { identifier } => {
+                ("DELETE", status_path(identifier, ""), json!({}))
+            }
+            Request::Like { target_identifier } => (
+                "POST",
+                status_path(target_identifier, "/favourite"),
+                json!({}),
+            ),
+            Request::Unlike { identifier } => {
+                ("POST", status_path(identifier, "/unfavourite"), json!({}))
+            }
+            Request::UpdateProfile { profile } => {
+                let (texts, images) = update_credentials_fields(profile);
+                let form: serde_json::Map<_, _> = texts
+                    .into_iter()
+                    .chain(images)
+                    .map(|(name, value)| (name.to_owned(), value.into()))
+                    .collect();
+                (
+                    "PATCH",
+                    format!("{}/api/v1/accounts/update_credentials", self.origin),
+                    form.into(),
+                )
+            }
+            Request::Pin { identifier } => ("POST", status_path(identifier, "/pin"), json!({})),
+            Request::Unpin { identifier } => ("POST", status_path(identifier, "/unpin"), json!({})),
+        };
+        Ok(json!({ "method": method, "url": url, "params": params }))
+    }
+
     #[tracing::instrument(name = "megalodon_client::Client::repost", skip_all)]
     async fn repost(
         &mut self,
@@ -244,4 +410,163 @@ impl super::Client for Client {
             Err(err) => Err(err.into()),
         }
     }
+
+    #[tracing::instrument(name = "megalodon_client::Client::fetch_likes", skip_all)]
+    async fn fetch_likes(&mut self) -> Result<Vec<store::operations::CreateLikeOperationStatus>> {
+        let resp = self
+            .megalodon
+            .get_favourites(Some(&GetFavouritesInputOptions {
+                limit: Some(40),
+                ..Default::default()
+            }))
+            .await?;
+        trace_header(&resp.header);
+        Ok(resp
+            .json()
+            .into_iter()
+            .map(|status| store::operations::CreateLikeOperationStatus {
+                target_src_identifier: status.id,
+                target_src_uri: status.uri,
+            })
+            .collect())
+    }
+
+    #[tracing::instrument(name = "megalodon_client::Client::like", skip_all)]
+    async fn like(&mut self, target_identifier: &str) -> Result<String> {
+        let resp = self
+            .megalodon
+            .favourite_status(target_identifier.to_owned())
+            .await?;
+        trace_header(&resp.header);
+        // favourite は status ごとに一つなので、対象の identifier で取り消せる
+        Ok(resp.json().id)
+    }
+
+    #[tracing::instrument(name = "megalodon_client::Client::unlike", skip_all)]
+    async fn unlike(&mut self, identifier: &str) -> Result<()> {
+        let resp = self
+            .megalodon
+            .unfavourite_status(identifier.to_owned())
+            .await?;
+        trace_header(&resp.header);
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "megalodon_client::Client::fetch_profile", skip_all)]
+    async fn fetch_profile(&mut self) -> Result<Option<store::operations::Profile>> {
+        let resp = self.megalodon.verify_account_credentials().await?;
+        trace_header(&resp.header);
+        let account = resp.json();
+        Ok(Some(store::operations::Profile {
+            display_name: Some(account.display_name),
+            // note は HTML なので、元の文章があればそれを使う
+            description: Some(account.source.map_or(account.note, |source| source.note)),
+            avatar_url: Some(account.avatar).filter(|url| !is_missing_image(url)),
+            banner_url: Some(account.header).filter(|url| !is_missing_image(url)),
+        }))
+    }
+
+    #[tracing::instrument(name = "megalodon_client::Client::update_profile", skip_all)]
+    async fn update_profile(&mut self, profile: &store::operations::Profile) -> Result<()> {
+        let (texts, images) = update_credentials_fields(profile);
+        let mut form = reqwest::multipart::Form::new();
+        for (name, text) in texts {
+            form = form.text(name, text.to_owned());
+        }
+        for (name, url) in images {
+            form = form.part(name, fetch_part(&self.media_fetcher, url).await?);
+        }
+        let resp = self
+            .http_client
+            .patch(format!(
+                "{}{}",
+                self.origin, "/api/v1/accounts/update_credentials"
+            ))
+            .bearer_auth(&self.access_token)
+            .multipart(form)
+            .header(ACCEPT.as_str(), "application/json")
+            .send()
+            .await?;
+        trace_header(resp.headers());
+        resp.error_for_status()?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "megalodon_client::Client::exists_post", skip_all)]
+    async fn exists_post(&mut self, identifier: &str) -> Result<Option<bool>> {
+        let resp = self
+            .http_client
+            .get(format!("{}/api/v1/statuses/{}", self.origin, identifier))
+            .bearer_auth(&self.access_token)
+            .header(ACCEPT.as_str(), "application/json")
+            .send()
+            .await?;
+        trace_header(resp.headers());
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(Some(false));
+        }
+        resp.error_for_status()?;
+        Ok(Some(true))
+    }
+
+    /** reblog も status なので post と同じ方法で確認できる */
+    #[tracing::instrument(name = "megalodon_client::Client::exists_repost", skip_all)]
+    async fn exists_repost(&mut self, identifier: &str) -> Result<Option<bool>> {
+        self.exists_post(identifier).await
+    }
+
+    #[tracing::instrument(name = "megalodon_client::Client::fetch_pinned_identifiers", skip_all)]
+    async fn fetch_pinned_identifiers(&mut self) -> Result<Option<Vec<String>>> {
+        let resp = self
+            .megalodon
+            .get_account_statuses(
+                self.account_id.clone(),
+                Some(&GetAccountStatusesInputOptions {
+                    pinned: Some(true),
+                    ..Default::default()
+                }),
+            )
+            .await?;
+        trace_header(&resp.header);
+        Ok(Some(
+            resp.json().into_iter().map(|status| status.id).collect(),
+        ))
+    }
+
+    #[tracing::instrument(name = "megalodon_client::Client::pin", skip_all)]
+    async fn pin(&mut self, identifier: &str) -> Result<()> {
+        let resp = self.megalodon.pin_status(identifier.to_owned()).await?;
+        trace_header(&resp.header);
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "megalodon_client::Client::unpin", skip_all)]
+    async fn unpin(&mut self, identifier: &str) -> Result<()> {
+        let resp = self.megalodon.unpin_status(identifier.to_owned()).await?;
+        trace_header(&resp.header);
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "megalodon_client::Client::resolve", skip_all)]
+    async fn resolve(&mut self, uri: &str) -> Result<Option<String>> {
+        let resp = self
+            .megalodon
+            .search(
+                uri.to_owned(),
+                Some(&SearchInputOptions {
+                    r#type: Some(SearchType::Statuses),
+                    limit: Some(1),
+                    resolve: Some(true),
+                    ..Default::default()
+                }),
+            )
+            .await?;
+        trace_header(&resp.header);
+        Ok(resp
+            .json()
+            .statuses
+            .into_iter()
+            .next()
+            .map(|status| status.id))
+    }
 }
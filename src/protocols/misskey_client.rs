@@ -10,6 +10,11 @@ use tracing::trace;
 
 use crate::{sources::source, store};
 
+use super::{
+    media::{MediaError, MediaFetcher},
+    Request,
+};
+
 fn get_value<'a>(value: &'a Value, key: &str) -> Result<&'a Value> {
     value.get(key).ok_or_else(|| {
         anyhow!(
@@ -44,8 +49,67 @@ fn create_facets(content: &str) -> Vec<store::operations::Facet> {
         .collect()
 }
 
+/**
+ * 要求を送る API の名前と body。
+ * メディアはアップロードするまで id が無いので、代わりに URL を入れておく
+ */
+fn to_api_request(request: &Request<'_>) -> (&'static str, Value) {
+    match *request {
+        Request::Post {
+            content,
+            reply_identifier,
+            images,
+            ..
+        } => {
+            let mut json = json!({
+                "replyId": reply_identifier,
+                "text": content,
+            });
+            if !images.is_empty() {
+                json["mediaIds"] = images
+                    .iter()
+                    .map(|image| image.url.clone())
+                    .collect::<Vec<_>>()
+                    .into();
+            }
+            ("notes/create", json)
+        }
+        Request::Repost {
+            target_identifier, ..
+        } => ("notes/create", json!({ "renoteId": target_identifier })),
+        Request::DeletePost { identifier } => ("notes/delete", json!({ "noteId": identifier })),
+        Request::DeleteRepost { identifier } => ("notes/unrenote", json!({ "noteId": identifier })),
+        Request::Like { target_identifier } => (
+            "notes/reactions/create",
+            json!({ "noteId": target_identifier, "reaction": "❤" }),
+        ),
+        Request::Unlike { identifier } => {
+            ("notes/reactions/delete", json!({ "noteId": identifier }))
+        }
+        Request::UpdateProfile { profile } => {
+            let mut json = json!({});
+            if let Some(display_name) = &profile.display_name {
+                json["name"] = display_name.as_str().into();
+            }
+            if let Some(description) = &profile.description {
+                json["description"] = description.as_str().into();
+            }
+            if let Some(avatar_url) = &profile.avatar_url {
+                json["avatarId"] = avatar_url.as_str().into();
+            }
+            if let Some(banner_url) = &profile.banner_url {
+                json["bannerId"] = banner_url.as_str().into();
+            }
+            ("i/update", json)
+        }
+        Request::Pin { identifier } => ("i/pin", json!({ "noteId": identifier })),
+        Request::Unpin { identifier } => ("i/unpin", json!({ "noteId": identifier })),
+    }
+}
+
 pub struct Client {
     http_client: Arc<reqwest::Client>,
+    media_fetcher: Arc<MediaFetcher>,
     origin: String,
     access_token: String,
     user_id: String,
@@ -55,6 +119,7 @@ impl Client {
     #[tracing::instrument(name = "misskey_client::Client::new", skip_all)]
     pub async fn new(
         http_client: Arc<reqwest::Client>,
+        media_fetcher: Arc<MediaFetcher>,
         origin: String,
         access_token: String,
     ) -> Result<Self> {
@@ -67,11 +132,46 @@ impl Client {
         let user_id = get_as_string(&json, "id")?;
         Ok(Self {
             http_client,
+            media_fetcher,
             origin,
             access_token,
             user_id,
         })
     }
+
+    /** ドライブにファイルを追加し、その id を返す */
+    async fn upload_file(&self, src_url: &str) -> Result<String> {
+        let media = self.media_fetcher.fetch(src_url).await?;
+        let part = Part::stream(media.bytes)
+            .file_name("file.jpg")
+            .mime_str(&media.content_type)?;
+        let multipart = Form::new().part("file", part);
+        let url = format!("{}/api/drive/files/create", self.origin);
+        let resp = self
+            .http_client
+            .post(url)
+            .bearer_auth(self.access_token.to_owned())
+            .multipart(multipart)
+            .send()
+            .await?;
+        let json: Value = resp.json().await?;
+        let media_id = json
+            .get("id")
+            .ok_or_else(|| anyhow!("id is not found"))?
+            .as_str()
+            .ok_or_else(|| anyhow!("id is not str"))?;
+        Ok(media_id.to_owned())
+    }
+
+    async fn send(&self, endpoint: &str, json: &Value) -> Result<reqwest::Response> {
+        Ok(self
+            .http_client
+            .post(format!("{}/api/{}", self.origin, endpoint))
+            .bearer_auth(self.access_token.to_owned())
+            .json(json)
+            .send()
+            .await?)
+    }
 }
 
 #[async_trait]
@@ -86,18 +186,34 @@ impl super::Client for Client {
 
     #[tracing::instrument(name = "misskey_client::Client::fetch_statuses", skip_all)]
     async fn fetch_statuses(&mut self) -> Result<Vec<source::LiveStatus>> {
+        Ok(self.fetch_statuses_page(None).await?.0)
+    }
+
+    #[tracing::instrument(name = "misskey_client::Client::fetch_statuses_page", skip_all)]
+    async fn fetch_statuses_page(
+        &mut self,
+        cursor: Option<&str>,
+    ) -> Result<(Vec<source::LiveStatus>, Option<String>)> {
+        let mut body = json!({ "userId": self.user_id, "limit": 100 });
+        if let Some(cursor) = cursor {
+            body["untilId"] = cursor.into();
+        }
         let resp = self
             .http_client
             .post(format!("{}/api/users/notes", self.origin))
             .bearer_auth(self.access_token.to_owned())
-            .json(&json!({ "userId": self.user_id, "limit": 100 }))
+            .json(&body)
             .send()
             .await?;
         let json: Value = resp.json().await?;
         let root = json
             .as_array()
             .ok_or_else(|| anyhow!("root is not array"))?;
-        Ok(root
+        let next_cursor = root
+            .last()
+            .map(|item| get_as_string(item, "id"))
+            .transpose()?;
+        let statuses = root
             .iter()
             .map(|item| {
                 let created_at = DateTime::parse_from_rfc3339(&get_as_string(item, "createdAt")?)?;
@@ -115,6 +231,7 @@ impl super::Client for Client {
                             src_identifier: get_as_string(item, "id")?,
                             target_src_identifier,
                             target_src_uri,
+                            target_content: get_as_string_opt(renote, "text")?,
                             created_at,
                         },
                     ))
@@ -135,6 +252,21 @@ impl super::Client for Client {
                         content,
                         facets,
                         reply_src_identifier: get_as_string_opt(item, "replyId")?,
+                        reply_src_uri: item
+                            .get("reply")
+                            .filter(|reply| !reply.is_null())
+                            .map(|reply| -> Result<_> {
+                                let id = get_as_string(reply, "id")?;
+                                Ok(reply.get("uri").and_then(Value::as_str).map_or_else(
+                                    || format!("{}/notes/{}", self.origin, id),
+                                    str::to_owned,
+                                ))
+                            })
+                            .transpose()?,
+                        visibility: item
+                            .get("visibility")
+                            .and_then(Value::as_str)
+                            .map(str::to_owned),
                         media: get_as_array(item, "files")?
                             .iter()
                             .map(|file| {
@@ -149,54 +281,40 @@ impl super::Client for Client {
                     }))
                 }
             })
-            .collect::<Result<Vec<_>>>()?)
+            .collect::<Result<Vec<_>>>()?;
+        Ok((statuses, next_cursor))
     }
 
     #[tracing::instrument(name = "misskey_client::Client::post", skip_all)]
     async fn post(
         &mut self,
         content: &str,
-        _facets: &[store::operations::Facet],
+        facets: &[store::operations::Facet],
         reply_identifier: Option<&str>,
         images: Vec<store::operations::Medium>,
-        _external: Option<store::operations::External>,
-        _created_at: &DateTime<FixedOffset>,
+        external: Option<store::operations::External>,
+        created_at: &DateTime<FixedOffset>,
     ) -> Result<String> {
-        let mut json = json!({
-            "replyId": reply_identifier,
-            "text": content,
+        let (endpoint, mut json) = to_api_request(&Request::Post {
+            content,
+            facets,
+            reply_identifier,
+            images: &images,
+            external: external.as_ref(),
+            created_at,
         });
         if !images.is_empty() {
             let mut media_ids = Vec::new();
             for image in images {
-                let resp = self.http_client.get(image.url).send().await?;
-                trace!("{:?}", resp);
-                let multipart = Form::new().part("file", Part::stream(resp).file_name("file.jpg"));
-                let url = format!("{}/api/drive/files/create", self.origin);
-                let resp = self
-                    .http_client
-                    .post(url)
-                    .bearer_auth(self.access_token.to_owned())
-                    .multipart(multipart)
-                    .send()
-                    .await?;
-                let json: Value = resp.json().await?;
-                let media_id = json
-                    .get("id")
-                    .ok_or_else(|| anyhow!("id is not found"))?
-                    .as_str()
-                    .ok_or_else(|| anyhow!("id is not str"))?;
-                media_ids.push(media_id.to_owned());
+                let media_id = self
+                    .upload_file(&image.url)
+                    .await
+                    .map_err(|err| MediaError::wrap(&image.url, err))?;
+                media_ids.push(media_id);
             }
             json["mediaIds"] = media_ids.into();
         }
-        let resp = self
-            .http_client
-            .post(format!("{}/api/notes/create", self.origin))
-            .bearer_auth(self.access_token.to_owned())
-            .json(&json)
-            .send()
-            .await?;
+        let resp = self.send(endpoint, &json).await?;
         let json: Value = resp.json().await?;
         trace!("resp: {}", serde_json::to_string_pretty(&json)?);
         json.as_object()
@@ -212,19 +330,25 @@ impl super::Client for Client {
             .map(str::to_owned)
     }
 
+    fn render(&self, request: &Request<'_>) -> Result<Value> {
+        let (endpoint, json) = to_api_request(request);
+        Ok(json!({
+            "url": format!("{}/api/{}", self.origin, endpoint),
+            "body": json,
+        }))
+    }
+
     #[tracing::instrument(name = "misskey_client::Client::repost", skip_all)]
     async fn repost(
         &mut self,
         target_identifier: &str,
-        _created_at: &DateTime<FixedOffset>,
+        created_at: &DateTime<FixedOffset>,
     ) -> Result<String> {
-        let resp = self
-            .http_client
-            .post(format!("{}/api/notes/create", self.origin))
-            .bearer_auth(self.access_token.to_owned())
-            .json(&json!({ "renoteId": target_identifier }))
-            .send()
-            .await?;
+        let (endpoint, json) = to_api_request(&Request::Repost {
+            target_identifier,
+            created_at,
+        });
+        let resp = self.send(endpoint, &json).await?;
         let json: Value = resp.json().await?;
         trace!("resp: {}", serde_json::to_string_pretty(&json)?);
         json.as_object()
@@ -242,25 +366,170 @@ impl super::Client for Client {
 
     #[tracing::instrument(name = "misskey_client::Client::delete_post", skip_all)]
     async fn delete_post(&mut self, identifier: &str) -> Result<()> {
+        let (endpoint, json) = to_api_request(&Request::DeletePost { identifier });
+        let resp = self.send(endpoint, &json).await?;
+        resp.error_for_status().map(|_| ()).map_err(|e| e.into())
+    }
+
+    #[tracing::instrument(name = "misskey_client::Client::delete_repost", skip_all)]
+    async fn delete_repost(&mut self, identifier: &str) -> Result<()> {
+        let (endpoint, json) = to_api_request(&Request::DeleteRepost { identifier });
+        let resp = self.send(endpoint, &json).await?;
+        resp.error_for_status().map(|_| ()).map_err(|e| e.into())
+    }
+
+    #[tracing::instrument(name = "misskey_client::Client::fetch_likes", skip_all)]
+    async fn fetch_likes(&mut self) -> Result<Vec<store::operations::CreateLikeOperationStatus>> {
         let resp = self
             .http_client
-            .post(format!("{}/api/notes/delete", self.origin))
+            .post(format!("{}/api/users/reactions", self.origin))
             .bearer_auth(self.access_token.to_owned())
-            .json(&json!({ "noteId": identifier }))
+            .json(&json!({ "userId": self.user_id, "limit": 100 }))
             .send()
             .await?;
+        let json: Value = resp.error_for_status()?.json().await?;
+        json.as_array()
+            .ok_or_else(|| anyhow!("root is not array"))?
+            .iter()
+            .map(|item| {
+                let note = get_value(item, "note")?;
+                let target_src_identifier = get_as_string(note, "id")?;
+                let target_src_uri = note.get("uri").and_then(Value::as_str).map_or_else(
+                    || format!("{}/notes/{}", self.origin, target_src_identifier),
+                    str::to_owned,
+                );
+                Ok(store::operations::CreateLikeOperationStatus {
+                    target_src_identifier,
+                    target_src_uri,
+                })
+            })
+            .collect()
+    }
+
+    #[tracing::instrument(name = "misskey_client::Client::like", skip_all)]
+    async fn like(&mut self, target_identifier: &str) -> Result<String> {
+        let (endpoint, json) = to_api_request(&Request::Like { target_identifier });
+        let resp = self.send(endpoint, &json).await?;
+        resp.error_for_status()?;
+        // リアクションは note ごとに一つなので、対象の identifier で取り消せる
+        Ok(target_identifier.to_owned())
+    }
+
+    #[tracing::instrument(name = "misskey_client::Client::unlike", skip_all)]
+    async fn unlike(&mut self, identifier: &str) -> Result<()> {
+        let (endpoint, json) = to_api_request(&Request::Unlike { identifier });
+        let resp = self.send(endpoint, &json).await?;
         resp.error_for_status().map(|_| ()).map_err(|e| e.into())
     }
 
-    #[tracing::instrument(name = "misskey_client::Client::delete_repost", skip_all)]
-    async fn delete_repost(&mut self, identifier: &str) -> Result<()> {
+    #[tracing::instrument(name = "misskey_client::Client::fetch_profile", skip_all)]
+    async fn fetch_profile(&mut self) -> Result<Option<store::operations::Profile>> {
+        let resp = self
+            .http_client
+            .post(format!("{}/api/i", self.origin))
+            .bearer_auth(self.access_token.to_owned())
+            .json(&json!({}))
+            .send()
+            .await?;
+        let json: Value = resp.error_for_status()?.json().await?;
+        let get = |key| json.get(key).and_then(Value::as_str).map(str::to_owned);
+        Ok(Some(store::operations::Profile {
+            display_name: get("name"),
+            description: get("description"),
+            avatar_url: get("avatarUrl"),
+            banner_url: get("bannerUrl"),
+        }))
+    }
+
+    #[tracing::instrument(name = "misskey_client::Client::update_profile", skip_all)]
+    async fn update_profile(&mut self, profile: &store::operations::Profile) -> Result<()> {
+        let (endpoint, mut json) = to_api_request(&Request::UpdateProfile { profile });
+        if let Some(avatar_url) = &profile.avatar_url {
+            json["avatarId"] = self.upload_file(avatar_url).await?.into();
+        }
+        if let Some(banner_url) = &profile.banner_url {
+            json["bannerId"] = self.upload_file(banner_url).await?.into();
+        }
+        let resp = self.send(endpoint, &json).await?;
+        resp.error_for_status().map(|_| ()).map_err(|e| e.into())
+    }
+
+    /** repost の identifier は対象の note の id なので、repost は確認できない */
+    #[tracing::instrument(name = "misskey_client::Client::exists_post", skip_all)]
+    async fn exists_post(&mut self, identifier: &str) -> Result<Option<bool>> {
         let resp = self
             .http_client
-            .post(format!("{}/api/notes/unrenote", self.origin))
+            .post(format!("{}/api/notes/show", self.origin))
             .bearer_auth(self.access_token.to_owned())
             .json(&json!({ "noteId": identifier }))
             .send()
             .await?;
+        if resp.status().is_client_error() {
+            let json: Value = resp.json().await?;
+            let code = json
+                .get("error")
+                .and_then(|error| error.get("code"))
+                .and_then(Value::as_str);
+            if code == Some("NO_SUCH_NOTE") {
+                return Ok(Some(false));
+            }
+            return Err(anyhow!("notes/show failed: {}", json));
+        }
+        resp.error_for_status()?;
+        Ok(Some(true))
+    }
+
+    #[tracing::instrument(name = "misskey_client::Client::fetch_pinned_identifiers", skip_all)]
+    async fn fetch_pinned_identifiers(&mut self) -> Result<Option<Vec<String>>> {
+        let resp = self
+            .http_client
+            .post(format!("{}/api/i", self.origin))
+            .bearer_auth(self.access_token.to_owned())
+            .json(&json!({}))
+            .send()
+            .await?;
+        let json: Value = resp.error_for_status()?.json().await?;
+        Ok(Some(
+            get_as_array(&json, "pinnedNoteIds")?
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_owned)
+                .collect(),
+        ))
+    }
+
+    #[tracing::instrument(name = "misskey_client::Client::pin", skip_all)]
+    async fn pin(&mut self, identifier: &str) -> Result<()> {
+        let (endpoint, json) = to_api_request(&Request::Pin { identifier });
+        let resp = self.send(endpoint, &json).await?;
         resp.error_for_status().map(|_| ()).map_err(|e| e.into())
     }
+
+    #[tracing::instrument(name = "misskey_client::Client::unpin", skip_all)]
+    async fn unpin(&mut self, identifier: &str) -> Result<()> {
+        let (endpoint, json) = to_api_request(&Request::Unpin { identifier });
+        let resp = self.send(endpoint, &json).await?;
+        resp.error_for_status().map(|_| ()).map_err(|e| e.into())
+    }
+
+    #[tracing::instrument(name = "misskey_client::Client::resolve", skip_all)]
+    async fn resolve(&mut self, uri: &str) -> Result<Option<String>> {
+        let resp = self
+            .http_client
+            .post(format!("{}/api/ap/show", self.origin))
+            .bearer_auth(self.access_token.to_owned())
+            .json(&json!({ "uri": uri }))
+            .send()
+            .await?;
+        // 解決できない uri はエラーになる
+        if resp.status().is_client_error() {
+            trace!("resp: {:?}", resp);
+            return Ok(None);
+        }
+        let json: Value = resp.error_for_status()?.json().await?;
+        if get_as_string(&json, "type")? != "Note" {
+            return Ok(None);
+        }
+        Ok(Some(get_as_string(get_value(&json, "object")?, "id")?))
+    }
 }
@@ -0,0 +1,138 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset};
+use serde_json::{json, Value};
+
+use crate::{sources::source, store};
+
+use super::Request;
+
+/** テスト用の宛先。送信した要求を記録する */
+#[derive(Default)]
+pub struct Client {
+    pub origin: String,
+    pub identifier: String,
+    pub calls: Vec<String>,
+}
+
+impl Client {
+    pub fn new(origin: &str, identifier: &str) -> Self {
+        Self {
+            origin: origin.to_owned(),
+            identifier: identifier.to_owned(),
+            calls: Vec::new(),
+        }
+    }
+
+    fn call(&mut self, request: Request<'_>) -> String {
+        self.calls.push(describe(&request));
+        format!("dst-{}", self.calls.len())
+    }
+}
+
+/** 記録する要求の内容。render もこれを返す */
+fn describe(request: &Request<'_>) -> String {
+    match *request {
+        Request::Post { content, .. } => format!("post {}", content),
+        Request::Repost {
+            target_identifier, ..
+        } => format!("repost {}", target_identifier),
+        Request::DeletePost { identifier } => format!("delete_post {}", identifier),
+        Request::DeleteRepost { identifier } => format!("delete_repost {}", identifier),
+        Request::Like { target_identifier } => format!("like {}", target_identifier),
+        Request::Unlike { identifier } => format!("unlike {}", identifier),
+        Request::UpdateProfile { .. } => "update_profile".to_owned(),
+        Request::Pin { identifier } => format!("pin {}", identifier),
+        Request::Unpin { identifier } => format!("unpin {}", identifier),
+    }
+}
+
+#[async_trait]
+impl super::Client for Client {
+    fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    fn identifier(&self) -> &str {
+        &self.identifier
+    }
+
+    async fn fetch_statuses(&mut self) -> Result<Vec<source::LiveStatus>> {
+        Ok(Vec::new())
+    }
+
+    async fn fetch_statuses_page(
+        &mut self,
+        _cursor: Option<&str>,
+    ) -> Result<(Vec<source::LiveStatus>, Option<String>)> {
+        Ok((Vec::new(), None))
+    }
+
+    async fn post(
+        &mut self,
+        content: &str,
+        facets: &[store::operations::Facet],
+        reply_identifier: Option<&str>,
+        images: Vec<store::operations::Medium>,
+        external: Option<store::operations::External>,
+        created_at: &DateTime<FixedOffset>,
+    ) -> Result<String> {
+        Ok(self.call(Request::Post {
+            content,
+            facets,
+            reply_identifier,
+            images: &images,
+            external: external.as_ref(),
+            created_at,
+        }))
+    }
+
+    fn render(&self, request: &Request<'_>) -> Result<Value> {
+        Ok(json!(describe(request)))
+    }
+
+    async fn repost(
+        &mut self,
+        target_identifier: &str,
+        created_at: &DateTime<FixedOffset>,
+    ) -> Result<String> {
+        Ok(self.call(Request::Repost {
+            target_identifier,
+            created_at,
+        }))
+    }
+
+    async fn delete_post(&mut self, identifier: &str) -> Result<()> {
+        self.call(Request::DeletePost { identifier });
+        Ok(())
+    }
+
+    async fn delete_repost(&mut self, identifier: &str) -> Result<()> {
+        self.call(Request::DeleteRepost { identifier });
+        Ok(())
+    }
+
+    async fn like(&mut self, target_identifier: &str) -> Result<String> {
+        Ok(self.call(Request::Like { target_identifier }))
+    }
+
+    async fn unlike(&mut self, identifier: &str) -> Result<()> {
+        self.call(Request::Unlike { identifier });
+        Ok(())
+    }
+
+    async fn update_profile(&mut self, profile: &store::operations::Profile) -> Result<()> {
+        self.call(Request::UpdateProfile { profile });
+        Ok(())
+    }
+
+    async fn pin(&mut self, identifier: &str) -> Result<()> {
+        self.call(Request::Pin { identifier });
+        Ok(())
+    }
+
+    async fn unpin(&mut self, identifier: &str) -> Result<()> {
+        self.call(Request::Unpin { identifier });
+        Ok(())
+    }
+}
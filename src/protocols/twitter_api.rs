@@ -56,6 +56,49 @@ pub struct TweetBody<'a> {
     pub text: &'a str,
 }
 
+pub const CREATE_TWEET_URL: &str = "https://api.twitter.com/2/tweets";
+pub const UPDATE_PROFILE_URL: &str = "https://api.twitter.com/1.1/account/update_profile.json";
+
+pub fn tweet_url(id: &str) -> String {
+    format!("https://api.twitter.com/2/tweets/{}", id)
+}
+
+pub fn retweet_1_1_url(tweet_id: &str) -> String {
+    format!(
+        "https://api.twitter.com/1.1/statuses/retweet/{}.json",
+        tweet_id
+    )
+}
+
+pub fn unretweet_1_1_url(tweet_id: &str) -> String {
+    format!(
+        "https://api.twitter.com/1.1/statuses/unretweet/{}.json",
+        tweet_id
+    )
+}
+
+pub fn likes_url(user_id: &str) -> String {
+    format!("https://api.twitter.com/2/users/{}/likes", user_id)
+}
+
+pub fn like_url(user_id: &str, tweet_id: &str) -> String {
+    format!(
+        "https://api.twitter.com/2/users/{}/likes/{}",
+        user_id, tweet_id
+    )
+}
+
+/** update_profile の query。指定した項目だけを変える */
+pub fn update_profile_query<'a>(
+    name: Option<&'a str>,
+    description: Option<&'a str>,
+) -> Vec<(&'static str, &'a str)> {
+    [("description", description), ("name", name)]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, value?)))
+        .collect()
+}
+
 pub struct Api {
     http_client: Arc<reqwest::Client>,
     oauth1_request_builder: oauth1_request::Builder<'static, oauth1_request::HmacSha1>,
@@ -99,7 +142,7 @@ impl Api {
     }
 
     pub async fn create_tweet<T: DeserializeOwned>(&self, body: TweetBody<'_>) -> Result<T> {
-        let url = "https://api.twitter.com/2/tweets";
+        let url = CREATE_TWEET_URL;
         let resp = self
             .http_client
             .post(url)
@@ -112,7 +155,7 @@ impl Api {
     }
 
     pub async fn delete_tweet<T: DeserializeOwned>(&self, id: &str) -> Result<T> {
-        let url = format!("https://api.twitter.com/2/tweets/{}", id);
+        let url = tweet_url(id);
         let resp = self
             .http_client
             .delete(&url)
@@ -125,10 +168,7 @@ impl Api {
     }
 
     pub async fn create_retweet_1_1<T: DeserializeOwned>(&self, tweet_id: &str) -> Result<T> {
-        let url = format!(
-            "https://api.twitter.com/1.1/statuses/retweet/{}.json",
-            tweet_id
-        );
+        let url = retweet_1_1_url(tweet_id);
         let resp = self
             .http_client
             .post(&url)
@@ -150,10 +190,7 @@ impl Api {
     }
 
     pub async fn delete_retweet_1_1<T: DeserializeOwned>(&self, tweet_id: &str) -> Result<T> {
-        let url = format!(
-            "https://api.twitter.com/1.1/statuses/unretweet/{}.json",
-            tweet_id
-        );
+        let url = unretweet_1_1_url(tweet_id);
         let resp = self
             .http_client
             .post(&url)
@@ -169,6 +206,62 @@ impl Api {
         self.delete_tweet(tweet_id).await
     }
 
+    pub async fn create_like<T: DeserializeOwned>(
+        &self,
+        user_id: &str,
+        tweet_id: &str,
+    ) -> Result<T> {
+        let url = likes_url(user_id);
+        let resp = self
+            .http_client
+            .post(&url)
+            .header(AUTHORIZATION, self.oauth1_request_builder.post(url, &()))
+            .json(&serde_json::json!({ "tweet_id": tweet_id }))
+            .send()
+            .await?;
+        let resp = trace_header_and_throw_if_error_status(resp).await?;
+        Ok(resp.json().await?)
+    }
+
+    pub async fn delete_like<T: DeserializeOwned>(
+        &self,
+        user_id: &str,
+        tweet_id: &str,
+    ) -> Result<T> {
+        let url = like_url(user_id, tweet_id);
+        let resp = self
+            .http_client
+            .delete(&url)
+            .header(AUTHORIZATION, self.oauth1_request_builder.delete(url, &()))
+            .header(ACCEPT, "application/json")
+            .send()
+            .await?;
+        let resp = trace_header_and_throw_if_error_status(resp).await?;
+        Ok(resp.json().await?)
+    }
+
+    pub async fn update_profile<T: DeserializeOwned>(
+        &self,
+        name: Option<&str>,
+        description: Option<&str>,
+    ) -> Result<T> {
+        let url = UPDATE_PROFILE_URL;
+        let query = update_profile_query(name, description);
+        let resp = self
+            .http_client
+            .post(url)
+            .header(
+                AUTHORIZATION,
+                self.oauth1_request_builder
+                    .post(url, &ParameterList::new(query.clone())),
+            )
+            .query(&query)
+            .send()
+            .await?;
+        let resp = trace_header_and_throw_if_error_status(resp).await?;
+        Ok(resp.json().await?)
+    }
+
     pub async fn verify_credentials<T: DeserializeOwned>(&self) -> Result<T> {
         let url = "https://api.twitter.com/1.1/account/verify_credentials.json";
         let resp = self
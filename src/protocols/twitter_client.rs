@@ -1,19 +1,41 @@
 use std::sync::Arc;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use async_trait::async_trait;
 use chrono::{DateTime, FixedOffset};
 use futures::future::join_all;
 use serde_json::{json, Value};
+use tracing::warn;
 
 use crate::{sources::source, store};
 
-use super::twitter_api::{Api, TweetBody};
+use super::{
+    media::{MediaError, MediaFetcher},
+    twitter_api::{
+        like_url, likes_url, retweet_1_1_url, tweet_url, unretweet_1_1_url, update_profile_query,
+        Api, TweetBody, CREATE_TWEET_URL, UPDATE_PROFILE_URL,
+    },
+    Request,
+};
 
 pub const ORIGIN: &str = "https://twitter.com";
 
+fn to_tweet_body<'a>(
+    content: &'a str,
+    reply_identifier: Option<&str>,
+    media_ids: Vec<String>,
+) -> TweetBody<'a> {
+    TweetBody {
+        media: (!media_ids.is_empty()).then(|| json!({ "media_ids": media_ids })),
+        quote_tweet_id: None,
+        reply: reply_identifier
+            .map(|reply_identifier| json!({ "in_reply_to_tweet_id": reply_identifier })),
+        text: content,
+    }
+}
+
 pub struct Client {
-    http_client: Arc<reqwest::Client>,
+    media_fetcher: Arc<MediaFetcher>,
     api: Api,
     user_id: String,
 }
@@ -22,6 +44,7 @@ impl Client {
     #[tracing::instrument(name = "twitter_client::Client::new", skip_all)]
     pub async fn new(
         http_client: Arc<reqwest::Client>,
+        media_fetcher: Arc<MediaFetcher>,
         api_key: String,
         api_key_secret: String,
         access_token: String,
@@ -43,7 +66,7 @@ impl Client {
             .to_owned();
 
         Ok(Self {
-            http_client,
+            media_fetcher,
             api,
             user_id,
         })
@@ -65,6 +88,14 @@ impl super::Client for Client {
         todo!()
     }
 
+    #[tracing::instrument(name = "twitter_client::Client::fetch_statuses_page", skip_all)]
+    async fn fetch_statuses_page(
+        &mut self,
+        _cursor: Option<&str>,
+    ) -> Result<(Vec<source::LiveStatus>, Option<String>)> {
+        bail!("fetching statuses from twitter is not supported")
+    }
+
     #[tracing::instrument(name = "twitter_client::Client::post", skip_all)]
     async fn post(
         &mut self,
@@ -75,13 +106,17 @@ impl super::Client for Client {
         _external: Option<store::operations::External>,
         _created_at: &DateTime<FixedOffset>,
     ) -> Result<String> {
-        let media = if images.is_empty() {
-            None
+        let media_ids = if images.is_empty() {
+            Vec::new()
         } else {
             // TODO: alt
-            let media_ids = join_all(images.into_iter().map(|image| async {
-                let resp = self.http_client.get(image.url).send().await?;
-                let res: Value = self.api.upload(resp).await?;
+            let (media_fetcher, api) = (&self.media_fetcher, &self.api);
+            join_all(images.into_iter().map(|image| async move {
+                let media = media_fetcher.fetch(&image.url).await?;
+                let res: Value = api
+                    .upload(media.bytes)
+                    .await
+                    .map_err(|err| MediaError::wrap(&image.url, err))?;
                 Ok(res)
             }))
             .await
@@ -96,19 +131,10 @@ impl super::Client for Client {
                     .ok_or_else(|| anyhow!("media_id_string is not str"))?
                     .to_owned())
             })
-            .collect::<Result<Vec<_>>>()?;
-            Some(json!({ "media_ids": media_ids }))
-        };
-
-        let body = TweetBody {
-            media,
-            quote_tweet_id: None,
-            reply: reply_identifier.map(
-                |reply_identifier| serde_json::json!({ "in_reply_to_tweet_id": reply_identifier }),
-            ),
-            text: content,
+            .collect::<Result<Vec<_>>>()?
         };
 
+        let body = to_tweet_body(content, reply_identifier, media_ids);
         let json: Value = self.api.create_tweet(body).await?;
         let id = json
             .get("data")
@@ -122,6 +148,61 @@ impl super::Client for Client {
         Ok(id.to_owned())
     }
 
+    /** repost と取り消しは 1.1 の API で示す。アクセス違反で proxy を使うかは送るまで分からない */
+    fn render(&self, request: &Request<'_>) -> Result<Value> {
+        let (method, url, body) = match *request {
+            Request::Post {
+                content,
+                reply_identifier,
+                images,
+                ..
+            } => {
+                // アップロードしないので、media_ids には画像の URL を入れる
+                let media_ids = images.iter().map(|image| image.url.clone()).collect();
+                let body = to_tweet_body(content, reply_identifier, media_ids);
+                (
+                    "POST",
+                    CREATE_TWEET_URL.to_owned(),
+                    serde_json::to_value(body)?,
+                )
+            }
+            Request::Repost {
+                target_identifier, ..
+            } => ("POST", retweet_1_1_url(target_identifier), Value::Null),
+            Request::DeletePost { identifier } => ("DELETE", tweet_url(identifier), Value::Null),
+            Request::DeleteRepost { identifier } => {
+                ("POST", unretweet_1_1_url(identifier), Value::Null)
+            }
+            Request::Like { target_identifier } => (
+                "POST",
+                likes_url(&self.user_id),
+                json!({ "tweet_id": target_identifier }),
+            ),
+            Request::Unlike { identifier } => {
+                ("DELETE", like_url(&self.user_id, identifier), Value::Null)
+            }
+            Request::UpdateProfile { profile } => {
+                let query = update_profile_query(
+                    profile.display_name.as_deref(),
+                    profile.description.as_deref(),
+                );
+                let query: serde_json::Map<_, _> = query
+                    .into_iter()
+                    .map(|(key, value)| (key.to_owned(), value.into()))
+                    .collect();
+                return Ok(json!({
+                    "method": "POST",
+                    "url": UPDATE_PROFILE_URL,
+                    "query": query,
+                }));
+            }
+            Request::Pin { .. } | Request::Unpin { .. } => {
+                return Ok(json!({ "unsupported": "pinning is not supported on twitter" }));
+            }
+        };
+        Ok(json!({ "method": method, "url": url, "body": body }))
+    }
+
     #[tracing::instrument(name = "twitter_client::Client::repost", skip_all)]
     async fn repost(
         &mut self,
@@ -177,4 +258,47 @@ impl super::Client for Client {
             .await?;
         Ok(())
     }
+
+    #[tracing::instrument(name = "twitter_client::Client::like", skip_all)]
+    async fn like(&mut self, target_identifier: &str) -> Result<String> {
+        let _: Value = self
+            .api
+            .create_like(&self.user_id, target_identifier)
+            .await?;
+        // like は tweet ごとに一つなので、対象の identifier で取り消せる
+        Ok(target_identifier.to_owned())
+    }
+
+    #[tracing::instrument(name = "twitter_client::Client::unlike", skip_all)]
+    async fn unlike(&mut self, identifier: &str) -> Result<()> {
+        let _: Value = self.api.delete_like(&self.user_id, identifier).await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "twitter_client::Client::update_profile", skip_all)]
+    async fn update_profile(&mut self, profile: &store::operations::Profile) -> Result<()> {
+        if profile.avatar_url.is_some() || profile.banner_url.is_some() {
+            warn!("updating profile images is not supported on twitter");
+        }
+        let _: Value = self
+            .api
+            .update_profile(
+                profile.display_name.as_deref(),
+                profile.description.as_deref(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "twitter_client::Client::pin", skip_all)]
+    async fn pin(&mut self, _identifier: &str) -> Result<()> {
+        warn!("pinning is not supported on twitter");
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "twitter_client::Client::unpin", skip_all)]
+    async fn unpin(&mut self, _identifier: &str) -> Result<()> {
+        warn!("pinning is not supported on twitter");
+        Ok(())
+    }
 }
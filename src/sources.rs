@@ -1,3 +1,6 @@
-mod merge_operations;
-mod operation_factory;
+pub mod backfill;
+pub mod merge_operations;
+pub mod operation_factory;
+pub mod retract;
+mod rules;
 pub mod source;
@@ -0,0 +1,224 @@
+use std::{collections::HashSet, sync::Arc};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, FixedOffset};
+use tracing::info;
+
+use crate::{
+    app::AccountKey,
+    config,
+    protocols::{
+        create_client, create_dst_clients, create_http_client, media::MediaFetcher, Client,
+    },
+    store::{
+        self,
+        jobs::{Job, JobKind},
+        operations::AccountPair,
+        user::{DestinationPost, DestinationRepost, DestinationStatus},
+    },
+};
+
+use super::{
+    operation_factory::create_backfill_operations,
+    source::{fill_reply_src_uris, LiveStatus},
+};
+
+/** 1回のバックフィルで遡るページ数の上限 */
+const MAX_PAGES: usize = 100;
+
+async fn find_src_client<'a>(
+    http_client: &Arc<reqwest::Client>,
+    media_fetcher: &Arc<MediaFetcher>,
+    config: &'a config::Config,
+    src_account_key: &AccountKey,
+) -> Result<(&'a config::User, Box<dyn Client>)> {
+    for config_user in &config.users {
+        let src_client =
+            create_client(http_client.clone(), media_fetcher.clone(), &config_user.src).await?;
+        if &src_client.to_account_key() == src_account_key {
+            return Ok((config_user, src_client));
+        }
+    }
+    Err(anyhow!(
+        "src account is not configured ({} {})",
+        src_account_key.origin,
+        src_account_key.identifier
+    ))
+}
+
+async fn find_dst_config<'a>(
+    http_client: &Arc<reqwest::Client>,
+    media_fetcher: &Arc<MediaFetcher>,
+    config_user: &'a config::User,
+    dst_account_key: &AccountKey,
+) -> Result<&'a config::Destination> {
+    create_dst_clients(http_client, media_fetcher, config_user)
+        .await?
+        .into_iter()
+        .find(|dst_client| &dst_client.client.to_account_key() == dst_account_key)
+        .map(|dst_client| dst_client.config)
+        .ok_or_else(|| {
+            anyhow!(
+                "dst account is not configured ({} {})",
+                dst_account_key.origin,
+                dst_account_key.identifier
+            )
+        })
+}
+
+/** account_pair で送信済み、または送信を待っている status の src の identifier */
+fn mapped_src_identifiers(store: &store::Store, account_pair: &AccountPair) -> HashSet<String> {
+    let dst_statuses = store
+        .users
+        .iter()
+        .filter(|user| {
+            user.src.origin == account_pair.src_origin
+                && user.src.identifier == account_pair.src_account_identifier
+        })
+        .flat_map(|user| &user.dsts)
+        .filter(|dst| {
+            dst.origin == account_pair.dst_origin
+                && dst.identifier == account_pair.dst_account_identifier
+        })
+        .flat_map(|dst| &dst.statuses);
+    let archived_statuses = store
+        .archive
+        .statuses
+        .iter()
+        .filter(|archived| &archived.account_pair == account_pair)
+        .map(|archived| &archived.status);
+    let mapped = dst_statuses
+        .chain(archived_statuses)
+        .filter_map(|status| match status {
+            DestinationStatus::Post(DestinationPost { src_identifier, .. })
+            | DestinationStatus::Repost(DestinationRepost { src_identifier, .. }) => {
+                Some(src_identifier.clone())
+            }
+            DestinationStatus::Like(_) => None,
+        });
+    let queued = store
+        .operations
+        .iter()
+        .chain(store.jobs.iter().flat_map(|job| &job.operations))
+        .filter(|operation| operation.is_post() && operation.account_pair() == account_pair)
+        .map(|operation| operation.src_identifier().to_owned());
+    mapped.chain(queued).collect()
+}
+
+async fn fetch_past_statuses(
+    src_client: &mut dyn Client,
+    since: Option<&DateTime<FixedOffset>>,
+    until: Option<&DateTime<FixedOffset>>,
+    count: Option<usize>,
+) -> Result<Vec<LiveStatus>> {
+    let mut live_statuses = Vec::new();
+    let mut cursor = None;
+    for _ in 0..MAX_PAGES {
+        let (page, next_cursor) = src_client.fetch_statuses_page(cursor.as_deref()).await?;
+        let reached_since = page
+            .iter()
+            .any(|live| since.is_some_and(|since| live.created_at() < since));
+        let is_empty = page.is_empty();
+        live_statuses.extend(page.into_iter().filter(|live| {
+            since.is_none_or(|since| live.created_at() >= since)
+                && until.is_none_or(|until| live.created_at() <= until)
+        }));
+        if is_empty
+            || reached_since
+            || count.is_some_and(|count| live_statuses.len() >= count)
+            || next_cursor.is_none()
+        {
+            break;
+        }
+        cursor = next_cursor;
+    }
+    // 新しいものから count 件を取り、古い順に並べる
+    live_statuses.sort_by_key(|live| std::cmp::Reverse(*live.created_at()));
+    if let Some(count) = count {
+        live_statuses.truncate(count);
+    }
+    live_statuses.reverse();
+    Ok(live_statuses)
+}
+
+/**
+ * 指定した期間または件数の過去の status を、古い順に送信するジョブとして登録する
+ */
+pub async fn backfill(
+    config: &config::Config,
+    store: &mut store::Store,
+    account_pair: &AccountPair,
+    since: Option<&DateTime<FixedOffset>>,
+    until: Option<&DateTime<FixedOffset>>,
+    count: Option<usize>,
+) -> Result<()> {
+    if since.is_none() && count.is_none() {
+        bail!("since or count is required");
+    }
+    let http_client = Arc::new(create_http_client(config.http_timeout())?);
+    let media_fetcher = Arc::new(MediaFetcher::new(
+        http_client.clone(),
+        config.media_cache_dir(),
+    ));
+    let (config_user, mut src_client) = find_src_client(
+        &http_client,
+        &media_fetcher,
+        config,
+        &account_pair.to_src_key(),
+    )
+    .await?;
+    let dst_config = find_dst_config(
+        &http_client,
+        &media_fetcher,
+        config_user,
+        &account_pair.to_dst_key(),
+    )
+    .await?;
+
+    let live_statuses = fetch_past_statuses(src_client.as_mut(), since, until, count).await?;
+    // 送信済みのものや、前回のバックフィルで送信を待っているものは積まない
+    let mapped = mapped_src_identifiers(store, account_pair);
+    let new_statuses: Vec<_> = live_statuses
+        .iter()
+        .filter(|live| !mapped.contains(live.identifier()))
+        .cloned()
+        .collect();
+    let mut operations = vec![
+        create_backfill_operations(
+            &http_client,
+            &new_statuses,
+            &store.echoed_identifiers(&account_pair.to_src_key()),
+            dst_config,
+        )
+        .await?,
+    ];
+    // 取得範囲外への返信は、送信時に宛先の reply policy に従って扱う
+    fill_reply_src_uris(
+        src_client.as_mut(),
+        &live_statuses,
+        &[config_user.reply_policy(dst_config)],
+        &mut operations,
+    )
+    .await;
+    let operations: Vec<_> = operations
+        .iter()
+        .flatten()
+        .map(|operation| operation.to_store(account_pair.clone()))
+        .collect();
+    info!(
+        "backfill job created: {} operations ({} -> {})",
+        operations.len(),
+        account_pair.src_origin,
+        account_pair.dst_origin
+    );
+    if operations.is_empty() {
+        return Ok(());
+    }
+    store.jobs.push(Job {
+        kind: JobKind::Backfill,
+        account_pair: account_pair.clone(),
+        total: operations.len(),
+        operations,
+    });
+    Ok(())
+}
@@ -1,42 +1,55 @@
-use tracing::warn;
+use chrono::Duration;
+use tracing::debug;
 
 use super::source::Operation;
 use crate::{
     app::AccountKey,
-    protocols::Client,
+    protocols::DstClient,
     store::{
         self,
-        operations::Operation::{CreatePost, CreateRepost, DeletePost, DeleteRepost, UpdatePost},
+        operations::Operation::{
+            CreateLike, CreatePost, CreateRepost, DeleteLike, DeletePost, DeleteRepost, PinPost,
+            UnpinPost, UpdatePost, UpdateProfile,
+        },
     },
 };
 
 fn to_store_operations(
-    dst_clients: &[Box<dyn Client>],
-    operations: &[Operation],
+    dst_clients: &[DstClient],
+    operations: &[Vec<Operation>],
     src_account_key: &AccountKey,
 ) -> Vec<store::operations::Operation> {
     dst_clients
         .iter()
-        .flat_map(|dst_client| {
-            let dst_account_key = dst_client.to_account_key();
+        .zip(operations)
+        .flat_map(|(dst_client, operations)| {
+            let dst_account_key = dst_client.client.to_account_key();
 
             let account_pair =
                 store::operations::AccountPair::from_keys(src_account_key.clone(), dst_account_key);
+            let delay = Duration::seconds(dst_client.config.delay_seconds as i64);
 
             operations
                 .iter()
-                .map(|operation| operation.to_store(account_pair.clone()))
+                .map(|operation| {
+                    let mut operation = operation.to_store(account_pair.clone());
+                    if delay > Duration::zero() {
+                        operation.delay(delay);
+                    }
+                    operation
+                })
                 .collect::<Vec<_>>()
         })
         .collect()
 }
 
 /** 投稿は降順で、それ以外は末尾に積む */
-fn sort_operations(operations: &mut [store::operations::Operation]) {
+pub fn sort_operations(operations: &mut [store::operations::Operation]) {
     operations.sort_by_key(|operation| -match operation {
         CreatePost(content) => content.status.created_at.timestamp_micros(),
         CreateRepost(content) => content.status.created_at.timestamp_micros(),
-        UpdatePost(_) | DeleteRepost(_) => i64::MAX - 1,
+        CreateLike(_) | UpdateProfile(_) | PinPost(_) => i64::MAX - 2,
+        UpdatePost(_) | DeleteRepost(_) | DeleteLike(_) | UnpinPost(_) => i64::MAX - 1,
         DeletePost(_) => i64::MAX,
     });
 }
@@ -71,6 +84,39 @@ fn to_delete_repost_operation_status(
     }
 }
 
+/** 対になる operation の種類、有効にするかどうか、対象 */
+fn to_toggle(
+    operation: &store::operations::Operation,
+) -> Option<(&'static str, bool, &store::operations::AccountPair, &str)> {
+    match operation {
+        CreateLike(content) => Some((
+            "like",
+            true,
+            &content.account_pair,
+            &content.status.target_src_identifier,
+        )),
+        DeleteLike(content) => Some((
+            "like",
+            false,
+            &content.account_pair,
+            &content.status.target_src_identifier,
+        )),
+        PinPost(content) => Some((
+            "pin",
+            true,
+            &content.account_pair,
+            &content.status.src_identifier,
+        )),
+        UnpinPost(content) => Some((
+            "pin",
+            false,
+            &content.account_pair,
+            &content.status.src_identifier,
+        )),
+        _ => None,
+    }
+}
+
 fn create_operation_target_state(
     content: &store::operations::CreatePostOperation,
 ) -> (AccountKey, &str) {
@@ -80,27 +126,107 @@ fn create_operation_target_state(
     )
 }
 
+fn is_same_shared_operation(a: &Operation, b: &Operation) -> bool {
+    match (a, b) {
+        (Operation::UpdatePost(a), Operation::UpdatePost(b)) => {
+            a.src_identifier == b.src_identifier
+        }
+        (Operation::DeletePost(a), Operation::DeletePost(b)) => {
+            a.src_identifier == b.src_identifier
+        }
+        (Operation::DeleteRepost(a), Operation::DeleteRepost(b)) => {
+            a.src_identifier == b.src_identifier
+        }
+        _ => false,
+    }
+}
+
+/**
+ * 投稿の更新と削除、repost の削除は宛先のルールに依らないので、
+ * 全ての宛先の operation から重複なく集める
+ */
+fn to_shared_operations(src_operations: &[Vec<Operation>]) -> Vec<&Operation> {
+    let mut shared: Vec<&Operation> = Vec::new();
+    for operation in src_operations.iter().flatten() {
+        let is_shared = matches!(
+            operation,
+            Operation::UpdatePost(_) | Operation::DeletePost(_) | Operation::DeleteRepost(_)
+        );
+        if is_shared
+            && !shared
+                .iter()
+                .any(|existing| is_same_shared_operation(existing, operation))
+        {
+            shared.push(operation);
+        }
+    }
+    shared
+}
+
+/** src_operations は dst_clients と同じ順序の、宛先ごとの operation */
 pub fn merge_operations(
     store: &mut store::Store,
-    dst_clients: &[Box<dyn Client>],
+    dst_clients: &[DstClient],
     src_account_key: &AccountKey,
-    src_operations: &[Operation],
+    src_operations: &[Vec<Operation>],
 ) {
     let mut new_operations = to_store_operations(dst_clients, src_operations, src_account_key);
+    let shared_operations = to_shared_operations(src_operations);
 
     let operations = &mut store.operations;
 
-    // 投稿の更新
-    src_operations
+    // 投稿の更新。create が未送信なら create を書き換える
+    for status in shared_operations
         .iter()
+        .copied()
         .filter_map(to_update_post_operation_status)
-        .for_each(|_| warn!("もし create が未送信なら、create を書き換える必要がある"));
+    {
+        for operation in operations.iter_mut() {
+            let CreatePost(content) = operation else {
+                continue;
+            };
+            if create_operation_target_state(content)
+                != (src_account_key.clone(), status.src_identifier.as_str())
+            {
+                continue;
+            }
+            debug!(
+                "rewrite pending create (src_identifier={})",
+                status.src_identifier
+            );
+            content.status.content = status.content.clone();
+            content.status.facets = status.facets.clone();
+            let account_pair = content.account_pair.clone();
+            new_operations.retain(|new_operation| {
+                !matches!(new_operation, UpdatePost(update)
+                    if update.account_pair == account_pair
+                        && update.status.src_identifier == status.src_identifier)
+            });
+        }
+    }
     // 投稿の削除を適用
-    let deleting_post_full_identifiers: Vec<_> = src_operations
+    let deleting_post_full_identifiers: Vec<_> = shared_operations
         .iter()
+        .copied()
         .filter_map(to_delete_post_operation_status)
         .map(|status| (src_account_key.clone(), status.src_identifier.as_str()))
         .collect();
+    // 未送信の create を取り消した場合は、削除も不要
+    let cancelled_posts: Vec<_> = operations
+        .iter()
+        .filter_map(|dst_operation| match dst_operation {
+            CreatePost(content)
+                if deleting_post_full_identifiers
+                    .contains(&create_operation_target_state(content)) =>
+            {
+                Some((
+                    content.account_pair.clone(),
+                    content.status.src_identifier.clone(),
+                ))
+            }
+            _ => None,
+        })
+        .collect();
     operations.retain(|dst_operation| match dst_operation {
         CreatePost(content) => {
             let operation_full_identifier = create_operation_target_state(content);
@@ -113,14 +239,40 @@ pub fn merge_operations(
             );
             !deleting_post_full_identifiers.contains(&operation_full_identifier)
         }
-        UpdatePost(_) | DeletePost(_) | DeleteRepost(_) => true,
+        UpdatePost(_) | DeletePost(_) | DeleteRepost(_) | CreateLike(_) | DeleteLike(_)
+        | UpdateProfile(_) | PinPost(_) | UnpinPost(_) => true,
+    });
+    new_operations.retain(|new_operation| {
+        !matches!(new_operation, DeletePost(content)
+        if cancelled_posts.iter().any(|(account_pair, src_identifier)| {
+            &content.account_pair == account_pair
+                && &content.status.src_identifier == src_identifier
+        }))
     });
     // repost の削除を適用
-    let deleting_repost_full_identifiers: Vec<_> = src_operations
+    let deleting_repost_full_identifiers: Vec<_> = shared_operations
         .iter()
+        .copied()
         .filter_map(to_delete_repost_operation_status)
         .map(|status| (src_account_key.clone(), status.src_identifier.as_str()))
         .collect();
+    let cancelled_reposts: Vec<_> = operations
+        .iter()
+        .filter_map(|dst_operation| match dst_operation {
+            CreateRepost(content)
+                if deleting_repost_full_identifiers.contains(&(
+                    content.account_pair.to_src_key(),
+                    content.status.src_identifier.as_str(),
+                )) =>
+            {
+                Some((
+                    content.account_pair.clone(),
+                    content.status.src_identifier.clone(),
+                ))
+            }
+            _ => None,
+        })
+        .collect();
     operations.retain(|dst_operation| match dst_operation {
         CreateRepost(content) => {
             let operation_full_identifier = (
@@ -129,7 +281,47 @@ pub fn merge_operations(
             );
             !deleting_repost_full_identifiers.contains(&operation_full_identifier)
         }
-        CreatePost(_) | UpdatePost(_) | DeletePost(_) | DeleteRepost(_) => true,
+        CreatePost(_) | UpdatePost(_) | DeletePost(_) | DeleteRepost(_) | CreateLike(_)
+        | DeleteLike(_) | UpdateProfile(_) | PinPost(_) | UnpinPost(_) => true,
+    });
+    new_operations.retain(|new_operation| {
+        !matches!(new_operation, DeleteRepost(content)
+        if cancelled_reposts.iter().any(|(account_pair, src_identifier)| {
+            &content.account_pair == account_pair
+                && &content.status.src_identifier == src_identifier
+        }))
+    });
+    // like や固定と、その取り消しが未送信同士なら、両方とも不要
+    let cancelled_toggles: Vec<_> = new_operations
+        .iter()
+        .filter_map(to_toggle)
+        .filter(|(kind, is_on, account_pair, src_identifier)| {
+            operations
+                .iter()
+                .filter_map(to_toggle)
+                .any(|pending| pending == (kind, !is_on, account_pair, src_identifier))
+        })
+        .map(|(kind, _, account_pair, src_identifier)| {
+            (kind, account_pair.clone(), src_identifier.to_owned())
+        })
+        .collect();
+    let is_cancelled_toggle = |operation: &store::operations::Operation| {
+        to_toggle(operation).is_some_and(|(kind, _, account_pair, src_identifier)| {
+            cancelled_toggles.iter().any(|cancelled| {
+                cancelled.0 == kind && &cancelled.1 == account_pair && cancelled.2 == src_identifier
+            })
+        })
+    };
+    operations.retain(|dst_operation| !is_cancelled_toggle(dst_operation));
+    new_operations.retain(|new_operation| !is_cancelled_toggle(new_operation));
+
+    // 未送信のプロフィールの更新は、新しいもので置き換える
+    operations.retain(|dst_operation| {
+        !matches!(dst_operation, UpdateProfile(content)
+        if new_operations.iter().any(|new_operation| {
+            matches!(new_operation, UpdateProfile(new_content)
+                if new_content.account_pair == content.account_pair)
+        }))
     });
 
     operations.append(&mut new_operations);
@@ -1,14 +1,20 @@
 use anyhow::Result;
 use futures::future::join_all;
-use tracing::warn;
+use tracing::{debug, info, warn};
 
-use crate::store::{
-    self,
-    operations::{DeleteRepostOperationStatus, Facet::Link},
-    user::SourceStatus,
+use crate::{
+    config,
+    store::{
+        self,
+        operations::{DeleteRepostOperationStatus, Facet::Link},
+        user::SourceStatus,
+    },
 };
 
-use super::source::{LiveExternal, LiveStatus, Operation};
+use super::{
+    rules::find_dropping_rule,
+    source::{LiveExternal, LiveStatus, Operation},
+};
 
 async fn fetch_html(http_client: &reqwest::Client, uri: String) -> Result<webpage::HTML> {
     let text = http_client
@@ -21,7 +27,7 @@ async fn fetch_html(http_client: &reqwest::Client, uri: String) -> Result<webpag
     Ok(webpage::HTML::from_string(text, Some(uri))?)
 }
 
-async fn create_external(
+pub async fn create_external(
     facets: &[store::operations::Facet],
     http_client: &reqwest::Client,
 ) -> Result<Option<store::operations::External>> {
@@ -61,6 +67,7 @@ async fn try_into_operation(live: LiveStatus, http_client: &reqwest::Client) ->
                 content: post.content,
                 facets: post.facets,
                 reply_src_identifier: post.reply_src_identifier,
+                reply_src_uri: post.reply_src_uri,
                 media: post.media,
                 external,
                 created_at: post.created_at,
@@ -70,37 +77,81 @@ async fn try_into_operation(live: LiveStatus, http_client: &reqwest::Client) ->
     })
 }
 
+/** 宛先のルールで送信対象になるか */
+fn is_echoed(dst: &config::Destination, live: &LiveStatus) -> bool {
+    let Some(reason) = find_dropping_rule(&dst.rules, live) else {
+        return true;
+    };
+    info!(
+        "dropped for {}: {} (src_identifier={})",
+        dst.account.origin(),
+        reason,
+        live.identifier()
+    );
+    false
+}
+
+/** 他のアカウントから送信された status は、送り返さない */
+fn is_not_echoed_status(live: &LiveStatus, echoed_identifiers: &[String]) -> bool {
+    if !echoed_identifiers
+        .iter()
+        .any(|identifier| identifier == live.identifier())
+    {
+        return true;
+    }
+    debug!(
+        "skip status echoed from another account (src_identifier={})",
+        live.identifier()
+    );
+    false
+}
+
+/** 過去の status から operation を作る。live_statuses と同じ順序で返す */
+pub async fn create_backfill_operations(
+    http_client: &reqwest::Client,
+    live_statuses: &[LiveStatus],
+    echoed_identifiers: &[String],
+    dst: &config::Destination,
+) -> Result<Vec<Operation>> {
+    let operations = live_statuses
+        .iter()
+        .filter(|live| is_not_echoed_status(live, echoed_identifiers))
+        .filter(|live| is_echoed(dst, live))
+        .map(|live| try_into_operation(live.clone(), http_client));
+    join_all(operations).await.into_iter().collect()
+}
+
+/** 宛先ごとの operation を dsts と同じ順序で返す */
 pub async fn create_operations(
     http_client: &reqwest::Client,
     live_statuses: &[LiveStatus],
     stored_statuses: &[store::user::SourceStatus],
-) -> Result<Vec<Operation>> {
-    if live_statuses.is_empty() || stored_statuses.is_empty() {
-        return Ok(vec![]);
+    echoed_identifiers: &[String],
+    dsts: &[config::Destination],
+) -> Result<Vec<Vec<Operation>>> {
+    let empty = || dsts.iter().map(|_| Vec::new()).collect();
+    if live_statuses.is_empty() {
+        return Ok(empty());
+    }
+    if stored_statuses.is_empty() {
+        info!("no stored statuses, start from now (use backfill command to echo past statuses)");
+        return Ok(empty());
     }
     // C
     let last_date_time = stored_statuses
         .iter()
         .max_by_key(|status| status.created_at())
         .map(SourceStatus::created_at);
-    let c = live_statuses
+    let c_lives: Vec<_> = live_statuses
         .iter()
         .filter(|live| {
-            last_date_time.map_or(true, |last_date_time| live.created_at() > last_date_time)
-        })
-        .filter(|live| {
-            if let LiveStatus::Post(post) = live {
-                // 自分宛てのリプライのみを投稿対象にする
-                if let Some(reply_src_identifier) = &post.reply_src_identifier {
-                    return live_statuses.iter().any(|live| match live {
-                        LiveStatus::Post(post) => &post.identifier == reply_src_identifier,
-                        LiveStatus::Repost(_) => false,
-                    });
-                }
-            }
-            true
+            last_date_time.is_none_or(|last_date_time| live.created_at() > last_date_time)
         })
-        .map(|live| try_into_operation(live.clone(), http_client));
+        .filter(|live| is_not_echoed_status(live, echoed_identifiers))
+        .collect();
+    let c = c_lives
+        .iter()
+        .map(|live| try_into_operation((*live).clone(), http_client));
     let c = join_all(c).await.into_iter().collect::<Result<Vec<_>>>()?;
     // UD
     let since = &live_statuses
@@ -155,7 +206,87 @@ pub async fn create_operations(
                     }))
                 }
             }
+        })
+        .collect::<Vec<_>>();
+
+    Ok(dsts
+        .iter()
+        .map(|dst| {
+            c_lives
+                .iter()
+                .zip(&c)
+                .filter(|(live, _)| is_echoed(dst, live))
+                .map(|(_, operation)| operation.clone())
+                .chain(ud.iter().cloned())
+                .collect()
+        })
+        .collect())
+}
+
+/** like の追加と取り消しを検出する。live_likes と stored_likes は新しい順 */
+pub fn create_like_operations(
+    live_likes: &[store::operations::CreateLikeOperationStatus],
+    stored_likes: &[store::user::SourceLike],
+) -> Vec<Operation> {
+    if stored_likes.is_empty() {
+        return Vec::new();
+    }
+    let is_stored = |target_identifier: &str| {
+        stored_likes
+            .iter()
+            .any(|stored| stored.target_identifier == target_identifier)
+    };
+    let is_live = |target_identifier: &str| {
+        live_likes
+            .iter()
+            .any(|live| live.target_src_identifier == target_identifier)
+    };
+    // 取得範囲から外れただけのものを除くため、live にも残っている最も古いものより新しいものだけを見る。
+    // 重なりが無い場合は区別できないので何もしない
+    let Some(last_live_idx) = stored_likes
+        .iter()
+        .rposition(|stored| is_live(&stored.target_identifier))
+    else {
+        return Vec::new();
+    };
+    let deleted = stored_likes[..last_live_idx]
+        .iter()
+        .filter(|stored| !is_live(&stored.target_identifier))
+        .map(|stored| {
+            Operation::DeleteLike(store::operations::DeleteLikeOperationStatus {
+                target_src_identifier: stored.target_identifier.clone(),
+            })
         });
+    let created = live_likes
+        .iter()
+        .take_while(|live| !is_stored(&live.target_src_identifier))
+        .map(|live| Operation::CreateLike(live.clone()));
+    created.chain(deleted).collect()
+}
 
-    Ok(c.into_iter().chain(ud).collect())
+/** 固定の変化を検出する。初回は記録のみ */
+pub fn create_pin_operations(
+    live_pinned_identifiers: &[String],
+    stored_pinned_identifiers: Option<&[String]>,
+) -> Vec<Operation> {
+    let Some(stored_pinned_identifiers) = stored_pinned_identifiers else {
+        return Vec::new();
+    };
+    let unpinned = stored_pinned_identifiers
+        .iter()
+        .filter(|stored| !live_pinned_identifiers.contains(stored))
+        .map(|stored| {
+            Operation::UnpinPost(store::operations::UnpinPostOperationStatus {
+                src_identifier: stored.clone(),
+            })
+        });
+    let pinned = live_pinned_identifiers
+        .iter()
+        .filter(|live| !stored_pinned_identifiers.contains(live))
+        .map(|live| {
+            Operation::PinPost(store::operations::PinPostOperationStatus {
+                src_identifier: live.clone(),
+            })
+        });
+    unpinned.chain(pinned).collect()
 }
@@ -0,0 +1,118 @@
+use anyhow::Result;
+use chrono::{DateTime, FixedOffset};
+use tracing::info;
+
+use crate::store::{
+    self,
+    jobs::{Job, JobKind},
+    operations::{
+        AccountPair, DeletePostOperation, DeletePostOperationStatus, DeleteRepostOperation,
+        DeleteRepostOperationStatus, Operation,
+    },
+    user::{DestinationPost, DestinationRepost, DestinationStatus},
+};
+
+fn is_target(
+    src_identifier: &str,
+    created_at: Option<&DateTime<FixedOffset>>,
+    since: Option<&DateTime<FixedOffset>>,
+    until: Option<&DateTime<FixedOffset>>,
+    src_identifiers: &[String],
+) -> bool {
+    if !src_identifiers.is_empty() && !src_identifiers.iter().any(|x| x == src_identifier) {
+        return false;
+    }
+    if since.is_none() && until.is_none() {
+        return true;
+    }
+    // 作成日時を持たない古い対応は、期間を指定した場合は対象にしない
+    created_at.is_some_and(|created_at| {
+        since.is_none_or(|since| created_at >= since)
+            && until.is_none_or(|until| created_at <= until)
+    })
+}
+
+/**
+ * AccountPair で送信した post と repost を全て削除するジョブを登録する。
+ * 期間や取得元の identifier で対象を絞れる
+ */
+pub fn retract(
+    store: &mut store::Store,
+    account_pair: &AccountPair,
+    since: Option<&DateTime<FixedOffset>>,
+    until: Option<&DateTime<FixedOffset>>,
+    src_identifiers: &[String],
+) -> Result<()> {
+    let to_operation = |status: &DestinationStatus| match status {
+        DestinationStatus::Post(DestinationPost {
+            src_identifier,
+            created_at,
+            ..
+        }) if is_target(
+            src_identifier,
+            created_at.as_ref(),
+            since,
+            until,
+            src_identifiers,
+        ) =>
+        {
+            Some(Operation::DeletePost(DeletePostOperation {
+                account_pair: account_pair.clone(),
+                status: DeletePostOperationStatus {
+                    src_identifier: src_identifier.clone(),
+                },
+            }))
+        }
+        DestinationStatus::Repost(DestinationRepost {
+            src_identifier,
+            created_at,
+            ..
+        }) if is_target(
+            src_identifier,
+            created_at.as_ref(),
+            since,
+            until,
+            src_identifiers,
+        ) =>
+        {
+            Some(Operation::DeleteRepost(DeleteRepostOperation {
+                account_pair: account_pair.clone(),
+                status: DeleteRepostOperationStatus {
+                    src_identifier: src_identifier.clone(),
+                },
+            }))
+        }
+        _ => None,
+    };
+    let mut operations: Vec<_> = store
+        .get_or_create_dst_mut(account_pair)
+        .statuses
+        .iter()
+        .filter_map(to_operation)
+        .collect();
+    // 取得範囲から外れた古いものは archive にある
+    operations.extend(
+        store
+            .archive
+            .statuses
+            .iter()
+            .filter(|archived| &archived.account_pair == account_pair)
+            .filter_map(|archived| to_operation(&archived.status)),
+    );
+    info!(
+        "retract job created: {} operations ({} -> {})",
+        operations.len(),
+        account_pair.src_origin,
+        account_pair.dst_origin
+    );
+    if operations.is_empty() {
+        return Ok(());
+    }
+    store.jobs.push(Job {
+        kind: JobKind::Retract,
+        account_pair: account_pair.clone(),
+        total: operations.len(),
+        operations,
+    });
+    Ok(())
+}
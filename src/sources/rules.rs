@@ -0,0 +1,141 @@
+use std::sync::LazyLock;
+
+use chrono::{DateTime, FixedOffset};
+use linkify::LinkFinder;
+use regex::Regex;
+
+use crate::{
+    config::{Rule, RuleAction, StatusKind},
+    store::operations::Facet,
+};
+
+use super::source::LiveStatus;
+
+/** ルールの判定対象 */
+struct Target<'a> {
+    kind: StatusKind,
+    content: &'a str,
+    links: Vec<String>,
+    has_media: bool,
+    visibility: Option<&'a str>,
+    created_at: &'a DateTime<FixedOffset>,
+}
+
+impl<'a> From<&'a LiveStatus> for Target<'a> {
+    fn from(live: &'a LiveStatus) -> Self {
+        match live {
+            LiveStatus::Post(post) => Target {
+                kind: if post.reply_src_identifier.is_some() {
+                    StatusKind::Reply
+                } else {
+                    StatusKind::Post
+                },
+                content: &post.content,
+                links: post
+                    .facets
+                    .iter()
+                    .map(|facet| match facet {
+                        Facet::Link { uri, .. } => uri.clone(),
+                    })
+                    .collect(),
+                has_media: !post.media.is_empty(),
+                visibility: post.visibility.as_deref(),
+                created_at: &post.created_at,
+            },
+            LiveStatus::Repost(repost) => {
+                let content = repost.target_content.as_deref().unwrap_or_default();
+                Target {
+                    kind: StatusKind::Repost,
+                    content,
+                    links: LinkFinder::new()
+                        .links(content)
+                        .map(|link| link.as_str().to_owned())
+                        .collect(),
+                    has_media: false,
+                    visibility: None,
+                    created_at: &repost.created_at,
+                }
+            }
+        }
+    }
+}
+
+static HASHTAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"#([^\s#]+)").unwrap());
+
+fn hashtags(content: &str) -> Vec<String> {
+    HASHTAG
+        .captures_iter(content)
+        .map(|m| m[1].to_lowercase())
+        .collect()
+}
+
+fn link_domains(links: &[String]) -> Vec<String> {
+    links
+        .iter()
+        .filter_map(|link| reqwest::Url::parse(link).ok())
+        .filter_map(|url| url.host_str().map(str::to_lowercase))
+        .collect()
+}
+
+fn is_match(rule: &Rule, target: &Target) -> bool {
+    rule.content
+        .as_ref()
+        .is_none_or(|content| content.is_match(target.content))
+        && (rule.hashtags.is_empty() || {
+            let hashtags = hashtags(target.content);
+            rule.hashtags
+                .iter()
+                .any(|hashtag| hashtags.contains(&hashtag.trim_start_matches('#').to_lowercase()))
+        })
+        && (rule.link_domains.is_empty() || {
+            let domains = link_domains(&target.links);
+            rule.link_domains.iter().any(|rule_domain| {
+                let rule_domain = rule_domain.to_lowercase();
+                domains.iter().any(|domain| {
+                    *domain == rule_domain || domain.ends_with(&format!(".{}", rule_domain))
+                })
+            })
+        })
+        && rule
+            .has_media
+            .is_none_or(|has_media| has_media == target.has_media)
+        && (rule.kinds.is_empty() || rule.kinds.contains(&target.kind))
+        && (rule.visibilities.is_empty()
+            || target
+                .visibility
+                .is_some_and(|visibility| rule.visibilities.iter().any(|x| x == visibility)))
+        && rule
+            .time_of_day
+            .as_ref()
+            .is_none_or(|time_of_day| time_of_day.contains(target.created_at))
+}
+
+fn to_label(idx: usize, rule: &Rule) -> String {
+    match &rule.name {
+        Some(name) => format!("rules[{}] {}", idx, name),
+        None => format!("rules[{}]", idx),
+    }
+}
+
+/** 送信しない場合は、その理由となったルールを返す */
+pub fn find_dropping_rule(rules: &[Rule], live: &LiveStatus) -> Option<String> {
+    if rules.is_empty() {
+        return None;
+    }
+    let target = Target::from(live);
+    if let Some((idx, rule)) = rules
+        .iter()
+        .enumerate()
+        .find(|(_, rule)| matches!(rule.action, RuleAction::Exclude) && is_match(rule, &target))
+    {
+        return Some(format!("excluded by {}", to_label(idx, rule)));
+    }
+    let mut includes = rules
+        .iter()
+        .filter(|rule| matches!(rule.action, RuleAction::Include))
+        .peekable();
+    if includes.peek().is_some() && !includes.any(|rule| is_match(rule, &target)) {
+        return Some("no include rule matched".to_owned());
+    }
+    None
+}
@@ -1,24 +1,35 @@
 use std::{
+    collections::HashMap,
     convert::Into,
     sync::{Arc, Mutex},
 };
 
 use anyhow::Result;
-use chrono::{DateTime, FixedOffset};
-use tracing::trace;
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use tracing::{info, trace, warn};
 
 use crate::{
     app::AccountKey,
     config,
-    protocols::{create_client, create_clients, Client},
+    protocols::{
+        create_client, create_dst_clients, create_retired_dst_clients, media::MediaFetcher, Client,
+        DstClient,
+    },
     store::{
         self,
-        operations::Operation::{CreatePost, CreateRepost, DeletePost, DeleteRepost, UpdatePost},
+        archive::ArchivedStatus,
+        operations::Operation::{
+            CreateLike, CreatePost, CreateRepost, DeleteLike, DeletePost, DeleteRepost, PinPost,
+            UnpinPost, UpdatePost, UpdateProfile,
+        },
         user::SourceStatus::{Post, Repost},
     },
 };
 
-use super::{merge_operations::merge_operations, operation_factory::create_operations};
+use super::{
+    merge_operations::merge_operations,
+    operation_factory::{create_like_operations, create_operations, create_pin_operations},
+};
 
 #[derive(Clone, Debug)]
 pub enum LiveExternal {
@@ -34,6 +45,9 @@ pub struct LivePost {
     pub content: String,
     pub facets: Vec<store::operations::Facet>,
     pub reply_src_identifier: Option<String>,
+    pub reply_src_uri: Option<String>,
+    /** 元のサーバーでの公開範囲 */
+    pub visibility: Option<String>,
     pub media: Vec<store::operations::Medium>,
     pub external: LiveExternal,
     pub created_at: DateTime<FixedOffset>,
@@ -46,6 +60,16 @@ pub enum LiveStatus {
 }
 
 impl LiveStatus {
+    pub fn identifier(&self) -> &str {
+        match self {
+            LiveStatus::Post(LivePost { identifier, .. })
+            | LiveStatus::Repost(store::operations::CreateRepostOperationStatus {
+                src_identifier: identifier,
+                ..
+            }) => identifier,
+        }
+    }
+
     pub fn created_at(&self) -> &DateTime<FixedOffset> {
         match self {
             LiveStatus::Post(LivePost { created_at, .. })
@@ -57,13 +81,18 @@ impl LiveStatus {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum Operation {
     CreatePost(store::operations::CreatePostOperationStatus),
     CreateRepost(store::operations::CreateRepostOperationStatus),
     UpdatePost(store::operations::UpdatePostOperationStatus),
     DeletePost(store::operations::DeletePostOperationStatus),
     DeleteRepost(store::operations::DeleteRepostOperationStatus),
+    CreateLike(store::operations::CreateLikeOperationStatus),
+    DeleteLike(store::operations::DeleteLikeOperationStatus),
+    UpdateProfile(store::operations::UpdateProfileOperationStatus),
+    PinPost(store::operations::PinPostOperationStatus),
+    UnpinPost(store::operations::UnpinPostOperationStatus),
 }
 
 impl Operation {
@@ -75,11 +104,14 @@ impl Operation {
             Operation::CreatePost(status) => CreatePost(store::operations::CreatePostOperation {
                 account_pair,
                 status: status.clone(),
+                not_before: None,
+                media_failures: 0,
             }),
             Operation::CreateRepost(status) => {
                 CreateRepost(store::operations::CreateRepostOperation {
                     account_pair,
                     status: status.clone(),
+                    not_before: None,
                 })
             }
             Operation::UpdatePost(status) => UpdatePost(store::operations::UpdatePostOperation {
@@ -96,7 +128,82 @@ impl Operation {
                     status: status.clone(),
                 })
             }
+            Operation::CreateLike(status) => CreateLike(store::operations::CreateLikeOperation {
+                account_pair,
+                status: status.clone(),
+            }),
+            Operation::DeleteLike(status) => DeleteLike(store::operations::DeleteLikeOperation {
+                account_pair,
+                status: status.clone(),
+            }),
+            Operation::UpdateProfile(status) => {
+                UpdateProfile(store::operations::UpdateProfileOperation {
+                    account_pair,
+                    status: status.clone(),
+                })
+            }
+            Operation::PinPost(status) => PinPost(store::operations::PinPostOperation {
+                account_pair,
+                status: status.clone(),
+            }),
+            Operation::UnpinPost(status) => UnpinPost(store::operations::UnpinPostOperation {
+                account_pair,
+                status: status.clone(),
+            }),
+        }
+    }
+}
+
+/**
+ * 宛先側で返信先を扱えるように、取得範囲外の返信先の uri を取得する。
+ * reply_policies は operations と同じ並びの宛先ごとの設定で、Skip の宛先には取得しない
+ */
+pub async fn fill_reply_src_uris(
+    src_client: &mut dyn Client,
+    live_statuses: &[LiveStatus],
+    reply_policies: &[config::ReplyPolicy],
+    operations: &mut [Vec<Operation>],
+) {
+    // 同じ返信先は宛先が違っても一度だけ取得する
+    let mut reply_src_uris: HashMap<String, Option<String>> = HashMap::new();
+    let operations = operations
+        .iter_mut()
+        .zip(reply_policies)
+        .filter(|(_, reply_policy)| !matches!(reply_policy, config::ReplyPolicy::Skip))
+        .flat_map(|(operations, _)| operations.iter_mut());
+    for operation in operations {
+        let Operation::CreatePost(status) = operation else {
+            continue;
+        };
+        let Some(reply_src_identifier) = status.reply_src_identifier.clone() else {
+            continue;
+        };
+        let is_reply_to_live = live_statuses.iter().any(|live| match live {
+            LiveStatus::Post(post) => post.identifier == reply_src_identifier,
+            LiveStatus::Repost(_) => false,
+        });
+        // 取得元が返信先の uri を付けていても、取得範囲内の返信先は宛先の対応で解決する
+        if is_reply_to_live {
+            status.reply_src_uri = None;
+            continue;
         }
+        if status.reply_src_uri.is_some() {
+            continue;
+        }
+        if !reply_src_uris.contains_key(&reply_src_identifier) {
+            let reply_src_uri = match src_client.fetch_status_uri(&reply_src_identifier).await {
+                Ok(reply_src_uri) => reply_src_uri,
+                Err(err) => {
+                    warn!(
+                        "fetch reply target failed (reply_src_identifier={}): {:?}",
+                        reply_src_identifier, err
+                    );
+                    None
+                }
+            };
+            reply_src_uris.insert(reply_src_identifier.clone(), reply_src_uri);
+        }
+        status.reply_src_uri = reply_src_uris[&reply_src_identifier].clone();
     }
 }
 
@@ -104,54 +211,271 @@ async fn fetch_statuses(
     src_client: &mut dyn Client,
     http_client: &reqwest::Client,
     src_statuses: &[store::user::SourceStatus],
-) -> Result<(Vec<store::user::SourceStatus>, Vec<Operation>)> {
+    echoed_identifiers: &[String],
+    config_user: &config::User,
+) -> Result<(Vec<store::user::SourceStatus>, Vec<Vec<Operation>>)> {
     let live_statuses = src_client.fetch_statuses().await?;
 
-    let operations = create_operations(http_client, &live_statuses, src_statuses).await?;
+    let mut operations = create_operations(
+        http_client,
+        &live_statuses,
+        src_statuses,
+        echoed_identifiers,
+        &config_user.dsts,
+    )
+    .await?;
+    let reply_policies: Vec<_> = config_user
+        .dsts
+        .iter()
+        .map(|dst| config_user.reply_policy(dst))
+        .collect();
+    fill_reply_src_uris(src_client, &live_statuses, &reply_policies, &mut operations).await;
     let statuses: Vec<_> = live_statuses.into_iter().map(Into::into).collect();
     Ok((statuses, operations))
 }
 
+/** プロフィールが変わっていれば全ての宛先に反映する。取得したプロフィールの hash を返す */
+async fn fetch_profile(
+    src_client: &mut dyn Client,
+    stored_hash: Option<String>,
+    operations: &mut [Vec<Operation>],
+) -> Result<Option<String>> {
+    let Some(profile) = src_client.fetch_profile().await? else {
+        return Ok(stored_hash);
+    };
+    let hash = profile.hash();
+    if stored_hash.as_ref() != Some(&hash) {
+        info!("profile changed (hash={})", hash);
+        let operation = Operation::UpdateProfile(store::operations::UpdateProfileOperationStatus {
+            hash: hash.clone(),
+            profile,
+        });
+        for operations in operations {
+            operations.push(operation.clone());
+        }
+    }
+    Ok(Some(hash))
+}
+
+/**
+ * archive にある post のうち、取得元で削除されたものの削除を全ての宛先に反映する。
+ * 取得範囲より古い post の削除は fetch_statuses では検出できないので、少しずつ確認する。
+ * 次に確認する位置を返す
+ */
+async fn sweep_archived_posts(
+    src_client: &mut dyn Client,
+    archived_posts: &[(String, String)],
+    offset: usize,
+    per_run: usize,
+    operations: &mut [Vec<Operation>],
+) -> usize {
+    if archived_posts.is_empty() || per_run == 0 {
+        return 0;
+    }
+    let offset = offset % archived_posts.len();
+    let batch: Vec<_> = archived_posts
+        .iter()
+        .cycle()
+        .skip(offset)
+        .take(per_run.min(archived_posts.len()))
+        .cloned()
+        .collect();
+    let deleted = match src_client.fetch_deleted_post_identifiers(&batch).await {
+        Ok(Some(deleted)) => deleted,
+        Ok(None) => return offset,
+        Err(err) => {
+            warn!("sweep archived posts failed: {:?}", err);
+            return offset;
+        }
+    };
+    for src_identifier in deleted {
+        info!(
+            "archived post is deleted (src_identifier={})",
+            src_identifier
+        );
+        let operation =
+            Operation::DeletePost(store::operations::DeletePostOperationStatus { src_identifier });
+        for operations in &mut *operations {
+            operations.push(operation.clone());
+        }
+    }
+    (offset + batch.len()) % archived_posts.len()
+}
+
+/** archive にある src_key の post の identifier と uri。削除を送信待ちのものは除く */
+fn archived_posts(store: &store::Store, src_key: &AccountKey) -> Vec<(String, String)> {
+    let mut posts: Vec<(String, String)> = Vec::new();
+    for archived in &store.archive.statuses {
+        if &archived.account_pair.to_src_key() != src_key {
+            continue;
+        }
+        let store::user::DestinationStatus::Post(post) = &archived.status else {
+            continue;
+        };
+        if posts
+            .iter()
+            .any(|(identifier, _)| identifier == &post.src_identifier)
+        {
+            continue;
+        }
+        let is_deleting = store.operations.iter().any(|operation| {
+            matches!(operation, DeletePost(content)
+                if &content.account_pair.to_src_key() == src_key
+                    && content.status.src_identifier == post.src_identifier)
+        });
+        if !is_deleting {
+            posts.push((post.src_identifier.clone(), post.src_uri.clone()));
+        }
+    }
+    posts
+}
+
 fn has_users_operations(operations: &[store::operations::Operation], src_key: &AccountKey) -> bool {
     operations
         .iter()
         .any(|operation| &operation.account_pair().to_src_key() == src_key)
 }
 
-pub async fn get(
+pub async fn get<'a>(
     http_client: &Arc<reqwest::Client>,
-    config_user: &config::User,
+    media_fetcher: &Arc<MediaFetcher>,
+    config_user: &'a config::User,
+    sweep_archived_posts_per_run: usize,
     store: &Mutex<&mut store::Store>,
-) -> Result<Option<(AccountKey, Vec<Box<dyn Client>>)>> {
-    let mut src_client = create_client(http_client.clone(), &config_user.src).await?;
+) -> Result<Option<(AccountKey, Vec<DstClient<'a>>)>> {
+    let mut src_client =
+        create_client(http_client.clone(), media_fetcher.clone(), &config_user.src).await?;
 
     let src_account_key = src_client.to_account_key();
-    let (has_users_operations, src_statuses) = {
+    let (
+        has_users_operations,
+        src_statuses,
+        src_likes,
+        src_profile_hash,
+        src_pinned_identifiers,
+        echoed_identifiers,
+        archived_posts,
+        sweep_offset,
+    ) = {
         let mut store = store.lock().unwrap();
         let has_users_operations = has_users_operations(&store.operations, &src_account_key);
+        let echoed_identifiers = store.echoed_identifiers(&src_account_key);
+        let archived_posts = archived_posts(&store, &src_account_key);
         let stored_user = store.get_or_create_user_mut(&src_account_key);
-        (has_users_operations, &stored_user.src.statuses.clone())
+        (
+            has_users_operations,
+            &stored_user.src.statuses.clone(),
+            &stored_user.src.likes.clone(),
+            stored_user.src.profile_hash.clone(),
+            stored_user.src.pinned_identifiers.clone(),
+            echoed_identifiers,
+            archived_posts,
+            stored_user.src.sweep_offset,
+        )
     };
 
-    let (statuses, operations) =
-        fetch_statuses(src_client.as_mut(), http_client.as_ref(), src_statuses).await?;
+    let (statuses, mut operations) = fetch_statuses(
+        src_client.as_mut(),
+        http_client.as_ref(),
+        src_statuses,
+        &echoed_identifiers,
+        config_user,
+    )
+    .await?;
+    // like、プロフィール、固定の反映は失敗しても他の処理を止めず、前回の状態のまま次回に回す
+    let likes = if !config_user.sync_likes {
+        Vec::new()
+    } else {
+        match src_client.fetch_likes().await {
+            Ok(live_likes) => {
+                let like_operations = create_like_operations(&live_likes, src_likes);
+                for operations in &mut operations {
+                    operations.extend(like_operations.iter().cloned());
+                }
+                live_likes
+                    .into_iter()
+                    .map(|live| store::user::SourceLike {
+                        target_identifier: live.target_src_identifier,
+                    })
+                    .collect()
+            }
+            Err(err) => {
+                warn!("fetch likes failed, skip syncing likes: {:?}", err);
+                src_likes.clone()
+            }
+        }
+    };
+    let profile_hash = if !config_user.sync_profile {
+        None
+    } else {
+        match fetch_profile(
+            src_client.as_mut(),
+            src_profile_hash.clone(),
+            &mut operations,
+        )
+        .await
+        {
+            Ok(profile_hash) => profile_hash,
+            Err(err) => {
+                warn!("fetch profile failed, skip syncing profile: {:?}", err);
+                src_profile_hash
+            }
+        }
+    };
+    let pinned_identifiers = if !config_user.sync_pins {
+        None
+    } else {
+        match src_client.fetch_pinned_identifiers().await {
+            Ok(live_pinned_identifiers) => {
+                if let Some(live_pinned_identifiers) = &live_pinned_identifiers {
+                    let pin_operations = create_pin_operations(
+                        live_pinned_identifiers,
+                        src_pinned_identifiers.as_deref(),
+                    );
+                    for operations in &mut operations {
+                        operations.extend(pin_operations.iter().cloned());
+                    }
+                }
+                live_pinned_identifiers
+            }
+            Err(err) => {
+                warn!("fetch pinned posts failed, skip syncing pins: {:?}", err);
+                src_pinned_identifiers
+            }
+        }
+    };
+    let sweep_offset = sweep_archived_posts(
+        src_client.as_mut(),
+        &archived_posts,
+        sweep_offset,
+        sweep_archived_posts_per_run,
+        &mut operations,
+    )
+    .await;
 
     {
         let mut store = store.lock().unwrap();
         let stored_user = store.get_or_create_user_mut(&src_account_key);
         stored_user.src.statuses = statuses;
+        stored_user.src.likes = likes;
+        stored_user.src.profile_hash = profile_hash;
+        stored_user.src.pinned_identifiers = pinned_identifiers;
+        stored_user.src.sweep_offset = sweep_offset;
     }
     trace!("new operations: {:?}", operations);
-    if operations.is_empty() && !has_users_operations {
+    let has_new_operations = operations.iter().any(|operations| !operations.is_empty());
+    if !has_new_operations && !has_users_operations {
         return Ok(None);
     }
 
-    let dst_clients = create_clients(http_client, &config_user.dsts).await?;
+    let mut dst_clients = create_dst_clients(http_client, media_fetcher, config_user).await?;
 
-    if !operations.is_empty() {
+    if has_new_operations {
         let mut store = store.lock().unwrap();
         merge_operations(&mut store, &dst_clients, &src_account_key, &operations);
     }
+    // 取り外した宛先には新しい operation を作らず、登録済みのものだけを送信する
+    dst_clients.extend(create_retired_dst_clients(http_client, media_fetcher, config_user).await?);
     Ok(Some((src_account_key, dst_clients)))
 }
 
@@ -180,23 +504,61 @@ fn necessary_repost_src_identifiers(users: &[store::user::User]) -> Vec<String>
         .collect()
 }
 
-pub async fn retain_all_dst_statuses(store: &mut store::Store) -> Result<()> {
+fn necessary_like_src_identifiers(users: &[store::user::User]) -> Vec<String> {
+    users
+        .iter()
+        .flat_map(|user| user.src.likes.iter())
+        .map(|like| like.target_identifier.clone())
+        .collect()
+}
+
+/** 取得範囲から外れた status との対応は archive に移す */
+pub async fn retain_all_dst_statuses(
+    store: &mut store::Store,
+    archive_retention: Duration,
+) -> Result<()> {
     let necessary_post_src_identifiers = necessary_post_src_identifiers(&store.users);
     let necessary_repost_src_identifiers = necessary_repost_src_identifiers(&store.users);
+    let necessary_like_src_identifiers = necessary_like_src_identifiers(&store.users);
 
-    store
-        .users
-        .iter_mut()
-        .flat_map(|user| user.dsts.iter_mut())
-        .for_each(|dst| {
-            dst.statuses.retain(|status| match status {
-                store::user::DestinationStatus::Post(post) => {
-                    necessary_post_src_identifiers.contains(&post.src_identifier)
-                }
-                store::user::DestinationStatus::Repost(repost) => {
-                    necessary_repost_src_identifiers.contains(&repost.src_identifier)
-                }
-            });
-        });
+    let now = Utc::now().fixed_offset();
+    let archive = &mut store.archive;
+    for user in &mut store.users {
+        let src_key = AccountKey {
+            origin: user.src.origin.clone(),
+            identifier: user.src.identifier.clone(),
+        };
+        for dst in &mut user.dsts {
+            let account_pair = store::operations::AccountPair::from_keys(
+                src_key.clone(),
+                AccountKey {
+                    origin: dst.origin.clone(),
+                    identifier: dst.identifier.clone(),
+                },
+            );
+            let (retained, archived): (Vec<_>, Vec<_>) = std::mem::take(&mut dst.statuses)
+                .into_iter()
+                .partition(|status| match status {
+                    store::user::DestinationStatus::Post(post) => {
+                        necessary_post_src_identifiers.contains(&post.src_identifier)
+                    }
+                    store::user::DestinationStatus::Repost(repost) => {
+                        necessary_repost_src_identifiers.contains(&repost.src_identifier)
+                    }
+                    store::user::DestinationStatus::Like(like) => {
+                        necessary_like_src_identifiers.contains(&like.src_identifier)
+                    }
+                });
+            dst.statuses = retained;
+            for status in archived.into_iter().rev() {
+                archive.insert(ArchivedStatus {
+                    account_pair: account_pair.clone(),
+                    status,
+                    archived_at: now,
+                });
+            }
+        }
+    }
+    archive.prune(now, archive_retention);
     Ok(())
 }
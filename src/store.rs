@@ -1,20 +1,41 @@
+pub mod archive;
+pub mod jobs;
 pub mod operations;
+pub mod usage;
 pub mod user;
 
+use std::mem::discriminant;
+
 use serde::{Deserialize, Serialize};
+use tracing::info;
 
-use crate::app::AccountKey;
+use crate::{app::AccountKey, sources::merge_operations::sort_operations};
 
 use self::{
+    archive::Archive,
+    jobs::Job,
     operations::{AccountPair, Operation},
-    user::{Destination, Source, User},
+    usage::Usage,
+    user::{
+        Destination, DestinationStatus, Fingerprint, ResolvedStatus, Source, SourceStatus, User,
+    },
 };
 
 #[derive(Clone, Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Store {
+    /** 楽観的排他制御のためのバージョン。保存先の Database が管理する */
+    #[serde(skip)]
+    pub version: u64,
     pub users: Vec<User>,
     pub operations: Vec<Operation>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub jobs: Vec<Job>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub usages: Vec<Usage>,
+    /** store とは別に保存する */
+    #[serde(skip)]
+    pub archive: Archive,
 }
 
 impl Store {
@@ -30,6 +51,10 @@ impl Store {
                 origin: account_key.origin.clone(),
                 identifier: account_key.identifier.clone(),
                 statuses: Vec::default(),
+                likes: Vec::default(),
+                profile_hash: None,
+                pinned_identifiers: None,
+                sweep_offset: 0,
             },
             dsts: Vec::default(),
         });
@@ -43,4 +68,420 @@ impl Store {
         self.get_or_create_user_mut(&account_pair.to_src_key())
             .get_or_create_dst_mut(&account_pair.to_dst_key())
     }
+
+    pub fn get_or_create_usage_mut<'a>(&'a mut self, account_key: &AccountKey) -> &'a mut Usage {
+        let idx = self.usages.iter().position(|usage| {
+            usage.origin == account_key.origin && usage.identifier == account_key.identifier
+        });
+        if let Some(idx) = idx {
+            return &mut self.usages[idx];
+        }
+        self.usages.push(Usage {
+            origin: account_key.origin.clone(),
+            identifier: account_key.identifier.clone(),
+            counters: Vec::default(),
+        });
+        self.usages.last_mut().unwrap()
+    }
+
+    /** account_key のアカウントに、送信先として作った status の identifier */
+    pub fn echoed_identifiers(&self, account_key: &AccountKey) -> Vec<String> {
+        self.users
+            .iter()
+            .flat_map(|user| &user.dsts)
+            .filter(|dst| {
+                dst.origin == account_key.origin && dst.identifier == account_key.identifier
+            })
+            .flat_map(|dst| &dst.statuses)
+            .map(|status| status.identifier_as_src())
+            .collect()
+    }
+
+    /**
+     * ジョブの operation を per_run 件ずつ送信キューに積む。
+     * 前回積んだ分が残っている AccountPair には積まない
+     */
+    pub fn release_jobs(&mut self, per_run: usize) {
+        for job in &mut self.jobs {
+            if self
+                .operations
+                .iter()
+                .any(|operation| operation.account_pair() == &job.account_pair)
+            {
+                continue;
+            }
+            let len = per_run.min(job.operations.len());
+            self.operations.extend(job.operations.drain(..len));
+            info!(
+                "{:?} job progress: {}/{}",
+                job.kind,
+                job.total - job.operations.len(),
+                job.total
+            );
+        }
+        self.jobs.retain(|job| !job.operations.is_empty());
+        sort_operations(&mut self.operations);
+    }
+
+    /**
+     * 自分が送信キューに積んだジョブの operation を相手側のジョブからも取り除き、
+     * 自分が作ったジョブを追加する
+     */
+    fn rebase_jobs(&self, base: &Store, theirs: &mut Store) {
+        let job_operations = |jobs: &[Job]| -> Vec<Operation> {
+            jobs.iter().flat_map(|job| job.operations.clone()).collect()
+        };
+        let ours = job_operations(&self.jobs);
+        let released: Vec<_> = job_operations(&base.jobs)
+            .into_iter()
+            .filter(|base_op| !ours.iter().any(|op| op.is_same(base_op)))
+            .collect();
+        for job in &mut theirs.jobs {
+            job.operations
+                .retain(|their_op| !released.iter().any(|op| op.is_same(their_op)));
+        }
+        theirs.jobs.retain(|job| !job.operations.is_empty());
+        let in_base = job_operations(&base.jobs);
+        let added = self.jobs.iter().filter(|job| {
+            !job.operations
+                .iter()
+                .any(|op| in_base.iter().any(|base_op| base_op.is_same(op)))
+        });
+        theirs.jobs.extend(added.cloned());
+    }
+
+    /**
+     * 他の実行が先に commit した store (theirs) に、base から自分が行った変更を載せ直す
+     */
+    pub fn rebase(&mut self, base: &Store, mut theirs: Store) {
+        let their_archive = std::mem::take(&mut theirs.archive);
+        self.archive.rebase(&base.archive, their_archive);
+        theirs.archive = std::mem::take(&mut self.archive);
+
+        // 自分が処理した operation は相手側からも取り除き、自分が積んだ operation は追加する
+        let done: Vec<_> = base
+            .operations
+            .iter()
+            .filter(|base_op| !self.operations.iter().any(|op| op.is_same(base_op)))
+            .collect();
+        theirs
+            .operations
+            .retain(|their_op| !done.iter().any(|done_op| done_op.is_same(their_op)));
+        let added: Vec<_> = self
+            .operations
+            .iter()
+            .filter(|op| !base.operations.iter().any(|base_op| base_op.is_same(op)))
+            .filter(|op| {
+                !theirs
+                    .operations
+                    .iter()
+                    .any(|their_op| their_op.is_same(op))
+            })
+            .cloned()
+            .collect();
+        theirs.operations.extend(added);
+        sort_operations(&mut theirs.operations);
+        self.rebase_jobs(base, &mut theirs);
+
+        for user in &self.users {
+            let src_key = AccountKey {
+                origin: user.src.origin.clone(),
+                identifier: user.src.identifier.clone(),
+            };
+            let their_user = theirs.get_or_create_user_mut(&src_key);
+            // 取得済みの状態はより新しい方を採用する
+            let newest = |statuses: &[SourceStatus]| {
+                statuses.iter().map(SourceStatus::created_at).max().copied()
+            };
+            if newest(&user.src.statuses) > newest(&their_user.src.statuses) {
+                their_user.src.statuses = user.src.statuses.clone();
+            }
+            let base_user = base.users.iter().find(|base_user| {
+                base_user.src.origin == user.src.origin
+                    && base_user.src.identifier == user.src.identifier
+            });
+            let base_likes = base_user.map_or(&[][..], |base_user| &base_user.src.likes);
+            if user.src.likes != base_likes {
+                their_user.src.likes = user.src.likes.clone();
+            }
+            if user.src.profile_hash.is_some() {
+                their_user.src.profile_hash = user.src.profile_hash.clone();
+            }
+            if user.src.pinned_identifiers.is_some() {
+                their_user.src.pinned_identifiers = user.src.pinned_identifiers.clone();
+            }
+            // 確認の位置は自分が進めていればそれを採用する
+            if base_user.map_or(0, |base_user| base_user.src.sweep_offset) != user.src.sweep_offset
+            {
+                their_user.src.sweep_offset = user.src.sweep_offset;
+            }
+            for dst in &user.dsts {
+                let dst_key = AccountKey {
+                    origin: dst.origin.clone(),
+                    identifier: dst.identifier.clone(),
+                };
+                let base_dst = base_user.and_then(|base_user| {
+                    base_user.dsts.iter().find(|base_dst| {
+                        base_dst.origin == dst.origin && base_dst.identifier == dst.identifier
+                    })
+                });
+                let their_dst = their_user.get_or_create_dst_mut(&dst_key);
+                if base_dst.map_or(0, |base_dst| base_dst.reconcile_offset) != dst.reconcile_offset
+                {
+                    their_dst.reconcile_offset = dst.reconcile_offset;
+                }
+                // like は対象と同じ identifier になる場合がある
+                let is_same_status = |a: &DestinationStatus, b: &DestinationStatus| {
+                    discriminant(a) == discriminant(b) && a.identifier() == b.identifier()
+                };
+                // base にあって自分が取り除いたものは、相手側からも取り除く
+                if let Some(base_dst) = base_dst {
+                    let removed = |status: &DestinationStatus| {
+                        base_dst
+                            .statuses
+                            .iter()
+                            .any(|base_status| is_same_status(base_status, status))
+                            && !dst
+                                .statuses
+                                .iter()
+                                .any(|own_status| is_same_status(own_status, status))
+                    };
+                    their_dst.statuses.retain(|status| !removed(status));
+                    their_dst.resolved_statuses.retain(|resolved_status| {
+                        let has = |statuses: &[ResolvedStatus]| {
+                            statuses.iter().any(|x| x.uri == resolved_status.uri)
+                        };
+                        !has(&base_dst.resolved_statuses) || has(&dst.resolved_statuses)
+                    });
+                    their_dst.fingerprints.retain(|fingerprint| {
+                        let has = |fingerprints: &[Fingerprint]| {
+                            fingerprints
+                                .iter()
+                                .any(|x| x.identifier == fingerprint.identifier)
+                        };
+                        !has(&base_dst.fingerprints) || has(&dst.fingerprints)
+                    });
+                }
+                // 自分が追加したものを載せる。base にあったものは相手側が取り除いていれば戻さない
+                for status in dst.statuses.iter().rev() {
+                    let in_base = base_dst.is_some_and(|base_dst| {
+                        base_dst
+                            .statuses
+                            .iter()
+                            .any(|base_status| is_same_status(base_status, status))
+                    });
+                    if !in_base
+                        && !their_dst
+                            .statuses
+                            .iter()
+                            .any(|their_status| is_same_status(their_status, status))
+                    {
+                        their_dst.statuses.insert(0, status.clone());
+                    }
+                }
+                for resolved_status in dst.resolved_statuses.iter().rev() {
+                    let has = |statuses: &[ResolvedStatus]| {
+                        statuses.iter().any(|x| x.uri == resolved_status.uri)
+                    };
+                    if !base_dst.is_some_and(|base_dst| has(&base_dst.resolved_statuses))
+                        && !has(&their_dst.resolved_statuses)
+                    {
+                        their_dst.insert_resolved_status(resolved_status.clone());
+                    }
+                }
+                for fingerprint in dst.fingerprints.iter().rev() {
+                    let has = |fingerprints: &[Fingerprint]| {
+                        fingerprints
+                            .iter()
+                            .any(|x| x.identifier == fingerprint.identifier)
+                    };
+                    if !base_dst.is_some_and(|base_dst| has(&base_dst.fingerprints))
+                        && !has(&their_dst.fingerprints)
+                    {
+                        their_dst.fingerprints.insert(0, fingerprint.clone());
+                    }
+                }
+            }
+        }
+        // 送信数は base から自分が増やした分を足す
+        for usage in &self.usages {
+            let dst_key = AccountKey {
+                origin: usage.origin.clone(),
+                identifier: usage.identifier.clone(),
+            };
+            let base_usage = base.usages.iter().find(|base_usage| {
+                base_usage.origin == usage.origin && base_usage.identifier == usage.identifier
+            });
+            let their_usage = theirs.get_or_create_usage_mut(&dst_key);
+            for counter in &usage.counters {
+                let base_count = base_usage
+                    .and_then(|base_usage| {
+                        base_usage
+                            .counters
+                            .iter()
+                            .find(|base_counter| base_counter.minute == counter.minute)
+                    })
+                    .map_or(0, |base_counter| base_counter.count);
+                if counter.count > base_count {
+                    their_usage.add(counter.minute, counter.count - base_count);
+                }
+            }
+        }
+        *self = theirs;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::{
+        archive::ArchivedStatus,
+        operations::{DeletePostOperation, DeletePostOperationStatus},
+        user::{DestinationPost, DestinationStatus},
+        *,
+    };
+
+    fn account_pair() -> AccountPair {
+        AccountPair {
+            src_origin: "https://src.example".into(),
+            src_account_identifier: "src".into(),
+            dst_origin: "https://dst.example".into(),
+            dst_account_identifier: "dst".into(),
+        }
+    }
+
+    fn delete_post(src_identifier: &str) -> Operation {
+        Operation::DeletePost(DeletePostOperation {
+            account_pair: account_pair(),
+            status: DeletePostOperationStatus {
+                src_identifier: src_identifier.into(),
+            },
+        })
+    }
+
+    fn job(src_identifiers: &[&str]) -> Job {
+        Job {
+            kind: jobs::JobKind::Retract,
+            account_pair: account_pair(),
+            operations: src_identifiers.iter().map(|id| delete_post(id)).collect(),
+            total: src_identifiers.len(),
+        }
+    }
+
+    fn src_identifiers(operations: &[Operation]) -> Vec<&str> {
+        let mut ids: Vec<_> = operations.iter().map(Operation::src_identifier).collect();
+        ids.sort();
+        ids
+    }
+
+    fn archived(src_identifier: &str) -> ArchivedStatus {
+        ArchivedStatus {
+            account_pair: account_pair(),
+            status: DestinationStatus::Post(DestinationPost {
+                identifier: format!("dst-{}", src_identifier),
+                src_identifier: src_identifier.into(),
+                src_uri: String::new(),
+                created_at: None,
+                degraded_media: Vec::new(),
+            }),
+            archived_at: DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap(),
+        }
+    }
+
+    #[test]
+    fn rebase_keeps_job_created_by_this_run() {
+        let base = Store::default();
+        let mut ours = base.clone();
+        ours.jobs.push(job(&["a", "b"]));
+        let mut theirs = base.clone();
+        theirs.operations.push(delete_post("x"));
+
+        ours.rebase(&base, theirs);
+
+        assert_eq!(ours.jobs.len(), 1);
+        assert_eq!(src_identifiers(&ours.jobs[0].operations), ["a", "b"]);
+        assert_eq!(src_identifiers(&ours.operations), ["x"]);
+    }
+
+    #[test]
+    fn rebase_does_not_release_job_operations_twice() {
+        let mut base = Store::default();
+        base.jobs.push(job(&["a", "b", "c"]));
+        let mut ours = base.clone();
+        ours.release_jobs(2);
+        let mut theirs = base.clone();
+        theirs.operations.push(delete_post("x"));
+
+        ours.rebase(&base, theirs);
+
+        assert_eq!(src_identifiers(&ours.operations), ["a", "b", "x"]);
+        assert_eq!(ours.jobs.len(), 1);
+        assert_eq!(src_identifiers(&ours.jobs[0].operations), ["c"]);
+    }
+
+    #[test]
+    fn rebase_keeps_advanced_offsets() {
+        let mut base = Store::default();
+        base.get_or_create_dst_mut(&account_pair());
+        let mut ours = base.clone();
+        ours.users[0].src.sweep_offset = 5;
+        ours.users[0].dsts[0].reconcile_offset = 3;
+        let theirs = base.clone();
+
+        ours.rebase(&base, theirs);
+
+        assert_eq!(ours.users[0].src.sweep_offset, 5);
+        assert_eq!(ours.users[0].dsts[0].reconcile_offset, 3);
+    }
+
+    #[test]
+    fn rebase_applies_archive_additions_and_removals() {
+        let mut base = Store::default();
+        base.archive.insert(archived("removed"));
+        base.archive.insert(archived("kept"));
+        let mut ours = base.clone();
+        ours.archive.remove(&account_pair(), |status| {
+            status.identifier() == "dst-removed"
+        });
+        ours.archive.insert(archived("ours"));
+        let mut theirs = base.clone();
+        theirs.archive.insert(archived("theirs"));
+
+        ours.rebase(&base, theirs);
+
+        let mut ids: Vec<_> = ours
+            .archive
+            .statuses
+            .iter()
+            .map(|archived| archived.status.identifier())
+            .collect();
+        ids.sort();
+        assert_eq!(ids, ["dst-kept", "dst-ours", "dst-theirs"]);
+    }
+
+    #[test]
+    fn rebase_keeps_mappings_removed_by_this_run_removed() {
+        let mut base = Store::default();
+        let dst = base.get_or_create_dst_mut(&account_pair());
+        dst.statuses.push(archived("deleted").status);
+        dst.statuses.push(archived("kept").status);
+        let mut ours = base.clone();
+        ours.users[0].dsts[0]
+            .statuses
+            .retain(|status| status.identifier() != "dst-deleted");
+        let mut theirs = base.clone();
+        theirs.users[0].dsts[0]
+            .statuses
+            .insert(0, archived("theirs").status);
+
+        ours.rebase(&base, theirs);
+
+        let ids: Vec<_> = ours.users[0].dsts[0]
+            .statuses
+            .iter()
+            .map(DestinationStatus::identifier)
+            .collect();
+        assert_eq!(ids, ["dst-theirs", "dst-kept"]);
+    }
 }
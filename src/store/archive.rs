@@ -0,0 +1,89 @@
+use std::mem::discriminant;
+
+use chrono::{DateTime, Duration, FixedOffset};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::format_rfc3339;
+
+use super::{operations::AccountPair, user::DestinationStatus};
+
+/** 作業用の statuses から外した、送信済みの status との対応 */
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchivedStatus {
+    pub account_pair: AccountPair,
+    pub status: DestinationStatus,
+    #[serde(with = "format_rfc3339")]
+    pub archived_at: DateTime<FixedOffset>,
+}
+
+/**
+ * 取得範囲から外れた src の status と宛先の status の対応。
+ * store とは別に保存し、保持期間を過ぎたものは捨てる
+ */
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Archive {
+    /** 新しい順に並ぶ */
+    pub statuses: Vec<ArchivedStatus>,
+}
+
+impl Archive {
+    pub fn statuses<'a>(
+        &'a self,
+        src_origin: &'a str,
+        dst_origin: &'a str,
+    ) -> impl Iterator<Item = &'a DestinationStatus> {
+        self.statuses
+            .iter()
+            .filter(move |archived| {
+                archived.account_pair.src_origin == src_origin
+                    && archived.account_pair.dst_origin == dst_origin
+            })
+            .map(|archived| &archived.status)
+    }
+
+    fn contains(&self, account_pair: &AccountPair, status: &DestinationStatus) -> bool {
+        self.statuses.iter().any(|archived| {
+            &archived.account_pair == account_pair
+                && discriminant(&archived.status) == discriminant(status)
+                && archived.status.identifier() == status.identifier()
+        })
+    }
+
+    pub fn insert(&mut self, status: ArchivedStatus) {
+        if self.contains(&status.account_pair, &status.status) {
+            return;
+        }
+        self.statuses.insert(0, status);
+    }
+
+    /** account_pair の対応のうち、f を満たすものを捨てる */
+    pub fn remove(
+        &mut self,
+        account_pair: &AccountPair,
+        mut f: impl FnMut(&DestinationStatus) -> bool,
+    ) {
+        self.statuses
+            .retain(|archived| &archived.account_pair != account_pair || !f(&archived.status));
+    }
+
+    pub fn prune(&mut self, now: DateTime<FixedOffset>, retention: Duration) {
+        self.statuses
+            .retain(|archived| archived.archived_at >= now - retention);
+    }
+
+    /** 他の実行が保存した archive に、自分が追加したものと取り除いたものを反映する */
+    pub fn rebase(&mut self, base: &Archive, mut theirs: Archive) {
+        theirs.statuses.retain(|archived| {
+            !base.contains(&archived.account_pair, &archived.status)
+                || self.contains(&archived.account_pair, &archived.status)
+        });
+        for archived in self.statuses.iter().rev() {
+            if !base.contains(&archived.account_pair, &archived.status) {
+                theirs.insert(archived.clone());
+            }
+        }
+        *self = theirs;
+    }
+}
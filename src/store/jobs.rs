@@ -0,0 +1,21 @@
+use serde::{Deserialize, Serialize};
+
+use super::operations::{AccountPair, Operation};
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum JobKind {
+    Backfill,
+    Retract,
+}
+
+/** 一度に送信せず、実行ごとに少しずつ送信キューに積む operation の集まり */
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Job {
+    pub kind: JobKind,
+    pub account_pair: AccountPair,
+    /** 送信する順に並ぶ */
+    pub operations: Vec<Operation>,
+    pub total: usize,
+}
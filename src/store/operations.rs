@@ -1,9 +1,12 @@
 use std::ops::Range;
 
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, Duration, FixedOffset};
 use serde::{Deserialize, Serialize};
 
-use crate::{app::AccountKey, utils::format_rfc3339};
+use crate::{
+    app::AccountKey,
+    utils::{fnv1a, format_rfc3339, format_rfc3339_option},
+};
 
 #[derive(Clone, Deserialize, Eq, Hash, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -79,6 +82,10 @@ pub struct CreatePostOperationStatus {
     #[serde(default)]
     pub facets: Vec<Facet>,
     pub reply_src_identifier: Option<String>,
+    /** 取得範囲外の返信先の uri */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub reply_src_uri: Option<String>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
     pub media: Vec<Medium>,
@@ -89,11 +96,19 @@ pub struct CreatePostOperationStatus {
 }
 
 #[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct CreatePostOperation {
     #[serde(flatten)]
     pub account_pair: AccountPair,
     #[serde(flatten)]
     pub status: CreatePostOperationStatus,
+    /** これより前には送信しない */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(with = "format_rfc3339_option")]
+    pub not_before: Option<DateTime<FixedOffset>>,
+    /** メディアを転送できずに失敗した回数 */
+    #[serde(default, skip_serializing_if = "super::user::is_zero")]
+    pub media_failures: usize,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -102,6 +117,9 @@ pub struct CreateRepostOperationStatus {
     pub src_identifier: String,
     pub target_src_identifier: String,
     pub target_src_uri: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub target_content: Option<String>,
     #[serde(with = "format_rfc3339")]
     pub created_at: DateTime<FixedOffset>,
 }
@@ -113,6 +131,10 @@ pub struct CreateRepostOperation {
     pub account_pair: AccountPair,
     #[serde(flatten)]
     pub status: CreateRepostOperationStatus,
+    /** これより前には送信しない */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(with = "format_rfc3339_option")]
+    pub not_before: Option<DateTime<FixedOffset>>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -163,6 +185,107 @@ pub struct DeleteRepostOperation {
     pub status: DeleteRepostOperationStatus,
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateLikeOperationStatus {
+    pub target_src_identifier: String,
+    pub target_src_uri: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateLikeOperation {
+    #[serde(flatten)]
+    pub account_pair: AccountPair,
+    #[serde(flatten)]
+    pub status: CreateLikeOperationStatus,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteLikeOperationStatus {
+    pub target_src_identifier: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteLikeOperation {
+    #[serde(flatten)]
+    pub account_pair: AccountPair,
+    #[serde(flatten)]
+    pub status: DeleteLikeOperationStatus,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PinPostOperationStatus {
+    pub src_identifier: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PinPostOperation {
+    #[serde(flatten)]
+    pub account_pair: AccountPair,
+    #[serde(flatten)]
+    pub status: PinPostOperationStatus,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnpinPostOperationStatus {
+    pub src_identifier: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnpinPostOperation {
+    #[serde(flatten)]
+    pub account_pair: AccountPair,
+    #[serde(flatten)]
+    pub status: UnpinPostOperationStatus,
+}
+
+/** None の項目は宛先で変更しない */
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub banner_url: Option<String>,
+}
+
+impl Profile {
+    pub fn hash(&self) -> String {
+        format!(
+            "{:016x}",
+            fnv1a(None, &serde_json::to_vec(self).unwrap_or_default())
+        )
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateProfileOperationStatus {
+    /** 取得元のプロフィールの Profile::hash */
+    pub hash: String,
+    pub profile: Profile,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateProfileOperation {
+    #[serde(flatten)]
+    pub account_pair: AccountPair,
+    #[serde(flatten)]
+    pub status: UpdateProfileOperationStatus,
+}
+
 #[derive(Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 #[serde(tag = "operation")]
@@ -172,6 +295,11 @@ pub enum Operation {
     UpdatePost(UpdatePostOperation),
     DeletePost(DeletePostOperation),
     DeleteRepost(DeleteRepostOperation),
+    CreateLike(CreateLikeOperation),
+    DeleteLike(DeleteLikeOperation),
+    UpdateProfile(UpdateProfileOperation),
+    PinPost(PinPostOperation),
+    UnpinPost(UnpinPostOperation),
 }
 
 impl Operation {
@@ -181,7 +309,137 @@ impl Operation {
             | Operation::CreateRepost(CreateRepostOperation { account_pair, .. })
             | Operation::UpdatePost(UpdatePostOperation { account_pair, .. })
             | Operation::DeletePost(DeletePostOperation { account_pair, .. })
-            | Operation::DeleteRepost(DeleteRepostOperation { account_pair, .. }) => account_pair,
+            | Operation::DeleteRepost(DeleteRepostOperation { account_pair, .. })
+            | Operation::CreateLike(CreateLikeOperation { account_pair, .. })
+            | Operation::DeleteLike(DeleteLikeOperation { account_pair, .. })
+            | Operation::UpdateProfile(UpdateProfileOperation { account_pair, .. })
+            | Operation::PinPost(PinPostOperation { account_pair, .. })
+            | Operation::UnpinPost(UnpinPostOperation { account_pair, .. }) => account_pair,
+        }
+    }
+
+    /** like は対象の status の identifier、プロフィールはその hash */
+    pub fn src_identifier(&self) -> &str {
+        match self {
+            Operation::CreatePost(CreatePostOperation { status, .. }) => &status.src_identifier,
+            Operation::CreateRepost(CreateRepostOperation { status, .. }) => &status.src_identifier,
+            Operation::UpdatePost(UpdatePostOperation { status, .. }) => &status.src_identifier,
+            Operation::DeletePost(DeletePostOperation { status, .. }) => &status.src_identifier,
+            Operation::DeleteRepost(DeleteRepostOperation { status, .. }) => &status.src_identifier,
+            Operation::CreateLike(CreateLikeOperation { status, .. }) => {
+                &status.target_src_identifier
+            }
+            Operation::DeleteLike(DeleteLikeOperation { status, .. }) => {
+                &status.target_src_identifier
+            }
+            Operation::UpdateProfile(UpdateProfileOperation { status, .. }) => &status.hash,
+            Operation::PinPost(PinPostOperation { status, .. }) => &status.src_identifier,
+            Operation::UnpinPost(UnpinPostOperation { status, .. }) => &status.src_identifier,
+        }
+    }
+
+    /** この operation より先に処理されている必要がある投稿 (CreatePost) の src_identifier */
+    pub fn dependency(&self) -> Option<&str> {
+        match self {
+            Operation::CreatePost(CreatePostOperation { status, .. }) => {
+                status.reply_src_identifier.as_deref()
+            }
+            Operation::CreateRepost(CreateRepostOperation { status, .. }) => {
+                Some(&status.target_src_identifier)
+            }
+            Operation::CreateLike(CreateLikeOperation { status, .. }) => {
+                Some(&status.target_src_identifier)
+            }
+            Operation::PinPost(PinPostOperation { status, .. }) => Some(&status.src_identifier),
+            Operation::UpdatePost(_)
+            | Operation::DeletePost(_)
+            | Operation::DeleteRepost(_)
+            | Operation::DeleteLike(_)
+            | Operation::UpdateProfile(_)
+            | Operation::UnpinPost(_) => None,
         }
     }
+
+    pub fn depends_on(&self, other: &Operation) -> bool {
+        let Operation::CreatePost(other) = other else {
+            return false;
+        };
+        self.account_pair() == &other.account_pair
+            && self.dependency() == Some(other.status.src_identifier.as_str())
+    }
+
+    /** other が作るものを、同じ status について更新または取り消す operation か */
+    pub fn undoes(&self, other: &Operation) -> bool {
+        let is_pair = matches!(
+            (self, other),
+            (
+                Operation::UpdatePost(_) | Operation::DeletePost(_),
+                Operation::CreatePost(_)
+            ) | (Operation::DeleteRepost(_), Operation::CreateRepost(_))
+                | (Operation::DeleteLike(_), Operation::CreateLike(_))
+                | (Operation::UnpinPost(_), Operation::PinPost(_))
+        );
+        is_pair
+            && self.account_pair() == other.account_pair()
+            && self.src_identifier() == other.src_identifier()
+    }
+
+    pub fn not_before(&self) -> Option<&DateTime<FixedOffset>> {
+        match self {
+            Operation::CreatePost(CreatePostOperation { not_before, .. })
+            | Operation::CreateRepost(CreateRepostOperation { not_before, .. }) => {
+                not_before.as_ref()
+            }
+            Operation::UpdatePost(_)
+            | Operation::DeletePost(_)
+            | Operation::DeleteRepost(_)
+            | Operation::CreateLike(_)
+            | Operation::DeleteLike(_)
+            | Operation::UpdateProfile(_)
+            | Operation::PinPost(_)
+            | Operation::UnpinPost(_) => None,
+        }
+    }
+
+    /** 宛先で新しく status が作られる。送信数の上限や静かな時間帯の対象になる */
+    pub fn is_post(&self) -> bool {
+        match self {
+            Operation::CreatePost(_) | Operation::CreateRepost(_) => true,
+            Operation::UpdatePost(_)
+            | Operation::DeletePost(_)
+            | Operation::DeleteRepost(_)
+            | Operation::CreateLike(_)
+            | Operation::DeleteLike(_)
+            | Operation::UpdateProfile(_)
+            | Operation::PinPost(_)
+            | Operation::UnpinPost(_) => false,
+        }
+    }
+
+    /** 投稿から delay が経つまで送信しないようにする */
+    pub fn delay(&mut self, delay: Duration) {
+        match self {
+            Operation::CreatePost(CreatePostOperation {
+                status, not_before, ..
+            }) => *not_before = Some(status.created_at + delay),
+            Operation::CreateRepost(CreateRepostOperation {
+                status, not_before, ..
+            }) => *not_before = Some(status.created_at + delay),
+            Operation::UpdatePost(_)
+            | Operation::DeletePost(_)
+            | Operation::DeleteRepost(_)
+            | Operation::CreateLike(_)
+            | Operation::DeleteLike(_)
+            | Operation::UpdateProfile(_)
+            | Operation::PinPost(_)
+            | Operation::UnpinPost(_) => {}
+        }
+    }
+
+    /** 同じ宛先への同じ種類の同じ status に対する operation か */
+    pub fn is_same(&self, other: &Operation) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+            && self.account_pair() == other.account_pair()
+            && self.src_identifier() == other.src_identifier()
+    }
 }
@@ -0,0 +1,89 @@
+use chrono::{DateTime, Duration, DurationRound, FixedOffset};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::format_rfc3339;
+
+/** 1分ごとの送信数 */
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageCounter {
+    /** 集計する時間の始まり。以前は1時間ごとに集計していた */
+    #[serde(alias = "hour", with = "format_rfc3339")]
+    pub minute: DateTime<FixedOffset>,
+    pub count: u32,
+}
+
+/** 宛先のアカウントごとの、新しく作った status の数 */
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Usage {
+    pub origin: String,
+    pub identifier: String,
+    /** 新しい順に並ぶ。24時間より前のものは捨てる */
+    pub counters: Vec<UsageCounter>,
+}
+
+fn to_minute(at: &DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+    at.duration_trunc(Duration::minutes(1)).unwrap()
+}
+
+impl Usage {
+    /** now までの period の間の送信数 */
+    fn count_in(&self, now: &DateTime<FixedOffset>, period: Duration) -> u32 {
+        let since = *now - period;
+        self.counters
+            .iter()
+            .filter(|counter| counter.minute > since)
+            .map(|counter| counter.count)
+            .sum()
+    }
+
+    pub fn count_in_hour(&self, now: &DateTime<FixedOffset>) -> u32 {
+        self.count_in(now, Duration::hours(1))
+    }
+
+    pub fn count_in_day(&self, now: &DateTime<FixedOffset>) -> u32 {
+        self.count_in(now, Duration::days(1))
+    }
+
+    pub fn add(&mut self, at: DateTime<FixedOffset>, count: u32) {
+        let minute = to_minute(&at);
+        if let Some(counter) = self.counters.iter_mut().find(|x| x.minute == minute) {
+            counter.count += count;
+        } else {
+            let idx = self
+                .counters
+                .iter()
+                .position(|x| x.minute < minute)
+                .unwrap_or(self.counters.len());
+            self.counters.insert(idx, UsageCounter { minute, count });
+        }
+        let since = self.counters[0].minute - Duration::days(1);
+        self.counters.retain(|counter| counter.minute > since);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(s).unwrap()
+    }
+
+    #[test]
+    fn count_in_hour_is_rolling() {
+        let mut usage = Usage {
+            origin: "https://example.com".to_owned(),
+            identifier: "user".to_owned(),
+            counters: vec![],
+        };
+        usage.add(at("2024-01-01T00:50:00Z"), 2);
+        usage.add(at("2024-01-01T01:10:00Z"), 1);
+
+        assert_eq!(usage.count_in_hour(&at("2024-01-01T01:20:00Z")), 3);
+        assert_eq!(usage.count_in_hour(&at("2024-01-01T01:55:00Z")), 1);
+        assert_eq!(usage.count_in_day(&at("2024-01-01T23:55:00Z")), 3);
+        assert_eq!(usage.count_in_day(&at("2024-01-02T00:55:00Z")), 1);
+    }
+}
@@ -1,7 +1,11 @@
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, Duration, FixedOffset};
 use serde::{Deserialize, Serialize};
 
-use crate::{app::AccountKey, sources::source, utils::format_rfc3339};
+use crate::{
+    app::AccountKey,
+    sources::source,
+    utils::{format_rfc3339, format_rfc3339_option},
+};
 
 #[derive(Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -64,12 +68,30 @@ impl From<source::LiveStatus> for SourceStatus {
     }
 }
 
+/** like した status。新しい順に並ぶ */
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceLike {
+    pub target_identifier: String,
+}
+
 #[derive(Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Source {
     pub origin: String,
     pub identifier: String,
     pub statuses: Vec<SourceStatus>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub likes: Vec<SourceLike>,
+    /** 最後に取得したプロフィールの Profile::hash */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile_hash: Option<String>,
+    /** 固定している post の identifier。未取得なら None */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pinned_identifiers: Option<Vec<String>>,
+    /** 次に取得元での削除を確認する、archive にある post の位置 */
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub sweep_offset: usize,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -78,6 +100,13 @@ pub struct DestinationPost {
     pub identifier: String,
     pub src_identifier: String,
     pub src_uri: String,
+    /** 取得元での作成日時。古いデータには無い */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(with = "format_rfc3339_option")]
+    pub created_at: Option<DateTime<FixedOffset>>,
+    /** 転送できずに除いたメディアの URL。添付画像は本文にリンクを付けている */
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub degraded_media: Vec<String>,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -85,6 +114,21 @@ pub struct DestinationPost {
 pub struct DestinationRepost {
     pub identifier: String,
     pub src_identifier: String,
+    /** repost の代わりに投稿した */
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub is_fallback_post: bool,
+    /** 取得元での作成日時。古いデータには無い */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(with = "format_rfc3339_option")]
+    pub created_at: Option<DateTime<FixedOffset>>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DestinationLike {
+    pub identifier: String,
+    /** like した status の取得元での identifier */
+    pub src_identifier: String,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -92,6 +136,47 @@ pub struct DestinationRepost {
 pub enum DestinationStatus {
     Post(DestinationPost),
     Repost(DestinationRepost),
+    Like(DestinationLike),
+}
+
+impl DestinationStatus {
+    pub fn identifier(&self) -> &str {
+        match self {
+            DestinationStatus::Post(DestinationPost { identifier, .. })
+            | DestinationStatus::Repost(DestinationRepost { identifier, .. })
+            | DestinationStatus::Like(DestinationLike { identifier, .. }) => identifier,
+        }
+    }
+
+    /**
+     * 宛先のアカウントを取得元として取得したときの identifier。
+     * at protocol の identifier は uri と cid の JSON なので cid を取り出す
+     */
+    pub fn identifier_as_src(&self) -> String {
+        let identifier = self.identifier();
+        serde_json::from_str::<serde_json::Value>(identifier)
+            .ok()
+            .and_then(|json| json.get("cid")?.as_str().map(str::to_owned))
+            .unwrap_or_else(|| identifier.to_owned())
+    }
+}
+
+/** 他のサーバーの status を宛先で解決した結果 */
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolvedStatus {
+    pub uri: String,
+    pub identifier: String,
+}
+
+/** 重複の検出に使う、送信済みの投稿の指紋 */
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Fingerprint {
+    pub hash: String,
+    pub identifier: String,
+    #[serde(with = "format_rfc3339")]
+    pub created_at: DateTime<FixedOffset>,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -100,6 +185,36 @@ pub struct Destination {
     pub origin: String,
     pub identifier: String,
     pub statuses: Vec<DestinationStatus>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub resolved_statuses: Vec<ResolvedStatus>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fingerprints: Vec<Fingerprint>,
+    /** 次に宛先での存在を確認する statuses の位置 */
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub reconcile_offset: usize,
+}
+
+pub(super) fn is_zero(value: &usize) -> bool {
+    *value == 0
+}
+
+impl Destination {
+    /** 保持する解決結果の上限 */
+    const MAX_RESOLVED_STATUSES: usize = 100;
+
+    pub fn insert_resolved_status(&mut self, resolved_status: ResolvedStatus) {
+        self.resolved_statuses
+            .retain(|status| status.uri != resolved_status.uri);
+        self.resolved_statuses.insert(0, resolved_status);
+        self.resolved_statuses.truncate(Self::MAX_RESOLVED_STATUSES);
+    }
+
+    /** window より古い指紋は捨てる */
+    pub fn insert_fingerprint(&mut self, fingerprint: Fingerprint, window: Duration) {
+        self.fingerprints
+            .retain(|x| x.created_at >= fingerprint.created_at - window);
+        self.fingerprints.insert(0, fingerprint);
+    }
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -124,6 +239,9 @@ impl User {
             origin: account_key.origin.clone(),
             identifier: account_key.identifier.clone(),
             statuses: Vec::default(),
+            resolved_statuses: Vec::default(),
+            fingerprints: Vec::default(),
+            reconcile_offset: 0,
         });
         self.dsts.last_mut().unwrap()
     }
@@ -1,3 +1,22 @@
+use sha2::{Digest, Sha256};
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/** 実行環境に依らない、保存しても良いハッシュ */
+pub fn fnv1a(hash: Option<u64>, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash.unwrap_or(FNV_OFFSET_BASIS), |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+        })
+}
+
+/** 衝突を考えなくて良い、内容の sha256 の16進表記 */
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
 pub mod format_rfc3339 {
     use chrono::{DateTime, FixedOffset, SecondsFormat};
     use serde::{de, Deserialize, Deserializer, Serializer};
@@ -16,3 +35,31 @@ pub mod format_rfc3339 {
         DateTime::parse_from_rfc3339(&String::deserialize(deserializer)?).map_err(de::Error::custom)
     }
 }
+
+pub mod format_rfc3339_option {
+    use chrono::{DateTime, FixedOffset};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(
+        date: &Option<DateTime<FixedOffset>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match date {
+            Some(date) => super::format_rfc3339::serialize(date, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<DateTime<FixedOffset>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Wrapper(#[serde(with = "super::format_rfc3339")] DateTime<FixedOffset>);
+
+        Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(date)| date))
+    }
+}